@@ -0,0 +1,524 @@
+//! A bump allocator inside protected pages, for data that is built in place.
+//!
+//! An [`Arena`] maps any number of pages under one protection key, shared with
+//! other processes when made from a file descriptor. Allocations are bumped
+//! off a counter in the first bytes of the mapping, so every process mapping
+//! the same memory allocates from the same arena. Memory is only accessible
+//! while an [`ArenaGuard`] is alive.
+//!
+//! Every mapping has its own base address, so allocations are handed out as
+//! [`Block`]s, which are offsets from the base that mean the same in every
+//! process. The guard also implements the interface of the unstable
+//! `allocator_api`, with pointers that are only valid while it lives; with the
+//! `nightly` feature `&ArenaGuard` is a `core::alloc::Allocator` proper:
+//!
+//! ```ignore
+//! let arena = pkey.make_arena(16 * 4096)?;
+//! let guard = arena.lock();
+//! let mut body = Vec::new_in(&guard);
+//! serde_json::to_writer(&mut body, &response)?;
+//! let block = guard.block_of(body.as_ptr(), body.len());
+//! ```
+//!
+//! On stable, [`ArenaGuard::writer()`] builds a body in place the same way.
+//!
+//! Freed memory is only reused if it was the most recent allocation, as is
+//! the case for a buffer that grows or a message that is dropped right away;
+//! everything else stays allocated until [`ArenaGuard::reset()`].
+
+use std::alloc::Layout;
+use std::io;
+use std::os::fd::RawFd;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::{map_anonymous, map_shared, ProtectionError, ProtectionKeys, PAGE_SIZE};
+
+/// Start of the mapping, shared by everyone who allocates from it.
+#[repr(C, align(64))]
+struct Header {
+    /// Offset of the end of the last allocation, 0 while nothing was allocated.
+    next: AtomicUsize,
+}
+
+const HEADER_LEN: usize = std::mem::size_of::<Header>();
+
+/// An allocation failed because the arena is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Arena is out of memory")]
+pub struct AllocError;
+
+/// Bytes of an [`Arena`], as offsets that are the same in every mapping of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Block {
+    fn end(&self) -> Option<usize> {
+        self.offset.checked_add(self.len)
+    }
+}
+
+/// Protected pages to allocate from, see the [module docs](self).
+pub struct Arena {
+    pkey: Arc<ProtectionKeys>,
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Arena {
+    pub(crate) fn new(
+        pkey: &Arc<ProtectionKeys>,
+        capacity: usize,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        let len = mapping_len(capacity);
+        let ptr = map_anonymous(pkey, len)?;
+        Ok(Arc::new(Self {
+            pkey: pkey.clone(),
+            ptr,
+            len,
+        }))
+    }
+
+    pub(crate) fn new_fd(
+        pkey: &Arc<ProtectionKeys>,
+        capacity: usize,
+        fd: RawFd,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        let len = mapping_len(capacity);
+        // A fresh file is zeroed, which is an empty header, so there is nothing to initialize
+        let ptr = map_shared(pkey, len, fd)?;
+        Ok(Arc::new(Self {
+            pkey: pkey.clone(),
+            ptr,
+            len,
+        }))
+    }
+
+    /// Bytes available for allocations when the arena is empty.
+    pub fn capacity(&self) -> usize {
+        self.len - HEADER_LEN
+    }
+
+    /// Creates arena guard with access to the allocations.
+    pub fn lock(&'_ self) -> ArenaGuard<'_> {
+        let rights = self.pkey.rights();
+        self.pkey.set(0);
+        ArenaGuard {
+            arena: self,
+            rights,
+            _marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        // SAFETY: region still exists, ptr and length were initialized once on creation
+        if unsafe { libc::munmap(self.ptr, self.len) } < 0 {
+            log::error!("failed to unmap arena: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+unsafe impl Sync for Arena {}
+unsafe impl Send for Arena {}
+
+/// Header and capacity rounded up to whole pages.
+fn mapping_len(capacity: usize) -> usize {
+    let len = capacity.saturating_add(HEADER_LEN);
+    (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+/// See [`Arena::lock()`]
+pub struct ArenaGuard<'a> {
+    arena: &'a Arena,
+    /// Rights of the key before the guard, restored after it so guards can nest.
+    rights: usize,
+    _marker: std::marker::PhantomData<*const u8>,
+}
+
+impl<'a> ArenaGuard<'a> {
+    fn header(&self) -> &Header {
+        // SAFETY: the mapping starts with the header and the guard makes it accessible
+        unsafe { &*(self.arena.ptr as *const Header) }
+    }
+
+    fn base(&self) -> usize {
+        self.arena.ptr as usize
+    }
+
+    /// Bytes allocated so far, including padding and the header.
+    pub fn used(&self) -> usize {
+        self.header().next.load(Ordering::Acquire).max(HEADER_LEN)
+    }
+
+    /// Allocates a block that fits `layout`.
+    pub fn allocate_block(&self, layout: Layout) -> Result<Block, AllocError> {
+        let next = &self.header().next;
+        let mut current = next.load(Ordering::Relaxed);
+        loop {
+            // Aligned as an address, so alignments beyond a page work as well
+            let start = align_up(self.base() + current.max(HEADER_LEN), layout.align())
+                .ok_or(AllocError)?
+                - self.base();
+            let block = Block {
+                offset: start,
+                len: layout.size(),
+            };
+            let end = block
+                .end()
+                .filter(|&end| end <= self.arena.len)
+                .ok_or(AllocError)?;
+            match next.compare_exchange_weak(current, end, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => return Ok(block),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Frees `block`, which only makes its memory available again if it's the last one.
+    pub fn deallocate_block(&self, block: Block) {
+        if let Some(end) = block.end() {
+            let _ = self.header().next.compare_exchange(
+                end,
+                block.offset,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Grows `block` to `len` bytes if nothing was allocated after it.
+    pub(crate) fn grow_in_place(&self, block: Block, len: usize) -> bool {
+        match (block.end(), block.offset.checked_add(len)) {
+            (Some(end), Some(new_end)) if new_end <= self.arena.len => self
+                .header()
+                .next
+                .compare_exchange(end, new_end, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Allocates a block with a copy of `bytes`.
+    pub fn write_block(&self, bytes: &[u8]) -> Result<Block, AllocError> {
+        let block = self.allocate_block(Layout::for_value(bytes))?;
+        // SAFETY: the block was just allocated, so nobody else refers to it
+        unsafe { self.bytes_mut(block) }
+            .expect("allocated block is in bounds")
+            .copy_from_slice(bytes);
+        Ok(block)
+    }
+
+    /// The bytes of `block`, or `None` if it's out of bounds.
+    ///
+    /// Blocks can come from other processes, so every one is checked.
+    pub fn bytes(&self, block: Block) -> Option<&[u8]> {
+        let ptr = self.checked(block)?;
+        // SAFETY: checked to be inside the mapping, which the guard makes accessible
+        Some(unsafe { std::slice::from_raw_parts(ptr, block.len) })
+    }
+
+    /// The bytes of `block` to write to, or `None` if it's out of bounds.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may refer to the block while the slice lives, in this
+    /// process or any other mapping the arena.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn bytes_mut(&self, block: Block) -> Option<&mut [u8]> {
+        let ptr = self.checked(block)?;
+        Some(std::slice::from_raw_parts_mut(ptr, block.len))
+    }
+
+    pub(crate) fn checked(&self, block: Block) -> Option<*mut u8> {
+        let end = block.end()?;
+        if block.offset < HEADER_LEN || end > self.arena.len {
+            return None;
+        }
+        Some((self.base() + block.offset) as *mut u8)
+    }
+
+    /// The block of `len` bytes at `ptr`, which has to be inside the arena.
+    pub fn block_of(&self, ptr: *const u8, len: usize) -> Option<Block> {
+        let block = Block {
+            offset: (ptr as usize).checked_sub(self.base())?,
+            len,
+        };
+        self.checked(block).map(|_| block)
+    }
+
+    /// A writer that builds a block in place.
+    pub fn writer(&self) -> ArenaWriter<'_, 'a> {
+        ArenaWriter {
+            guard: self,
+            block: None,
+        }
+    }
+
+    /// Frees every allocation, of every process.
+    ///
+    /// # Safety
+    ///
+    /// No block of the arena may be used afterwards.
+    pub unsafe fn reset(&self) {
+        self.header().next.store(HEADER_LEN, Ordering::Release);
+    }
+
+    /// Allocates memory that fits `layout`, as `Allocator::allocate` does.
+    pub fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.allocate_block(layout)?;
+        let ptr = self.checked(block).ok_or(AllocError)?;
+        let slice = std::ptr::slice_from_raw_parts_mut(ptr, block.len);
+        NonNull::new(slice).ok_or(AllocError)
+    }
+
+    /// Frees memory of [`allocate`](Self::allocate), as `Allocator::deallocate` does.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this arena with `layout`.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if let Some(block) = self.block_of(ptr.as_ptr(), layout.size()) {
+            self.deallocate_block(block);
+        }
+    }
+
+    /// Grows memory of [`allocate`](Self::allocate) in place if possible, as `Allocator::grow` does.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this arena with `old_layout`, and
+    /// `new_layout` must be at least as large.
+    pub unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self
+            .block_of(ptr.as_ptr(), old_layout.size())
+            .ok_or(AllocError)?;
+        if ptr.as_ptr() as usize % new_layout.align() == 0
+            && self.grow_in_place(block, new_layout.size())
+        {
+            let slice = std::ptr::slice_from_raw_parts_mut(ptr.as_ptr(), new_layout.size());
+            return NonNull::new(slice).ok_or(AllocError);
+        }
+
+        let new = self.allocate(new_layout)?;
+        std::ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr() as *mut u8, old_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(new)
+    }
+}
+
+impl Drop for ArenaGuard<'_> {
+    fn drop(&mut self) {
+        self.arena.pkey.set(self.rights);
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl core::alloc::Allocator for &ArenaGuard<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        ArenaGuard::allocate(self, layout).map_err(|_| core::alloc::AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        ArenaGuard::deallocate(self, ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        ArenaGuard::grow(self, ptr, old_layout, new_layout).map_err(|_| core::alloc::AllocError)
+    }
+}
+
+fn align_up(address: usize, align: usize) -> Option<usize> {
+    Some(address.checked_add(align - 1)? & !(align - 1))
+}
+
+/// Writes a block in place, growing it as long as nothing else is allocated
+/// meanwhile and moving it otherwise. See [`ArenaGuard::writer()`]
+pub struct ArenaWriter<'a, 'g> {
+    guard: &'a ArenaGuard<'g>,
+    block: Option<Block>,
+}
+
+impl ArenaWriter<'_, '_> {
+    /// The block written so far.
+    pub fn finish(self) -> Block {
+        self.block.unwrap_or(Block {
+            offset: HEADER_LEN,
+            len: 0,
+        })
+    }
+}
+
+impl io::Write for ArenaWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let full = || io::Error::new(io::ErrorKind::OutOfMemory, AllocError);
+        let block = match self.block {
+            Some(block) if self.guard.grow_in_place(block, block.len + buf.len()) => {
+                self.block = Some(Block {
+                    len: block.len + buf.len(),
+                    ..block
+                });
+                block
+            }
+            old => {
+                // Moved somewhere with room to grow in place again
+                let len = old.map_or(0, |old| old.len);
+                let new = self
+                    .guard
+                    .allocate_block(Layout::array::<u8>(len + buf.len()).map_err(|_| full())?)
+                    .map_err(|_| full())?;
+                if let Some(old) = old {
+                    let bytes = self.guard.bytes(old).expect("written block is in bounds");
+                    // SAFETY: new was just allocated, so nobody else refers to it
+                    unsafe { self.guard.bytes_mut(new) }.expect("allocated block is in bounds")
+                        [..len]
+                        .copy_from_slice(bytes);
+                }
+                self.block = Some(new);
+                Block { len, ..new }
+            }
+        };
+
+        let end = Block {
+            offset: block.offset + block.len,
+            len: buf.len(),
+        };
+        // SAFETY: the writer owns the block and just grew it by `end`
+        unsafe { self.guard.bytes_mut(end) }
+            .expect("grown block is in bounds")
+            .copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_allocations() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let arena = pkey.make_arena(3 * PAGE_SIZE).unwrap();
+        assert_eq!(arena.capacity(), 4 * PAGE_SIZE - HEADER_LEN);
+        let guard = arena.lock();
+
+        let first = guard.write_block(b"hello").unwrap();
+        let aligned = guard.allocate_block(Layout::new::<u64>()).unwrap();
+        assert_eq!(aligned.offset % 8, 0);
+        assert!(aligned.offset >= first.offset + first.len);
+        assert_eq!(guard.bytes(first), Some(&b"hello"[..]));
+
+        // Only the last allocation is freed
+        guard.deallocate_block(first);
+        assert_eq!(guard.used(), aligned.offset + 8);
+        guard.deallocate_block(aligned);
+        assert_eq!(guard.used(), aligned.offset);
+
+        let rest = arena.capacity() - (guard.used() - HEADER_LEN);
+        let last = guard
+            .allocate_block(Layout::array::<u8>(rest).unwrap())
+            .unwrap();
+        assert_eq!(guard.allocate_block(Layout::new::<u8>()), Err(AllocError));
+        assert_eq!(
+            guard.block_of(guard.bytes(last).unwrap().as_ptr(), rest),
+            Some(last)
+        );
+
+        let outside = Block {
+            offset: last.offset,
+            len: last.len + 1,
+        };
+        assert_eq!(guard.bytes(outside), None);
+        assert_eq!(guard.bytes(Block { offset: 0, len: 1 }), None);
+        assert_eq!(
+            guard.bytes(Block {
+                offset: usize::MAX,
+                len: 2
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_writer() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let arena = pkey.make_arena(PAGE_SIZE).unwrap();
+        let guard = arena.lock();
+
+        let mut writer = guard.writer();
+        writer.write_all(b"grows").unwrap();
+        writer.write_all(b" in place").unwrap();
+        let other = guard.write_block(b"in the way").unwrap();
+        write!(writer, ", then moves {} bytes", 14).unwrap();
+        let block = writer.finish();
+        assert!(block.offset > other.offset);
+        assert_eq!(
+            guard.bytes(block),
+            Some(&b"grows in place, then moves 14 bytes"[..])
+        );
+        assert_eq!(guard.bytes(other), Some(&b"in the way"[..]));
+
+        let mut writer = guard.writer();
+        let too_long = vec![0; arena.capacity()];
+        let error = writer.write_all(&too_long).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn test_shared_mappings() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let (writer, reader) = crate::map_twice(2 * PAGE_SIZE, |fd| {
+            pkey.make_arena_fd(PAGE_SIZE, fd).unwrap()
+        });
+        assert_ne!(writer.ptr, reader.ptr);
+
+        // Guards nest, and both mappings allocate from the same counter
+        let written = writer.lock();
+        let block = written.write_block(b"shared").unwrap();
+        let read = reader.lock();
+        assert_eq!(read.bytes(block), Some(&b"shared"[..]));
+        let next = read.write_block(b"next").unwrap();
+        assert!(next.offset >= block.offset + block.len);
+        drop(read);
+        assert_eq!(written.bytes(next), Some(&b"next"[..]));
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn test_allocator() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let arena = pkey.make_arena(PAGE_SIZE).unwrap();
+        let guard = arena.lock();
+
+        let mut body = Vec::new_in(&guard);
+        for i in 0..100u8 {
+            body.push(i);
+        }
+        let block = guard.block_of(body.as_ptr(), body.len()).unwrap();
+        assert_eq!(
+            guard.bytes(block).unwrap(),
+            &(0..100).collect::<Vec<u8>>()[..]
+        );
+    }
+}
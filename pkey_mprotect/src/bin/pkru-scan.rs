@@ -0,0 +1,96 @@
+//! Reports instructions that can write `PKRU` outside of allowed gates.
+//!
+//! ```text
+//! pkru-scan [--allow ADDRESS]... [--allow-symbol PATH]... [--all] BINARY...
+//! ```
+//!
+//! The checked `WRPKRU` of this crate's gate, `pkey_mprotect::wrpkru`, is always
+//! allowed, and `--allow-symbol` only allows checked ones too. Exits with 1 if
+//! any binary has an instruction that isn't allowed, and 2 if a binary can't be
+//! scanned.
+
+use std::path::PathBuf;
+use std::process::exit;
+
+use pkey_mprotect::scan::{self, AllowList};
+
+const USAGE: &str =
+    "Usage: pkru-scan [--allow ADDRESS]... [--allow-symbol PATH]... [--all] BINARY...";
+
+/// The gate `ProtectionKeys` and compartments switch rights through.
+const GATE: &str = "pkey_mprotect::wrpkru";
+
+fn parse_address(address: &str) -> Option<u64> {
+    match address.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => address.parse().ok(),
+    }
+}
+
+fn main() {
+    let mut allowed = AllowList::new().symbol(GATE);
+    let mut all = false;
+    let mut binaries = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--allow" => match args.next().as_deref().and_then(parse_address) {
+                Some(address) => allowed = allowed.address(address),
+                None => {
+                    eprintln!("--allow needs an address\n{}", USAGE);
+                    exit(2);
+                }
+            },
+            "--allow-symbol" => match args.next() {
+                Some(path) => allowed = allowed.symbol(&path),
+                None => {
+                    eprintln!("--allow-symbol needs a path\n{}", USAGE);
+                    exit(2);
+                }
+            },
+            "--all" => all = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => binaries.push(PathBuf::from(arg)),
+        }
+    }
+    if binaries.is_empty() {
+        eprintln!("{}", USAGE);
+        exit(2);
+    }
+
+    let mut unauthorised = 0;
+    let mut failed = false;
+    for binary in &binaries {
+        let findings = match scan::scan_file(binary) {
+            Ok(findings) => findings,
+            Err(e) => {
+                match std::error::Error::source(&e) {
+                    Some(source) => eprintln!("{}: {}: {}", binary.display(), e, source),
+                    None => eprintln!("{}: {}", binary.display(), e),
+                }
+                failed = true;
+                continue;
+            }
+        };
+        for finding in findings {
+            if !allowed.allows(&finding) {
+                unauthorised += 1;
+                println!("{}: {}", binary.display(), finding);
+            } else if all {
+                println!("{}: {} allowed", binary.display(), finding);
+            }
+        }
+    }
+
+    if failed {
+        exit(2);
+    }
+    if unauthorised > 0 {
+        eprintln!("{} unauthorised instructions", unauthorised);
+        exit(1);
+    }
+}
@@ -0,0 +1,727 @@
+//! One producer broadcasting messages to any number of subscribers.
+//!
+//! A [`Broadcast`] is a ring of fixed-size slots that the producer appends
+//! messages to. Every subscriber has its own cursor into the ring, so they
+//! read at their own pace, and may join late: a new subscriber starts at the
+//! oldest message still in the ring, or at the next one. Mapped from a file
+//! descriptor, producer and subscribers can live in different processes.
+//!
+//! The slots are tagged with the protection key. The producer opens them for
+//! writing while it appends, subscribers only ever get `PKEY_DISABLE_WRITE`,
+//! so one of them can't change what the others read. Cursors live in a
+//! control page in front of the slots, which isn't tagged, since subscribers
+//! have to write theirs.
+//!
+//! A subscriber that is a whole ring behind would have its next message
+//! overwritten. What happens then is up to the producer's [`SlowSubscribers`]
+//! policy: it drops the subscriber, waits for it, or overwrites the message
+//! and lets the subscriber know how many it missed.
+//!
+//! ```ignore
+//! let broadcast = pkey.make_broadcast(&BroadcastConfig::default())?;
+//! let mut producer = broadcast.producer(SlowSubscribers::Overwrite)?;
+//! let mut subscriber = broadcast.subscribe(Start::Oldest)?;
+//!
+//! producer.send(b"event")?;
+//! match subscriber.recv() {
+//!     Ok(message) => handle(message),
+//!     Err(BroadcastError::Lagged(missed)) => resync(missed),
+//!     Err(e) => return Err(e),
+//! }
+//! ```
+
+use std::mem::size_of;
+use std::os::fd::RawFd;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::{
+    backoff, is_alive, map_anonymous, map_shared, tag, ProtectionError, ProtectionKeys, PAGE_SIZE,
+    PKEY_DISABLE_WRITE, SPINS_BEFORE_YIELD,
+};
+
+/// Subscribers a broadcast has room for, one cache line each in the control page.
+pub const MAX_SUBSCRIBERS: usize = PAGE_SIZE / size_of::<Entry>() - 1;
+
+const FREE: u32 = 0;
+const JOINING: u32 = 1;
+const ACTIVE: u32 = 2;
+const DROPPED: u32 = 3;
+
+/// Start of the control page.
+#[repr(C, align(64))]
+struct Header {
+    /// Messages appended so far.
+    head: AtomicU64,
+    /// Pid of the process the producer is attached in, 0 if none.
+    producer: AtomicU32,
+    /// Set when the producer is gone, so subscribers stop once they caught up.
+    closed: AtomicU32,
+}
+
+/// A subscriber's line of the control page.
+#[repr(C, align(64))]
+struct Entry {
+    state: AtomicU32,
+    pid: AtomicU32,
+    /// Next message the subscriber reads.
+    cursor: AtomicU64,
+}
+
+/// Start of a slot, followed by the message.
+#[repr(C)]
+struct SlotHeader {
+    /// `2 * n + 2` once message `n` is in the slot, odd while it's written.
+    sequence: AtomicU64,
+    len: u64,
+}
+
+/// Failure to send or receive a broadcast message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum BroadcastError {
+    #[error("Message of {0} bytes doesn't fit into a slot")]
+    TooLarge(usize),
+    #[error("Broadcast already has a producer")]
+    ProducerExists,
+    #[error("Broadcast has no room for another subscriber")]
+    TooManySubscribers,
+    #[error("Subscriber missed {0} messages that were overwritten")]
+    Lagged(u64),
+    #[error("Subscriber was dropped for falling behind")]
+    Dropped,
+    #[error("Producer is gone")]
+    Closed,
+}
+
+/// What the producer does about a subscriber a whole ring behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowSubscribers {
+    /// Unsubscribes it; its next receive fails with [`BroadcastError::Dropped`].
+    Drop,
+    /// Waits until it catches up, or its process exits.
+    Block,
+    /// Overwrites its next message; its next receive fails with
+    /// [`BroadcastError::Lagged`] and continues at the oldest message left.
+    Overwrite,
+}
+
+/// Where a new subscriber starts reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    /// At the oldest message still in the ring.
+    Oldest,
+    /// At the next message sent.
+    Latest,
+}
+
+/// Size of a broadcast ring, which needs at least one slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BroadcastConfig {
+    /// Messages the ring holds.
+    pub slots: usize,
+    /// Longest message, in bytes.
+    pub slot_size: usize,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            slots: 64,
+            slot_size: 4096 - size_of::<SlotHeader>(),
+        }
+    }
+}
+
+impl BroadcastConfig {
+    fn stride(&self) -> usize {
+        (size_of::<SlotHeader>() + self.slot_size + 63) / 64 * 64
+    }
+
+    /// Bytes of the mapping, which a file has to be at least as long as.
+    ///
+    /// Every mapping of a file has to use the same config.
+    pub fn mapping_len(&self) -> usize {
+        let len = PAGE_SIZE + self.slots * self.stride();
+        (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+    }
+}
+
+/// A mapped broadcast ring, see the [module docs](self).
+pub struct Broadcast {
+    pkey: Arc<ProtectionKeys>,
+    ptr: *mut libc::c_void,
+    config: BroadcastConfig,
+}
+
+impl Broadcast {
+    pub(crate) fn new(
+        pkey: &Arc<ProtectionKeys>,
+        config: &BroadcastConfig,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        assert!(config.slots > 0, "a broadcast needs at least one slot");
+        let ptr = map_anonymous(pkey, config.mapping_len())?;
+        Self::with_control_page(pkey, ptr, config)
+    }
+
+    pub(crate) fn new_fd(
+        pkey: &Arc<ProtectionKeys>,
+        config: &BroadcastConfig,
+        fd: RawFd,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        assert!(config.slots > 0, "a broadcast needs at least one slot");
+        // A fresh file is zeroed, which is an empty ring without subscribers
+        let ptr = map_shared(pkey, config.mapping_len(), fd)?;
+        Self::with_control_page(pkey, ptr, config)
+    }
+
+    /// Tags the control page with the key of ordinary memory, so subscribers can move their
+    /// cursors.
+    fn with_control_page(
+        pkey: &Arc<ProtectionKeys>,
+        ptr: *mut libc::c_void,
+        config: &BroadcastConfig,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        let broadcast = Arc::new(Self {
+            pkey: pkey.clone(),
+            ptr,
+            config: *config,
+        });
+        if !pkey.is_empty() {
+            tag(ptr, PAGE_SIZE, 0)?;
+        }
+        Ok(broadcast)
+    }
+
+    pub fn config(&self) -> &BroadcastConfig {
+        &self.config
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: the mapping starts with the control page, which is always accessible
+        unsafe { &*(self.ptr as *const Header) }
+    }
+
+    fn entries(&self) -> &[Entry] {
+        // SAFETY: the entries fill the control page after the header
+        unsafe {
+            std::slice::from_raw_parts(
+                (self.ptr as usize + size_of::<Entry>()) as *const Entry,
+                MAX_SUBSCRIBERS,
+            )
+        }
+    }
+
+    /// Header and message bytes of the slot of message `n`.
+    fn slot(&self, n: u64) -> (*mut SlotHeader, *mut u8) {
+        let index = (n % self.config.slots as u64) as usize;
+        let slot = self.ptr as usize + PAGE_SIZE + index * self.config.stride();
+        (
+            slot as *mut SlotHeader,
+            (slot + size_of::<SlotHeader>()) as *mut u8,
+        )
+    }
+
+    /// Attaches the producer, of which there can only be one at a time.
+    ///
+    /// Takes over from a producer whose process died without detaching.
+    pub fn producer(self: &Arc<Self>, policy: SlowSubscribers) -> Result<Producer, BroadcastError> {
+        let header = self.header();
+        let pid = std::process::id();
+        if let Err(owner) =
+            header
+                .producer
+                .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed)
+        {
+            if is_alive(owner) {
+                return Err(BroadcastError::ProducerExists);
+            }
+            header
+                .producer
+                .compare_exchange(owner, pid, Ordering::AcqRel, Ordering::Relaxed)
+                .map_err(|_| BroadcastError::ProducerExists)?;
+        }
+        header.closed.store(0, Ordering::Release);
+        Ok(Producer {
+            broadcast: self.clone(),
+            policy,
+            stats: BroadcastStats::default(),
+        })
+    }
+
+    /// Adds a subscriber that reads from `start` on.
+    ///
+    /// Once every entry is taken, those of subscribers whose process died are reused.
+    pub fn subscribe(self: &Arc<Self>, start: Start) -> Result<Subscriber, BroadcastError> {
+        let claim = |entry: &Entry, state| {
+            entry
+                .state
+                .compare_exchange(state, JOINING, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        };
+        let mut entries = self.entries().iter().enumerate();
+        let (index, entry) = entries
+            .clone()
+            .find(|(_, entry)| claim(entry, FREE))
+            .or_else(|| {
+                entries.find(|(_, entry)| {
+                    let state = entry.state.load(Ordering::Acquire);
+                    (state == ACTIVE || state == DROPPED)
+                        && !is_alive(entry.pid.load(Ordering::Relaxed))
+                        && claim(entry, state)
+                })
+            })
+            .ok_or(BroadcastError::TooManySubscribers)?;
+
+        let head = self.header().head.load(Ordering::Acquire);
+        let cursor = match start {
+            Start::Oldest => head.saturating_sub(self.config.slots as u64),
+            Start::Latest => head,
+        };
+        entry.pid.store(std::process::id(), Ordering::Relaxed);
+        entry.cursor.store(cursor, Ordering::Relaxed);
+        entry.state.store(ACTIVE, Ordering::Release);
+        Ok(Subscriber {
+            broadcast: self.clone(),
+            index,
+            cursor,
+            received: 0,
+            missed: 0,
+        })
+    }
+}
+
+impl Drop for Broadcast {
+    fn drop(&mut self) {
+        // SAFETY: region still exists, ptr and length were initialized once on creation
+        if unsafe { libc::munmap(self.ptr, self.config.mapping_len()) } < 0 {
+            log::error!(
+                "failed to unmap broadcast: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+unsafe impl Sync for Broadcast {}
+unsafe impl Send for Broadcast {}
+
+/// What a producer has done so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BroadcastStats {
+    /// Messages sent.
+    pub sent: u64,
+    /// Subscribers dropped for falling behind, see [`SlowSubscribers::Drop`].
+    pub dropped: u64,
+    /// Sends that waited for a subscriber, see [`SlowSubscribers::Block`].
+    pub blocked: u64,
+}
+
+/// Appends messages to a [`Broadcast`]; closes it when dropped.
+pub struct Producer {
+    broadcast: Arc<Broadcast>,
+    policy: SlowSubscribers,
+    stats: BroadcastStats,
+}
+
+impl Producer {
+    /// Appends `message`, dealing with subscribers a whole ring behind as the policy says.
+    pub fn send(&mut self, message: &[u8]) -> Result<(), BroadcastError> {
+        if message.len() > self.broadcast.config.slot_size {
+            return Err(BroadcastError::TooLarge(message.len()));
+        }
+        let head = self.broadcast.header().head.load(Ordering::Relaxed);
+        match self.policy {
+            SlowSubscribers::Drop => self.drop_behind(head),
+            SlowSubscribers::Block => self.wait_for_behind(head),
+            SlowSubscribers::Overwrite => {}
+        }
+
+        let broadcast = &*self.broadcast;
+        let rights = broadcast.pkey.rights();
+        broadcast.pkey.set(0);
+        let (slot, data) = broadcast.slot(head);
+        // SAFETY: the slot is inside the mapping and the key allows writing it
+        let sequence = unsafe { &(*slot).sequence };
+        sequence.store(2 * head + 1, Ordering::Relaxed);
+        // Subscribers that see any of the new message also see the odd sequence
+        fence(Ordering::Release);
+        // SAFETY: the message fits into the slot; only the producer writes
+        // slots, and subscribers discard what they copy meanwhile
+        unsafe {
+            std::ptr::write_volatile(std::ptr::addr_of_mut!((*slot).len), message.len() as u64);
+            std::ptr::copy_nonoverlapping(message.as_ptr(), data, message.len());
+        }
+        sequence.store(2 * head + 2, Ordering::Release);
+        broadcast.pkey.set(rights);
+
+        broadcast.header().head.store(head + 1, Ordering::Release);
+        self.stats.sent += 1;
+        Ok(())
+    }
+
+    /// Subscribers whose next message would be overwritten by message `head`.
+    fn behind(&self, head: u64) -> impl Iterator<Item = &Entry> {
+        let slots = self.broadcast.config.slots as u64;
+        self.broadcast.entries().iter().filter(move |entry| {
+            entry.state.load(Ordering::Acquire) == ACTIVE
+                && head - entry.cursor.load(Ordering::Acquire).min(head) >= slots
+        })
+    }
+
+    fn drop_behind(&mut self, head: u64) {
+        let mut dropped = 0;
+        for entry in self.behind(head) {
+            if entry
+                .state
+                .compare_exchange(ACTIVE, DROPPED, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                dropped += 1;
+            }
+        }
+        self.stats.dropped += dropped;
+    }
+
+    fn wait_for_behind(&mut self, head: u64) {
+        let mut spins = 0;
+        let mut blocked = false;
+        while let Some(entry) = self.behind(head).next() {
+            blocked = true;
+            // A subscriber whose process exited won't catch up anymore
+            if spins >= SPINS_BEFORE_YIELD && !is_alive(entry.pid.load(Ordering::Relaxed)) {
+                let _ =
+                    entry
+                        .state
+                        .compare_exchange(ACTIVE, FREE, Ordering::AcqRel, Ordering::Relaxed);
+            }
+            backoff(&mut spins);
+        }
+        self.stats.blocked += blocked as u64;
+    }
+
+    pub fn stats(&self) -> BroadcastStats {
+        self.stats
+    }
+
+    /// Subscribers currently reading.
+    pub fn subscribers(&self) -> usize {
+        self.broadcast
+            .entries()
+            .iter()
+            .filter(|entry| entry.state.load(Ordering::Acquire) == ACTIVE)
+            .count()
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        let header = self.broadcast.header();
+        header.closed.store(1, Ordering::Release);
+        header.producer.store(0, Ordering::Release);
+    }
+}
+
+/// Reads the messages of a [`Broadcast`] from its own cursor; unsubscribes when dropped.
+pub struct Subscriber {
+    broadcast: Arc<Broadcast>,
+    index: usize,
+    cursor: u64,
+    received: u64,
+    missed: u64,
+}
+
+impl Subscriber {
+    fn entry(&self) -> &Entry {
+        &self.broadcast.entries()[self.index]
+    }
+
+    /// Waits for the next message.
+    pub fn recv(&mut self) -> Result<Vec<u8>, BroadcastError> {
+        let mut spins = 0;
+        loop {
+            if let Some(message) = self.try_recv()? {
+                return Ok(message);
+            }
+            backoff(&mut spins);
+        }
+    }
+
+    /// The next message, or `None` if there is none yet.
+    pub fn try_recv(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
+        if self.entry().state.load(Ordering::Acquire) == DROPPED {
+            return Err(BroadcastError::Dropped);
+        }
+        let broadcast = &*self.broadcast;
+        let closed = broadcast.header().closed.load(Ordering::Acquire) != 0;
+        let head = broadcast.header().head.load(Ordering::Acquire);
+        if self.cursor >= head {
+            return if closed {
+                Err(BroadcastError::Closed)
+            } else {
+                Ok(None)
+            };
+        }
+        if head - self.cursor > broadcast.config.slots as u64 {
+            return Err(self.lagged());
+        }
+
+        let rights = broadcast.pkey.rights();
+        broadcast.pkey.set(PKEY_DISABLE_WRITE);
+        let (slot, data) = broadcast.slot(self.cursor);
+        // SAFETY: the slot is inside the mapping and the key allows reading it
+        let sequence = unsafe { &(*slot).sequence };
+        let before = sequence.load(Ordering::Acquire);
+        let message = if before == 2 * self.cursor + 2 {
+            // SAFETY: the copy may be torn, so it's only used once the sequence
+            // shows it isn't, and its length is capped to the slot
+            let message = unsafe {
+                let len = std::ptr::read_volatile(std::ptr::addr_of!((*slot).len)) as usize;
+                let len = len.min(broadcast.config.slot_size);
+                let mut message = vec![0; len];
+                std::ptr::copy_nonoverlapping(data, message.as_mut_ptr(), len);
+                message
+            };
+            fence(Ordering::Acquire);
+            Some(message).filter(|_| sequence.load(Ordering::Relaxed) == before)
+        } else {
+            None
+        };
+        broadcast.pkey.set(rights);
+
+        match message {
+            Some(message) => {
+                self.advance(self.cursor + 1);
+                self.received += 1;
+                Ok(Some(message))
+            }
+            // Overwritten by a message a ring later, or dropped meanwhile
+            None if self.entry().state.load(Ordering::Acquire) == DROPPED => {
+                Err(BroadcastError::Dropped)
+            }
+            None => Err(self.lagged()),
+        }
+    }
+
+    /// Skips to the oldest message left and reports how many were missed.
+    fn lagged(&mut self) -> BroadcastError {
+        let head = self.broadcast.header().head.load(Ordering::Acquire);
+        let oldest = head
+            .saturating_sub(self.broadcast.config.slots as u64)
+            .max(self.cursor + 1);
+        let missed = oldest - self.cursor;
+        self.advance(oldest);
+        self.missed += missed;
+        BroadcastError::Lagged(missed)
+    }
+
+    fn advance(&mut self, cursor: u64) {
+        self.cursor = cursor;
+        self.entry().cursor.store(cursor, Ordering::Release);
+    }
+
+    /// Messages sent that this subscriber hasn't read yet.
+    pub fn lag(&self) -> u64 {
+        self.broadcast
+            .header()
+            .head
+            .load(Ordering::Acquire)
+            .saturating_sub(self.cursor)
+    }
+
+    /// Messages received so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Messages overwritten before they were read, see [`SlowSubscribers::Overwrite`].
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.entry().state.store(FREE, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broadcast(slots: usize) -> Arc<Broadcast> {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        pkey.make_broadcast(&BroadcastConfig {
+            slots,
+            slot_size: 16,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let broadcast = broadcast(4);
+        let mut producer = broadcast.producer(SlowSubscribers::Overwrite).unwrap();
+        assert_eq!(
+            broadcast.producer(SlowSubscribers::Block).err(),
+            Some(BroadcastError::ProducerExists)
+        );
+        let mut first = broadcast.subscribe(Start::Oldest).unwrap();
+        let mut second = broadcast.subscribe(Start::Oldest).unwrap();
+        assert_eq!(first.try_recv(), Ok(None));
+
+        producer.send(b"one").unwrap();
+        producer.send(b"two").unwrap();
+        assert_eq!(producer.send(&[0; 17]), Err(BroadcastError::TooLarge(17)));
+        // Late joiners see what's left in the ring, or only what comes next
+        let mut late = broadcast.subscribe(Start::Oldest).unwrap();
+        let mut latest = broadcast.subscribe(Start::Latest).unwrap();
+        producer.send(b"three").unwrap();
+
+        for subscriber in [&mut first, &mut second, &mut late] {
+            assert_eq!(subscriber.recv().unwrap(), b"one");
+            assert_eq!(subscriber.recv().unwrap(), b"two");
+            assert_eq!(subscriber.recv().unwrap(), b"three");
+        }
+        assert_eq!(latest.recv().unwrap(), b"three");
+        assert_eq!(producer.subscribers(), 4);
+
+        drop(producer);
+        assert_eq!(first.recv(), Err(BroadcastError::Closed));
+        drop(second);
+        assert_eq!(
+            broadcast
+                .producer(SlowSubscribers::Overwrite)
+                .unwrap()
+                .subscribers(),
+            3
+        );
+    }
+
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    fn test_dead_producer() {
+        let broadcast = broadcast(4);
+        let dead = dead_pid();
+        broadcast.header().producer.store(dead, Ordering::Relaxed);
+
+        let mut producer = broadcast.producer(SlowSubscribers::Overwrite).unwrap();
+        assert_eq!(
+            broadcast.producer(SlowSubscribers::Overwrite).err(),
+            Some(BroadcastError::ProducerExists)
+        );
+        let mut subscriber = broadcast.subscribe(Start::Oldest).unwrap();
+        producer.send(b"after").unwrap();
+        assert_eq!(subscriber.recv().unwrap(), b"after");
+    }
+
+    #[test]
+    fn test_dead_subscribers() {
+        let broadcast = broadcast(4);
+        let mut producer = broadcast.producer(SlowSubscribers::Drop).unwrap();
+        let mut subscribers = (0..MAX_SUBSCRIBERS)
+            .map(|_| broadcast.subscribe(Start::Latest).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            broadcast.subscribe(Start::Latest).err(),
+            Some(BroadcastError::TooManySubscribers)
+        );
+
+        // Two subscribers crash without dropping, one of them after it was dropped as too slow
+        let dead = dead_pid();
+        for subscriber in &subscribers[..2] {
+            subscriber.entry().pid.store(dead, Ordering::Relaxed);
+        }
+        subscribers[1]
+            .entry()
+            .state
+            .store(DROPPED, Ordering::Release);
+        let alive = subscribers.split_off(2);
+        std::mem::forget(subscribers);
+
+        let mut first = broadcast.subscribe(Start::Latest).unwrap();
+        let mut second = broadcast.subscribe(Start::Latest).unwrap();
+        assert_eq!(
+            broadcast.subscribe(Start::Latest).err(),
+            Some(BroadcastError::TooManySubscribers)
+        );
+        producer.send(b"reused").unwrap();
+        assert_eq!(first.recv().unwrap(), b"reused");
+        assert_eq!(second.recv().unwrap(), b"reused");
+        assert_eq!(producer.subscribers(), alive.len() + 2);
+    }
+
+    #[test]
+    fn test_overwrite_reports_lag() {
+        let broadcast = broadcast(4);
+        let mut producer = broadcast.producer(SlowSubscribers::Overwrite).unwrap();
+        let mut slow = broadcast.subscribe(Start::Oldest).unwrap();
+        for i in 0..10u8 {
+            producer.send(&[i]).unwrap();
+        }
+        assert_eq!(slow.lag(), 10);
+        assert_eq!(slow.recv(), Err(BroadcastError::Lagged(6)));
+        assert_eq!(slow.recv().unwrap(), [6]);
+        assert_eq!(slow.missed(), 6);
+        assert_eq!(slow.received(), 1);
+    }
+
+    #[test]
+    fn test_drop_slow_subscribers() {
+        let broadcast = broadcast(2);
+        let mut producer = broadcast.producer(SlowSubscribers::Drop).unwrap();
+        let mut slow = broadcast.subscribe(Start::Oldest).unwrap();
+        let mut fast = broadcast.subscribe(Start::Oldest).unwrap();
+        for i in 0..4u8 {
+            producer.send(&[i]).unwrap();
+            assert_eq!(fast.recv().unwrap(), [i]);
+        }
+        assert_eq!(producer.stats().dropped, 1);
+        assert_eq!(producer.subscribers(), 1);
+        assert_eq!(slow.recv(), Err(BroadcastError::Dropped));
+    }
+
+    #[test]
+    fn test_block_until_caught_up() {
+        let broadcast = broadcast(2);
+        let mut subscriber = broadcast.subscribe(Start::Oldest).unwrap();
+        let producer = {
+            let broadcast = broadcast.clone();
+            std::thread::spawn(move || {
+                let mut producer = broadcast.producer(SlowSubscribers::Block).unwrap();
+                for i in 0..100u8 {
+                    producer.send(&[i]).unwrap();
+                }
+                producer.stats()
+            })
+        };
+        for i in 0..100u8 {
+            assert_eq!(subscriber.recv().unwrap(), [i]);
+        }
+        let stats = producer.join().unwrap();
+        assert_eq!(stats.sent, 100);
+        assert_eq!(subscriber.missed(), 0);
+        assert_eq!(subscriber.recv(), Err(BroadcastError::Closed));
+    }
+
+    #[test]
+    fn test_shared_mappings() {
+        let config = BroadcastConfig::default();
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let (writer, reader) = crate::map_twice(config.mapping_len(), |fd| {
+            pkey.make_broadcast_fd(&config, fd).unwrap()
+        });
+        assert_ne!(writer.ptr, reader.ptr);
+
+        let mut producer = writer.producer(SlowSubscribers::Block).unwrap();
+        let mut subscriber = reader.subscribe(Start::Latest).unwrap();
+        producer.send(b"across mappings").unwrap();
+        assert_eq!(subscriber.recv().unwrap(), b"across mappings");
+        assert_eq!(producer.subscribers(), 1);
+    }
+}
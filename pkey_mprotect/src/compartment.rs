@@ -0,0 +1,756 @@
+//! Compartments: threads that can only reach the memory granted to them.
+//!
+//! A [`Domain`] declares compartments, the regions they share and the channels
+//! between them, then spawns every compartment as a thread. Each compartment
+//! gets a protection key of its own for its private regions, and its thread runs
+//! with a `PKRU` that denies every key but its own and those of the regions and
+//! channels granted to it, so touching anything else faults.
+//!
+//! A compartment can also be entered through a [`Gate`] instead, which runs a
+//! function as the compartment on the caller's thread.
+//!
+//! Grants are fixed once a compartment is spawned or gated. Only memory handed out by a
+//! domain is isolated: heaps and stacks keep the default key and stay shared,
+//! so data that matters belongs in a region.
+//!
+//! ```no_run
+//! use pkey_mprotect::{Access, Domain};
+//!
+//! let mut domain = Domain::new(false).unwrap();
+//! let client = domain.compartment("client").unwrap();
+//! let server = domain.compartment("server").unwrap();
+//! let config = domain.region(42u32).unwrap();
+//! domain.grant(&config, server, Access::Read).unwrap();
+//! let (requests, incoming) = domain.channel(client, server).unwrap();
+//!
+//! let server = domain
+//!     .spawn(server, move || {
+//!         assert_eq!(*config.read().unwrap(), 42);
+//!         incoming.recv().unwrap()
+//!     })
+//!     .unwrap();
+//! domain.spawn(client, move || requests.send(b"hello").unwrap()).unwrap();
+//! assert_eq!(server.join().unwrap(), b"hello");
+//! ```
+
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::JoinHandle;
+
+use crate::{
+    backoff, map_anonymous, rdpkru, scrub_registers, with_rights, wrpkru, ProtectionError,
+    ProtectionKeys, PAGE_SIZE, PKEY_DISABLE_ACCESS, PKEY_DISABLE_WRITE,
+};
+
+// Keys a PKRU has rights for; key 0 tags all ordinary memory
+const KEY_COUNT: c_int = 16;
+
+/// Bytes a channel carries per chunk: its page minus the chunk length.
+pub const CHUNK_CAPACITY: usize = PAGE_SIZE - std::mem::size_of::<usize>();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<CompartmentId>> = const { Cell::new(None) };
+}
+
+/// Identifies a compartment of a [`Domain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompartmentId(usize);
+
+impl CompartmentId {
+    /// The compartment the current thread runs, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.with(Cell::get)
+    }
+}
+
+/// What a compartment may do with a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    /// Reading and writing.
+    Write,
+}
+
+struct Compartment {
+    id: CompartmentId,
+    name: String,
+    key: Arc<ProtectionKeys>,
+    grants: Vec<(Arc<ProtectionKeys>, Access)>,
+    /// Set once the compartment is spawned or has a gate, which fixes its grants.
+    sealed: bool,
+}
+
+/// Compartments and the memory they share.
+pub struct Domain {
+    require_protected: bool,
+    compartments: Vec<Compartment>,
+}
+
+impl Domain {
+    /// Creates an empty domain.
+    ///
+    /// Keys are allocated like [`ProtectionKeys::new`] does: without protection
+    /// keys, compartments still check their grants but nothing faults, unless
+    /// `require_protected` makes that an error.
+    pub fn new(require_protected: bool) -> Result<Self, ProtectionError> {
+        if require_protected && !ProtectionKeys::is_supported() {
+            return Err(ProtectionError::Unsupported);
+        }
+        Ok(Self {
+            require_protected,
+            compartments: Vec::new(),
+        })
+    }
+
+    /// Declares a compartment with a key of its own.
+    pub fn compartment(&mut self, name: &str) -> Result<CompartmentId, ProtectionError> {
+        let id = CompartmentId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        self.compartments.push(Compartment {
+            id,
+            name: name.to_string(),
+            key: ProtectionKeys::new(self.require_protected)?,
+            grants: Vec::new(),
+            sealed: false,
+        });
+        Ok(id)
+    }
+
+    /// Creates a region under a key of its own that no compartment has access to yet.
+    pub fn region<T>(&mut self, initial: T) -> Result<SharedRegion<T>, ProtectionError> {
+        let key = ProtectionKeys::new(self.require_protected)?;
+        SharedRegion::new(key, initial, None)
+    }
+
+    /// Creates a region under the key of `owner`, which only `owner` can access.
+    pub fn private_region<T>(
+        &mut self,
+        owner: CompartmentId,
+        initial: T,
+    ) -> Result<SharedRegion<T>, ProtectionError> {
+        let key = self.get(owner)?.key.clone();
+        let region = SharedRegion::new(key, initial, Some(owner))?;
+        region.inner.grant(owner, Access::Write);
+        Ok(region)
+    }
+
+    /// Lets `compartment` access `region`, before it is spawned or gets a gate.
+    pub fn grant<T>(
+        &mut self,
+        region: &SharedRegion<T>,
+        compartment: CompartmentId,
+        access: Access,
+    ) -> Result<(), ProtectionError> {
+        if region.inner.owner.is_some() {
+            return Err(ProtectionError::PrivateRegion);
+        }
+        let target = self.get_mut(compartment)?;
+        if target.sealed {
+            return Err(ProtectionError::AlreadySpawned);
+        }
+        target.grants.push((region.inner.key.clone(), access));
+        region.inner.grant(compartment, access);
+        Ok(())
+    }
+
+    /// Declares a channel that carries messages from `from` to `to`, before either is sealed.
+    ///
+    /// Messages go through a page of its own that `from` can write and `to`
+    /// can read, one [`CHUNK_CAPACITY`] at a time.
+    pub fn channel(
+        &mut self,
+        from: CompartmentId,
+        to: CompartmentId,
+    ) -> Result<(Sender, Receiver), ProtectionError> {
+        if self.get(from)?.sealed || self.get(to)?.sealed {
+            return Err(ProtectionError::AlreadySpawned);
+        }
+        let page = self.region(Chunk {
+            len: 0,
+            data: [0; CHUNK_CAPACITY],
+        })?;
+        self.grant(&page, from, Access::Write)?;
+        self.grant(&page, to, Access::Read)?;
+
+        let state = Arc::new(ChannelState {
+            full: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        });
+        let sender = Sender {
+            compartment: from,
+            page: page.clone(),
+            state: state.clone(),
+        };
+        let receiver = Receiver {
+            compartment: to,
+            page,
+            state,
+        };
+        Ok((sender, receiver))
+    }
+
+    /// Runs `f` as `compartment` on a thread of its own.
+    ///
+    /// The thread can access the compartment's private regions and what was
+    /// granted to it; every other protection key is denied.
+    pub fn spawn<F, R>(
+        &mut self,
+        compartment: CompartmentId,
+        f: F,
+    ) -> Result<JoinHandle<R>, ProtectionError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let target = self.get_mut(compartment)?;
+        if target.sealed {
+            return Err(ProtectionError::AlreadySpawned);
+        }
+        target.sealed = true;
+        let pkru = target.pkru();
+
+        std::thread::Builder::new()
+            .name(target.name.clone())
+            .spawn(move || {
+                if let Some(pkru) = pkru {
+                    // SAFETY: a PKRU is only computed if protection keys are supported
+                    unsafe { wrpkru(pkru) };
+                }
+                CURRENT.with(|current| current.set(Some(compartment)));
+                f()
+            })
+            .map_err(ProtectionError::SpawnFailed)
+    }
+
+    /// Returns the only gate into `compartment`, which then can't be spawned.
+    pub fn gate(&mut self, compartment: CompartmentId) -> Result<Gate, ProtectionError> {
+        let target = self.get_mut(compartment)?;
+        if target.sealed {
+            return Err(ProtectionError::AlreadySpawned);
+        }
+        target.sealed = true;
+
+        let mut keys = vec![target.key.clone()];
+        keys.extend(target.grants.iter().map(|(key, _)| key.clone()));
+        Ok(Gate {
+            compartment,
+            pkru: target.pkru(),
+            _keys: keys,
+        })
+    }
+
+    fn get(&self, id: CompartmentId) -> Result<&Compartment, ProtectionError> {
+        self.compartments
+            .iter()
+            .find(|compartment| compartment.id == id)
+            .ok_or(ProtectionError::UnknownCompartment)
+    }
+
+    fn get_mut(&mut self, id: CompartmentId) -> Result<&mut Compartment, ProtectionError> {
+        self.compartments
+            .iter_mut()
+            .find(|compartment| compartment.id == id)
+            .ok_or(ProtectionError::UnknownCompartment)
+    }
+}
+
+impl Compartment {
+    /// Rights of the compartment's thread, or `None` without protection keys.
+    fn pkru(&self) -> Option<u32> {
+        if !ProtectionKeys::is_supported() {
+            return None;
+        }
+        let mut pkru =
+            (1..KEY_COUNT).fold(0, |pkru, key| with_rights(pkru, key, PKEY_DISABLE_ACCESS));
+        if let Some(handle) = self.key.handle {
+            pkru = with_rights(pkru, handle, 0);
+        }
+        for (key, access) in &self.grants {
+            if let Some(handle) = key.handle {
+                let rights = match access {
+                    Access::Read => PKEY_DISABLE_WRITE,
+                    Access::Write => 0,
+                };
+                pkru = with_rights(pkru, handle, rights);
+            }
+        }
+        Some(pkru)
+    }
+}
+
+/// Runs functions as a compartment on the calling thread, in the style of ERIM.
+///
+/// On entry the thread switches to the rights of the compartment; on exit,
+/// whether by return or panic, the registers the callee may have left its data
+/// in are zeroed and the caller's rights are restored. The callee shares the
+/// caller's stack and heap, which stay under the default key.
+pub struct Gate {
+    compartment: CompartmentId,
+    pkru: Option<u32>,
+    /// Keys the PKRU refers to, kept from being reallocated while the gate exists.
+    _keys: Vec<Arc<ProtectionKeys>>,
+}
+
+impl Gate {
+    /// The compartment the gate enters.
+    pub fn compartment(&self) -> CompartmentId {
+        self.compartment
+    }
+
+    /// Calls `f` with `args` as the gate's compartment.
+    pub fn call<A, R>(&self, args: A, f: impl FnOnce(A) -> R) -> R {
+        let _exit = GateExit {
+            // SAFETY: a PKRU is only computed if protection keys are supported
+            pkru: self.pkru.map(|pkru| unsafe {
+                let caller = rdpkru();
+                wrpkru(pkru);
+                caller
+            }),
+            compartment: CURRENT.with(|current| current.replace(Some(self.compartment))),
+        };
+        f(args)
+    }
+}
+
+/// Leaves a gate when dropped, see [`Gate::call()`].
+struct GateExit {
+    pkru: Option<u32>,
+    compartment: Option<CompartmentId>,
+}
+
+impl Drop for GateExit {
+    fn drop(&mut self) {
+        // SAFETY: the callee is done with its registers, and `pkru` was read from the CPU
+        unsafe {
+            scrub_registers();
+            if let Some(pkru) = self.pkru {
+                wrpkru(pkru);
+            }
+        }
+        CURRENT.with(|current| current.set(self.compartment));
+    }
+}
+
+/// Memory that only compartments granted access to can reach.
+///
+/// Accesses are checked against the grants of the current compartment before
+/// the protection key gets a say, so a missing grant is an error rather than
+/// a fault. Readers and writers exclude each other like an [`RwLock`].
+pub struct SharedRegion<T> {
+    inner: Arc<RegionInner<T>>,
+}
+
+struct RegionInner<T> {
+    key: Arc<ProtectionKeys>,
+    ptr: *mut T,
+    len: usize,
+    owner: Option<CompartmentId>,
+    grants: RwLock<Vec<(CompartmentId, Access)>>,
+    lock: RwLock<()>,
+}
+
+// SAFETY: the value is only reached through `lock`, like in an `RwLock<T>`
+unsafe impl<T: Send + Sync> Send for RegionInner<T> {}
+unsafe impl<T: Send + Sync> Sync for RegionInner<T> {}
+
+impl<T> SharedRegion<T> {
+    fn new(
+        key: Arc<ProtectionKeys>,
+        initial: T,
+        owner: Option<CompartmentId>,
+    ) -> Result<Self, ProtectionError> {
+        let len = (std::mem::size_of::<T>().max(1) + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let ptr = map_anonymous(&key, len)? as *mut T;
+        // SAFETY: ptr is aligned to PAGE_SIZE and the mapping holds a T
+        key.with_access(|| unsafe { ptr.write(initial) });
+        Ok(Self {
+            inner: Arc::new(RegionInner {
+                key,
+                ptr,
+                len,
+                owner,
+                grants: RwLock::new(Vec::new()),
+                lock: RwLock::new(()),
+            }),
+        })
+    }
+
+    /// Locks the region for reading.
+    pub fn read(&self) -> Result<RegionRef<'_, T>, ProtectionError> {
+        self.inner.check(Access::Read)?;
+        Ok(RegionRef {
+            _guard: self
+                .inner
+                .lock
+                .read()
+                .unwrap_or_else(PoisonError::into_inner),
+            // SAFETY: the value lives as long as the region and the guard keeps writers out
+            value: unsafe { &*self.inner.ptr },
+        })
+    }
+
+    /// Locks the region for writing.
+    pub fn write(&self) -> Result<RegionMut<'_, T>, ProtectionError> {
+        self.inner.check(Access::Write)?;
+        Ok(RegionMut {
+            _guard: self
+                .inner
+                .lock
+                .write()
+                .unwrap_or_else(PoisonError::into_inner),
+            // SAFETY: the value lives as long as the region and the guard keeps everyone else out
+            value: unsafe { &mut *self.inner.ptr },
+        })
+    }
+}
+
+impl<T> Clone for SharedRegion<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> RegionInner<T> {
+    fn grant(&self, compartment: CompartmentId, access: Access) {
+        let mut grants = self.grants.write().unwrap_or_else(PoisonError::into_inner);
+        grants.push((compartment, access));
+    }
+
+    fn check(&self, access: Access) -> Result<(), ProtectionError> {
+        let current = CompartmentId::current().ok_or(ProtectionError::AccessDenied)?;
+        let grants = self.grants.read().unwrap_or_else(PoisonError::into_inner);
+        let granted = grants
+            .iter()
+            .any(|&(compartment, granted)| compartment == current && granted >= access);
+        if granted {
+            Ok(())
+        } else {
+            Err(ProtectionError::AccessDenied)
+        }
+    }
+}
+
+impl<T> Drop for RegionInner<T> {
+    fn drop(&mut self) {
+        // SAFETY: region still exists, properly aligned and nobody else holds it anymore
+        self.key
+            .with_access(|| unsafe { std::ptr::drop_in_place(self.ptr) });
+
+        // SAFETY: ptr and length were initialized once on creation
+        if unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) } < 0 {
+            log::error!(
+                "failed to unmap region: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// See [`SharedRegion::read()`]
+pub struct RegionRef<'a, T> {
+    _guard: RwLockReadGuard<'a, ()>,
+    value: &'a T,
+}
+
+impl<T> Deref for RegionRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// See [`SharedRegion::write()`]
+pub struct RegionMut<'a, T> {
+    _guard: RwLockWriteGuard<'a, ()>,
+    value: &'a mut T,
+}
+
+impl<T> Deref for RegionMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> DerefMut for RegionMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+/// Contents of a channel's page.
+struct Chunk {
+    len: usize,
+    data: [u8; CHUNK_CAPACITY],
+}
+
+struct ChannelState {
+    /// Whether the page holds a chunk the receiver hasn't read yet.
+    full: AtomicBool,
+    /// Set once either end is dropped.
+    closed: AtomicBool,
+}
+
+impl ChannelState {
+    /// Spins until the page is `full`, failing once the other end is gone.
+    fn wait(&self, full: bool) -> Result<(), ProtectionError> {
+        let mut spins = 0;
+        while self.full.load(Ordering::Acquire) != full {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(ProtectionError::Disconnected);
+            }
+            backoff(&mut spins);
+        }
+        Ok(())
+    }
+}
+
+/// The sending end of a channel, usable only in the compartment it was declared for.
+pub struct Sender {
+    compartment: CompartmentId,
+    page: SharedRegion<Chunk>,
+    state: Arc<ChannelState>,
+}
+
+impl Sender {
+    /// Sends a message, waiting for the receiver to take every chunk.
+    ///
+    /// A message ends with the first chunk that isn't full, which is empty
+    /// if its length is a multiple of [`CHUNK_CAPACITY`].
+    pub fn send(&self, message: &[u8]) -> Result<(), ProtectionError> {
+        if CompartmentId::current() != Some(self.compartment) {
+            return Err(ProtectionError::WrongCompartment);
+        }
+        let mut chunks = message.chunks(CHUNK_CAPACITY);
+        loop {
+            let chunk = chunks.next().unwrap_or_default();
+            self.state.wait(false)?;
+            {
+                let mut page = self.page.write()?;
+                page.data[..chunk.len()].copy_from_slice(chunk);
+                page.len = chunk.len();
+            }
+            self.state.full.store(true, Ordering::Release);
+            if chunk.len() < CHUNK_CAPACITY {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.state.closed.store(true, Ordering::Release);
+    }
+}
+
+/// The receiving end of a channel, usable only in the compartment it was declared for.
+pub struct Receiver {
+    compartment: CompartmentId,
+    page: SharedRegion<Chunk>,
+    state: Arc<ChannelState>,
+}
+
+impl Receiver {
+    /// Waits for the next message.
+    ///
+    /// Fails with [`ProtectionError::Disconnected`] once the sender is gone
+    /// and every message it sent was received.
+    pub fn recv(&self) -> Result<Vec<u8>, ProtectionError> {
+        if CompartmentId::current() != Some(self.compartment) {
+            return Err(ProtectionError::WrongCompartment);
+        }
+        let mut message = Vec::new();
+        loop {
+            self.state.wait(true)?;
+            let len = {
+                let page = self.page.read()?;
+                message.extend_from_slice(&page.data[..page.len]);
+                page.len
+            };
+            self.state.full.store(false, Ordering::Release);
+            if len < CHUNK_CAPACITY {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.state.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grants_are_checked() {
+        let mut domain = Domain::new(false).unwrap();
+        let reader = domain.compartment("reader").unwrap();
+        let writer = domain.compartment("writer").unwrap();
+        let other = domain.compartment("other").unwrap();
+        let region = domain.region(1u64).unwrap();
+        domain.grant(&region, reader, Access::Read).unwrap();
+        domain.grant(&region, writer, Access::Write).unwrap();
+        let secret = domain.private_region(other, 7u64).unwrap();
+        assert!(matches!(
+            domain.grant(&secret, reader, Access::Read),
+            Err(ProtectionError::PrivateRegion)
+        ));
+
+        // The thread that set everything up is no compartment
+        assert!(matches!(region.read(), Err(ProtectionError::AccessDenied)));
+
+        let shared = region.clone();
+        domain
+            .spawn(writer, move || *shared.write().unwrap() = 2)
+            .unwrap()
+            .join()
+            .unwrap();
+        let shared = region.clone();
+        let read = domain
+            .spawn(reader, move || {
+                assert!(matches!(shared.write(), Err(ProtectionError::AccessDenied)));
+                *shared.read().unwrap()
+            })
+            .unwrap();
+        assert_eq!(read.join().unwrap(), 2);
+
+        let read = domain
+            .spawn(other, move || {
+                assert!(matches!(region.read(), Err(ProtectionError::AccessDenied)));
+                *secret.read().unwrap()
+            })
+            .unwrap();
+        assert_eq!(read.join().unwrap(), 7);
+
+        assert!(matches!(
+            domain.spawn(other, || ()),
+            Err(ProtectionError::AlreadySpawned)
+        ));
+    }
+
+    #[test]
+    fn test_compartments_deny_other_keys() {
+        if !ProtectionKeys::is_supported() {
+            return;
+        }
+        let mut domain = Domain::new(true).unwrap();
+        let first = domain.compartment("first").unwrap();
+        let second = domain.compartment("second").unwrap();
+        let region = domain.region(0u8).unwrap();
+        domain.grant(&region, second, Access::Read).unwrap();
+
+        let own = domain.get(first).unwrap().key.handle.unwrap();
+        let other = domain.get(second).unwrap().key.handle.unwrap();
+        let shared = region.inner.key.handle.unwrap();
+        let rights = |pkru: u32, key: c_int| (pkru >> (2 * key)) & 0b11;
+
+        // SAFETY: protection keys are supported
+        let pkru = domain.spawn(first, || unsafe { crate::rdpkru() }).unwrap();
+        let pkru = pkru.join().unwrap();
+        assert_eq!(rights(pkru, 0), 0);
+        assert_eq!(rights(pkru, own), 0);
+        assert_eq!(rights(pkru, other), PKEY_DISABLE_ACCESS as u32);
+        assert_eq!(rights(pkru, shared), PKEY_DISABLE_ACCESS as u32);
+
+        let pkru = domain.spawn(second, || unsafe { crate::rdpkru() }).unwrap();
+        let pkru = pkru.join().unwrap();
+        assert_eq!(rights(pkru, own), PKEY_DISABLE_ACCESS as u32);
+        assert_eq!(rights(pkru, shared), PKEY_DISABLE_WRITE as u32);
+    }
+
+    #[test]
+    fn test_gates() {
+        let mut domain = Domain::new(false).unwrap();
+        let caller = domain.compartment("caller").unwrap();
+        let callee = domain.compartment("callee").unwrap();
+        let input = domain.region(String::from("input")).unwrap();
+        domain.grant(&input, callee, Access::Read).unwrap();
+        let secret = domain.private_region(caller, 7u64).unwrap();
+        let gate = domain.gate(callee).unwrap();
+        assert!(matches!(
+            domain.spawn(callee, || ()),
+            Err(ProtectionError::AlreadySpawned)
+        ));
+
+        let caller = domain
+            .spawn(caller, move || {
+                let caller = CompartmentId::current();
+                // SAFETY: reading PKRU is harmless where protection keys are supported
+                let rights = || ProtectionKeys::is_supported().then(|| unsafe { rdpkru() });
+                let before = rights();
+
+                let len = gate.call(&input, |input| {
+                    assert_eq!(CompartmentId::current(), Some(gate.compartment()));
+                    assert!(matches!(secret.read(), Err(ProtectionError::AccessDenied)));
+                    assert_ne!(rights(), before);
+                    input.read().unwrap().len()
+                });
+                assert_eq!(len, 5);
+                assert_eq!(CompartmentId::current(), caller);
+                assert_eq!(rights(), before);
+
+                // Unwinding out of the callee leaves the gate as well
+                let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    gate.call((), |()| panic!("callee failed"))
+                }));
+                assert!(panicked.is_err());
+                assert_eq!(CompartmentId::current(), caller);
+                assert_eq!(rights(), before);
+                *secret.read().unwrap()
+            })
+            .unwrap();
+        assert_eq!(caller.join().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut domain = Domain::new(false).unwrap();
+        let client = domain.compartment("client").unwrap();
+        let server = domain.compartment("server").unwrap();
+        let (requests, incoming) = domain.channel(client, server).unwrap();
+        let (responses, replies) = domain.channel(server, client).unwrap();
+        assert!(matches!(
+            requests.send(b"outside"),
+            Err(ProtectionError::WrongCompartment)
+        ));
+
+        let server = domain
+            .spawn(server, move || {
+                // Echo until the client hangs up
+                while let Ok(message) = incoming.recv() {
+                    responses.send(&message).unwrap();
+                }
+            })
+            .unwrap();
+        let client = domain
+            .spawn(client, move || {
+                for len in [
+                    0,
+                    1,
+                    CHUNK_CAPACITY - 1,
+                    CHUNK_CAPACITY,
+                    3 * CHUNK_CAPACITY + 5,
+                ] {
+                    let message = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+                    requests.send(&message).unwrap();
+                    assert_eq!(replies.recv().unwrap(), message);
+                }
+            })
+            .unwrap();
+        client.join().unwrap();
+        server.join().unwrap();
+    }
+}
@@ -1,9 +1,25 @@
 #![doc = include_str!("../README.md")]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
+
+pub mod arena;
+pub mod broadcast;
+pub mod compartment;
+pub mod scan;
+pub mod seqlock;
+pub mod shared;
+pub mod virtual_keys;
 
 use std::ops::Deref;
-use std::sync::Arc;
-use std::slice;
 use std::os::fd::RawFd;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+pub use arena::Arena;
+pub use broadcast::{Broadcast, BroadcastConfig};
+pub use compartment::{Access, CompartmentId, Domain, Gate, Receiver, Sender, SharedRegion};
+pub use seqlock::Published;
+
+use shared::Relocatable;
 
 #[cfg(all(target_arch = "x86", not(target_env = "sgx"), target_feature = "sse"))]
 use ::core::arch::x86 as arch;
@@ -16,7 +32,7 @@ use ::core::arch::x86_64 as arch;
 /// because there are only 15 available keys in system
 #[derive(Default)]
 pub struct ProtectionKeys {
-    handle: Option<libc::c_int>,
+    handle: Option<c_int>,
 }
 
 impl ProtectionKeys {
@@ -76,12 +92,20 @@ impl ProtectionKeys {
             } else {
                 // There are available protection keys
                 Ok(Arc::new(Self {
-                    handle: Some(pkey as libc::c_int),
+                    handle: Some(pkey as c_int),
                 }))
             }
         }
     }
 
+    /// Creates a keys stub whose regions are always accessible.
+    ///
+    /// Regions behave as if protection keys were unsupported, which is
+    /// useful to measure what switching access rights costs.
+    pub fn unprotected() -> Arc<Self> {
+        Arc::new(Self { handle: None })
+    }
+
     /// Creates protected region.
     ///
     /// Arc with protected keys is cloned so it is safe to keep only the region.
@@ -106,29 +130,91 @@ impl ProtectionKeys {
         ProtectedRegion::new_fd(self, initial, fd)
     }
 
+    /// Maps at least `capacity` bytes of protected pages to allocate from.
+    ///
+    /// Arc with protected keys is cloned so it is safe to keep only the arena.
+    pub fn make_arena(self: &Arc<Self>, capacity: usize) -> Result<Arc<Arena>, ProtectionError> {
+        Arena::new(self, capacity)
+    }
+
+    /// Maps an arena from a file of at least `capacity` bytes plus a page, shared with every
+    /// other mapping of it.
+    pub fn make_arena_fd(
+        self: &Arc<Self>,
+        capacity: usize,
+        fd: RawFd,
+    ) -> Result<Arc<Arena>, ProtectionError> {
+        Arena::new_fd(self, capacity, fd)
+    }
+
+    /// Creates a ring that one producer broadcasts messages through, see [`broadcast`].
+    pub fn make_broadcast(
+        self: &Arc<Self>,
+        config: &BroadcastConfig,
+    ) -> Result<Arc<Broadcast>, ProtectionError> {
+        Broadcast::new(self, config)
+    }
+
+    /// Maps a broadcast ring from a file of at least [`BroadcastConfig::mapping_len`] bytes,
+    /// shared with every other mapping of it.
+    pub fn make_broadcast_fd(
+        self: &Arc<Self>,
+        config: &BroadcastConfig,
+        fd: RawFd,
+    ) -> Result<Arc<Broadcast>, ProtectionError> {
+        Broadcast::new_fd(self, config, fd)
+    }
+
+    /// Creates a region that `initial` and later values are published to.
+    pub fn make_published<T: Relocatable + Copy>(
+        self: &Arc<Self>,
+        initial: &T,
+    ) -> Result<Arc<Published<T>>, ProtectionError> {
+        Published::new(self, initial)
+    }
+
+    /// Maps a published region from a file of at least [`Published::LEN`] bytes, shared with
+    /// every other mapping of it.
+    pub fn make_published_fd<T: Relocatable + Copy>(
+        self: &Arc<Self>,
+        fd: RawFd,
+    ) -> Result<Arc<Published<T>>, ProtectionError> {
+        Published::new_fd(self, fd)
+    }
+
     /// Whether protection keys were allocated
     pub fn is_empty(&self) -> bool {
         self.handle.is_none()
     }
 
-    fn set(&self, rights: usize) {
-        #[cfg(any(not(target_arch = "x86_64"), not(target_os = "linux")))]
-        let _unused = rights;
+    /// Runs `f` with full access to this key's memory, then restores the previous rights.
+    fn with_access<R>(&self, f: impl FnOnce() -> R) -> R {
+        let handle = match self.handle {
+            Some(handle) => handle,
+            None => return f(),
+        };
+        // SAFETY: handle will only be Some if `RDPKRU` and `WRPKRU` are supported
+        let saved = unsafe { rdpkru() };
+        unsafe { wrpkru(with_rights(saved, handle, 0)) };
+        let result = f();
+        unsafe { wrpkru(saved) };
+        result
+    }
 
-        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    /// The rights of this key for the current thread.
+    fn rights(&self) -> usize {
+        match self.handle {
+            // SAFETY: handle will only be Some if `RDPKRU` is supported
+            Some(handle) => ((unsafe { rdpkru() } >> (2 * handle as u32)) & 0b11) as usize,
+            None => 0,
+        }
+    }
+
+    /// Sets the rights of this key for the current thread, keeping those of other keys.
+    fn set(&self, rights: usize) {
         if let Some(handle) = self.handle {
-            // SAFETY: handle will only be Some if `WRPKRU` command is supported
-            unsafe {
-                let eax = (rights << (2 * handle as usize)) as u32;
-
-                std::arch::asm!(
-                    ".byte 0x0f, 0x01, 0xef",
-                    in("eax") eax,
-                    in("ecx") 0,
-                    in("edx") 0,
-                    options(nomem, preserves_flags, nostack)
-                )
-            }
+            // SAFETY: handle will only be Some if `RDPKRU` and `WRPKRU` are supported
+            unsafe { wrpkru(with_rights(rdpkru(), handle, rights)) }
         }
     }
 }
@@ -166,51 +252,7 @@ impl<T> ProtectedRegion<T> {
     where
         T: Sized,
     {
-        // SAFETY: all parameters are passed according to
-        // https://man7.org/linux/man-pages/man2/mmap.2.html
-        let ptr = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                PAGE_SIZE,
-                libc::PROT_NONE,
-                libc::MAP_ANON | libc::MAP_PRIVATE,
-                -1,
-                0,
-            )
-        };
-        if ptr == libc::MAP_FAILED {
-            return Err(ProtectionError::MMapFailed(std::io::Error::last_os_error()));
-        }
-
-        #[cfg(not(target_os = "linux"))]
-        {
-            let res = unsafe { libc::mprotect(ptr, PAGE_SIZE, libc::PROT_READ | libc::PROT_WRITE) };
-            if res < 0 {
-                return Err(ProtectionError::MProtectFailed(
-                    std::io::Error::last_os_error(),
-                ));
-            }
-        }
-
-        #[cfg(target_os = "linux")]
-        {
-            // SAFETY: it is called with backward capability with mprotect
-            // https://man7.org/linux/man-pages/man2/mprotect.2.html
-            let res = unsafe {
-                libc::syscall(
-                    libc::SYS_pkey_mprotect,
-                    ptr as usize,
-                    PAGE_SIZE,
-                    libc::PROT_READ | libc::PROT_WRITE,
-                    pkey.handle.unwrap_or(-1),
-                )
-            };
-            if res < 0 {
-                return Err(ProtectionError::MProtectFailed(
-                    std::io::Error::last_os_error(),
-                ));
-            }
-        }
+        let ptr = map_anonymous(pkey, PAGE_SIZE)?;
 
         // Enable memory access
         pkey.set(0);
@@ -223,7 +265,7 @@ impl<T> ProtectedRegion<T> {
         Ok(Arc::new(Self {
             pkey: pkey.clone(),
             ptr,
-            _marker: std::marker::PhantomData::default(),
+            _marker: std::marker::PhantomData,
         }))
     }
 
@@ -288,7 +330,7 @@ impl<T> ProtectedRegion<T> {
         Ok(Arc::new(Self {
             pkey: pkey.clone(),
             ptr,
-            _marker: std::marker::PhantomData::default(),
+            _marker: std::marker::PhantomData,
         }))
     }
 
@@ -297,11 +339,7 @@ impl<T> ProtectedRegion<T> {
         self.pkey.set(0);
 
         // SAFETY: ptr is always aligned to PAGE_SIZE (4KB) and not null
-        unsafe { 
-            let test = (self.ptr as *mut T);
-            (self.ptr as *mut T).write(initial); 
-            let after_test = (self.ptr as *mut T);
-        }
+        unsafe { (self.ptr as *mut T).write(initial) };
         // Disable memory access
         self.pkey.set(PKEY_DISABLE_ACCESS);
 
@@ -382,6 +420,8 @@ fn is_ospke_supported() -> bool {
     fn cpuid_count(eax: u32, ecx: u32) -> CpuIdResult {
         // Safety: CPUID is supported on all x86_64 CPUs and all x86 CPUs with
         // SSE, but not by SGX.
+        // `__cpuid_count` is only safe to call since Rust 1.87
+        #[allow(unused_unsafe)]
         let result = unsafe { arch::__cpuid_count(eax, ecx) };
         CpuIdResult {
             eax: result.eax,
@@ -405,7 +445,231 @@ fn is_ospke_supported() -> bool {
     false
 }
 
+/// Maps `len` bytes of zeroed memory tagged with `pkey`.
+fn map_anonymous(pkey: &ProtectionKeys, len: usize) -> Result<*mut libc::c_void, ProtectionError> {
+    // SAFETY: all parameters are passed according to
+    // https://man7.org/linux/man-pages/man2/mmap.2.html
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_NONE,
+            libc::MAP_ANON | libc::MAP_PRIVATE,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(ProtectionError::MMapFailed(std::io::Error::last_os_error()));
+    }
+
+    protect(ptr, len, pkey)?;
+    Ok(ptr)
+}
+
+/// Maps `len` bytes of the file `fd`, shared with every other mapping of it and tagged with `pkey`.
+fn map_shared(
+    pkey: &ProtectionKeys,
+    len: usize,
+    fd: RawFd,
+) -> Result<*mut libc::c_void, ProtectionError> {
+    // SAFETY: all parameters are passed according to
+    // https://man7.org/linux/man-pages/man2/mmap.2.html
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_NONE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(ProtectionError::MMapFailed(std::io::Error::last_os_error()));
+    }
+
+    if let Err(e) = protect(ptr, len, pkey) {
+        // SAFETY: the mapping was just created and nothing refers to it
+        unsafe { libc::munmap(ptr, len) };
+        return Err(e);
+    }
+    Ok(ptr)
+}
+
+/// Makes `len` bytes at `ptr` readable and writable, tagged with `pkey`.
+fn protect(ptr: *mut libc::c_void, len: usize, pkey: &ProtectionKeys) -> Result<(), ProtectionError> {
+    tag(ptr, len, pkey.handle.unwrap_or(-1))
+}
+
+/// Makes `len` bytes at `ptr` readable and writable, tagged with key `handle`.
+///
+/// Key 0 is the one of all ordinary memory, while -1 keeps the key the pages have.
+fn tag(ptr: *mut libc::c_void, len: usize, handle: c_int) -> Result<(), ProtectionError> {
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _unused = handle;
+        let res = unsafe { libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_WRITE) };
+        if res < 0 {
+            return Err(ProtectionError::MProtectFailed(
+                std::io::Error::last_os_error(),
+            ));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: it is called with backward capability with mprotect
+        // https://man7.org/linux/man-pages/man2/mprotect.2.html
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_pkey_mprotect,
+                ptr as usize,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                handle,
+            )
+        };
+        if res < 0 {
+            return Err(ProtectionError::MProtectFailed(
+                std::io::Error::last_os_error(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Spins while waiting on another thread or process before giving the core away.
+pub const SPINS_BEFORE_YIELD: usize = 1 << 10;
+
+/// Waits a little for another thread or process, spinning at first and yielding later.
+pub fn backoff(spins: &mut usize) {
+    *spins += 1;
+    if *spins < SPINS_BEFORE_YIELD {
+        std::hint::spin_loop();
+    } else {
+        std::thread::yield_now();
+    }
+}
+
+/// Whether the process `pid` still exists, for taking over what a dead one held.
+pub fn is_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// `PKRU` with the two bits of `handle` replaced by `rights`.
+fn with_rights(pkru: u32, handle: c_int, rights: usize) -> u32 {
+    let shift = 2 * handle as u32;
+    (pkru & !(0b11 << shift)) | ((rights as u32) << shift)
+}
+
+/// Reads the access rights of every key for the current thread.
+///
+/// # Safety
+///
+/// The CPU and OS must support protection keys.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+unsafe fn rdpkru() -> u32 {
+    let pkru: u32;
+    std::arch::asm!(
+        ".byte 0x0f, 0x01, 0xee",
+        in("ecx") 0,
+        out("eax") pkru,
+        out("edx") _,
+        options(nomem, preserves_flags, nostack)
+    );
+    pkru
+}
+
+/// Replaces the access rights of every key for the current thread.
+///
+/// As in ERIM, the `WRPKRU` is followed by a check that `EAX` still holds the
+/// value the gate was called with, aborting with `UD2` otherwise. Code that
+/// jumps straight to the `WRPKRU` with rights of its own choosing then traps
+/// before it can use them, unless it also set up `ESI`. [`scan`] only allows
+/// sequences followed by this check, see [`scan::CHECK`].
+///
+/// # Safety
+///
+/// The CPU and OS must support protection keys.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[inline(never)]
+unsafe fn wrpkru(pkru: u32) {
+    // Not `nomem`: memory accesses must not be moved across a change of rights
+    std::arch::asm!(
+        ".byte 0x0f, 0x01, 0xef",
+        "cmp eax, esi",
+        "je 2f",
+        "ud2",
+        "2:",
+        in("eax") pkru,
+        in("ecx") 0,
+        in("edx") 0,
+        in("esi") pkru,
+        options(nostack)
+    )
+}
+
+/// Zeroes the registers a callee may leave its data in without restoring them:
+/// the caller-saved general purpose and vector registers.
+///
+/// # Safety
+///
+/// Nothing may expect these registers to survive, as at a function boundary.
+#[cfg(target_arch = "x86_64")]
+unsafe fn scrub_registers() {
+    std::arch::asm!(
+        "xor eax, eax",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "pxor xmm0, xmm0",
+        "pxor xmm1, xmm1",
+        "pxor xmm2, xmm2",
+        "pxor xmm3, xmm3",
+        "pxor xmm4, xmm4",
+        "pxor xmm5, xmm5",
+        "pxor xmm6, xmm6",
+        "pxor xmm7, xmm7",
+        "pxor xmm8, xmm8",
+        "pxor xmm9, xmm9",
+        "pxor xmm10, xmm10",
+        "pxor xmm11, xmm11",
+        "pxor xmm12, xmm12",
+        "pxor xmm13, xmm13",
+        "pxor xmm14, xmm14",
+        "pxor xmm15, xmm15",
+        out("rax") _, out("rcx") _, out("rdx") _, out("rsi") _, out("rdi") _,
+        out("r8") _, out("r9") _, out("r10") _, out("r11") _,
+        out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
+        out("xmm4") _, out("xmm5") _, out("xmm6") _, out("xmm7") _,
+        out("xmm8") _, out("xmm9") _, out("xmm10") _, out("xmm11") _,
+        out("xmm12") _, out("xmm13") _, out("xmm14") _, out("xmm15") _,
+        options(nomem, nostack)
+    )
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn scrub_registers() {}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+unsafe fn rdpkru() -> u32 {
+    0
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+unsafe fn wrpkru(_pkru: u32) {}
+
 const PKEY_DISABLE_ACCESS: usize = 1;
+const PKEY_DISABLE_WRITE: usize = 2;
 
 const PAGE_SIZE: usize = 4096;
 
@@ -419,6 +683,34 @@ pub enum ProtectionError {
     MMapFailed(#[source] std::io::Error),
     #[error("Failed to protect memory")]
     MProtectFailed(#[source] std::io::Error),
+    #[error("Compartment belongs to another domain")]
+    UnknownCompartment,
+    #[error("Compartment was already spawned or has a gate")]
+    AlreadySpawned,
+    #[error("Private regions can't be granted to other compartments")]
+    PrivateRegion,
+    #[error("Region is not granted to the current compartment")]
+    AccessDenied,
+    #[error("Channel endpoint used outside of its compartment")]
+    WrongCompartment,
+    #[error("Other end of the channel is gone")]
+    Disconnected,
+    #[error("Every hardware key is held by a locked region")]
+    KeysInUse,
+    #[error("Failed to spawn compartment thread")]
+    SpawnFailed(#[source] std::io::Error),
+}
+
+/// Maps a fresh memory file of `len` bytes twice with `map`, as two processes would.
+#[cfg(test)]
+fn map_twice<M>(len: usize, map: impl Fn(RawFd) -> M) -> (M, M) {
+    let fd = unsafe { libc::memfd_create(b"pkey_mprotect\0".as_ptr() as *const libc::c_char, 0) };
+    assert!(fd >= 0);
+    assert_eq!(unsafe { libc::ftruncate(fd, len as libc::off_t) }, 0);
+    let mappings = (map(fd), map(fd));
+    // The mappings keep the file alive
+    unsafe { libc::close(fd) };
+    mappings
 }
 
 #[cfg(test)]
@@ -0,0 +1,523 @@
+//! Finding instructions in binaries that can change protection key rights.
+//!
+//! Protection keys only isolate anything while untrusted code can't write the
+//! `PKRU` register itself. `WRPKRU` (`0f 01 ef`) writes it directly and
+//! `XRSTOR` (`0f ae /5`) does whenever the restored state includes it. x86
+//! instructions can start at any byte, so every executable section is searched
+//! at every offset, which also finds these sequences hidden inside other
+//! instructions. Any occurrence that isn't a known gate is a way around the
+//! isolation.
+//!
+//! Jumping to the `WRPKRU` of a gate with rights of one's own choosing is a way
+//! around it too, so gates follow theirs with [`CHECK`], as in ERIM, and only
+//! checked sequences are allowed by symbol.
+//!
+//! `XRSTORS` restores `PKRU` as well, but only runs in the kernel. Only 64-bit
+//! little-endian ELF files are supported, as protection keys are x86-64 only.
+
+use std::fmt;
+use std::path::Path;
+
+const SECTION_HEADER_LEN: usize = 64;
+const SYMBOL_LEN: usize = 24;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHT_DYNSYM: u32 = 11;
+const SHF_EXECINSTR: u64 = 0x4;
+const STT_FUNC: u8 = 2;
+
+const WRPKRU: [u8; 3] = [0x0f, 0x01, 0xef];
+/// `cmp eax, esi; je +2; ud2`: aborts unless `PKRU` was written with the value
+/// in `ESI`, which the gate sets to the one it was called with.
+pub const CHECK: [u8; 6] = [0x39, 0xf0, 0x74, 0x02, 0x0f, 0x0b];
+const XRSTOR: [u8; 2] = [0x0f, 0xae];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScanError {
+    #[error("Failed to read binary")]
+    Io(#[from] std::io::Error),
+    #[error("Not a 64-bit little-endian ELF file")]
+    Unsupported,
+    #[error("Malformed ELF file: {0}")]
+    Malformed(&'static str),
+}
+
+/// An instruction that writes `PKRU`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Wrpkru,
+    Xrstor,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Wrpkru => "wrpkru",
+            Self::Xrstor => "xrstor",
+        })
+    }
+}
+
+/// Where an [`Instruction`] was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub instruction: Instruction,
+    /// Virtual address of the first byte of the sequence.
+    pub address: u64,
+    pub section: String,
+    /// The function symbol containing the address, as found in the binary.
+    pub symbol: Option<String>,
+    /// Whether a `WRPKRU` is followed by [`CHECK`].
+    pub checked: bool,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x} {} in {}",
+            self.address, self.instruction, self.section
+        )?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " ({})", symbol)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gates that are allowed to write `PKRU`.
+#[derive(Debug, Default, Clone)]
+pub struct AllowList {
+    addresses: Vec<u64>,
+    symbols: Vec<String>,
+}
+
+impl AllowList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the sequence starting at `address`.
+    pub fn address(mut self, address: u64) -> Self {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Allows every checked `WRPKRU` inside a function, given by its Rust path
+    /// such as `pkey_mprotect::wrpkru` or by its symbol name.
+    pub fn symbol(mut self, path: &str) -> Self {
+        self.symbols.push(path.to_string());
+        self
+    }
+
+    pub fn allows(&self, finding: &Finding) -> bool {
+        if self.addresses.contains(&finding.address) {
+            return true;
+        }
+        match &finding.symbol {
+            Some(symbol) if finding.checked => {
+                self.symbols.iter().any(|path| symbol_matches(symbol, path))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `symbol` is named `path`, either as is or mangled by Rust.
+fn symbol_matches(symbol: &str, path: &str) -> bool {
+    if symbol == path {
+        return true;
+    }
+    let mut segments = String::new();
+    for segment in path.split("::") {
+        segments.push_str(&segment.len().to_string());
+        segments.push_str(segment);
+    }
+
+    // v0 mangling ends with the path, after the crate's disambiguator if it has one
+    if symbol.starts_with("_R") {
+        return [format!("_{}", segments), format!("C{}", segments)]
+            .iter()
+            .any(|suffix| symbol.ends_with(suffix.as_str()));
+    }
+    // Legacy mangling follows the path with the hash segment, or ends the name
+    match symbol.strip_prefix(&format!("_ZN{}", segments)) {
+        Some(rest) => rest.starts_with('E') || rest.starts_with(|c: char| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// Finds every `WRPKRU` and `XRSTOR` in the executable sections of an ELF file.
+pub fn scan(elf: &[u8]) -> Result<Vec<Finding>, ScanError> {
+    let elf = Elf::parse(elf)?;
+    let functions = elf.functions()?;
+
+    let mut findings = Vec::new();
+    for section in &elf.sections {
+        if section.flags & SHF_EXECINSTR == 0 || section.kind == SHT_NOBITS {
+            continue;
+        }
+        let name = elf.section_name(section)?;
+        let code = elf.section_data(section)?;
+        for (offset, instruction) in find_instructions(code) {
+            let address = section.address.wrapping_add(offset as u64);
+            let symbol = functions
+                .iter()
+                .find(|function| function.contains(address))
+                .map(|function| function.name.to_string());
+            let checked = instruction == Instruction::Wrpkru
+                && code[offset + WRPKRU.len()..].starts_with(&CHECK);
+            findings.push(Finding {
+                instruction,
+                address,
+                section: name.to_string(),
+                symbol,
+                checked,
+            });
+        }
+    }
+    Ok(findings)
+}
+
+/// Reads and [`scan`]s a binary.
+pub fn scan_file(path: &Path) -> Result<Vec<Finding>, ScanError> {
+    scan(&std::fs::read(path)?)
+}
+
+/// The findings of [`scan`] that `allowed` doesn't allow.
+pub fn unauthorised(elf: &[u8], allowed: &AllowList) -> Result<Vec<Finding>, ScanError> {
+    let mut findings = scan(elf)?;
+    findings.retain(|finding| !allowed.allows(finding));
+    Ok(findings)
+}
+
+/// Offsets of every sequence in `code`, at any alignment.
+fn find_instructions(code: &[u8]) -> Vec<(usize, Instruction)> {
+    let mut found = Vec::new();
+    for offset in 0..code.len() {
+        let rest = &code[offset..];
+        if rest.starts_with(&WRPKRU) {
+            found.push((offset, Instruction::Wrpkru));
+        } else if rest.starts_with(&XRSTOR) {
+            // Register 5 with a memory operand; with a register operand it's LFENCE
+            if let Some(&modrm) = rest.get(2) {
+                if (modrm >> 3) & 0b111 == 5 && modrm >> 6 != 0b11 {
+                    found.push((offset, Instruction::Xrstor));
+                }
+            }
+        }
+    }
+    found
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+struct Function<'a> {
+    name: &'a str,
+    address: u64,
+    size: u64,
+}
+
+impl Function<'_> {
+    fn contains(&self, address: u64) -> bool {
+        address >= self.address && address - self.address < self.size.max(1)
+    }
+}
+
+struct Elf<'a> {
+    data: &'a [u8],
+    sections: Vec<Section>,
+    names: usize,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ScanError> {
+        // Magic, 64-bit class, little-endian
+        if data.get(..6) != Some(&[0x7f, b'E', b'L', b'F', 2, 1][..]) {
+            return Err(ScanError::Unsupported);
+        }
+        let table = read_u64(data, 0x28)? as usize;
+        let entry_len = read_u16(data, 0x3a)? as usize;
+        let count = read_u16(data, 0x3c)? as usize;
+        let names = read_u16(data, 0x3e)? as usize;
+        if count == 0 {
+            return Err(ScanError::Malformed("no section headers"));
+        }
+        if entry_len < SECTION_HEADER_LEN {
+            return Err(ScanError::Malformed("section headers too short"));
+        }
+
+        let mut sections = Vec::with_capacity(count);
+        for index in 0..count {
+            let header = index
+                .checked_mul(entry_len)
+                .and_then(|offset| offset.checked_add(table))
+                .and_then(|start| data.get(start..start.checked_add(SECTION_HEADER_LEN)?))
+                .ok_or(ScanError::Malformed("section header out of bounds"))?;
+            sections.push(Section {
+                name: read_u32(header, 0)?,
+                kind: read_u32(header, 4)?,
+                flags: read_u64(header, 8)?,
+                address: read_u64(header, 16)?,
+                offset: read_u64(header, 24)?,
+                size: read_u64(header, 32)?,
+                link: read_u32(header, 40)?,
+            });
+        }
+        if names >= count {
+            return Err(ScanError::Malformed("section name table out of bounds"));
+        }
+        Ok(Self {
+            data,
+            sections,
+            names,
+        })
+    }
+
+    fn section_data(&self, section: &Section) -> Result<&'a [u8], ScanError> {
+        let start = usize::try_from(section.offset).ok();
+        let end = start.and_then(|start| start.checked_add(usize::try_from(section.size).ok()?));
+        match (start, end) {
+            (Some(start), Some(end)) => self.data.get(start..end),
+            _ => None,
+        }
+        .ok_or(ScanError::Malformed("section out of bounds"))
+    }
+
+    /// The NUL-terminated string at `offset` of the string table `table`.
+    fn string(&self, table: usize, offset: u32) -> Result<&'a str, ScanError> {
+        let table = self
+            .sections
+            .get(table)
+            .ok_or(ScanError::Malformed("string table out of bounds"))?;
+        let bytes = self
+            .section_data(table)?
+            .get(offset as usize..)
+            .ok_or(ScanError::Malformed("string out of bounds"))?;
+        let len = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or(ScanError::Malformed("unterminated string"))?;
+        std::str::from_utf8(&bytes[..len]).map_err(|_| ScanError::Malformed("string isn't UTF-8"))
+    }
+
+    fn section_name(&self, section: &Section) -> Result<&'a str, ScanError> {
+        self.string(self.names, section.name)
+    }
+
+    /// Function symbols of the symbol tables, if the binary has any left.
+    fn functions(&self) -> Result<Vec<Function<'a>>, ScanError> {
+        let mut functions = Vec::new();
+        for table in &self.sections {
+            if table.kind != SHT_SYMTAB && table.kind != SHT_DYNSYM {
+                continue;
+            }
+            let symbols = self.section_data(table)?;
+            for symbol in symbols.chunks_exact(SYMBOL_LEN) {
+                let address = read_u64(symbol, 8)?;
+                if symbol[4] & 0xf != STT_FUNC || address == 0 {
+                    continue;
+                }
+                functions.push(Function {
+                    name: self.string(table.link as usize, read_u32(symbol, 0)?)?,
+                    address,
+                    size: read_u64(symbol, 16)?,
+                });
+            }
+        }
+        Ok(functions)
+    }
+}
+
+fn read<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ScanError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .map(|bytes| {
+            let mut array = [0; N];
+            array.copy_from_slice(bytes);
+            array
+        })
+        .ok_or(ScanError::Malformed("header out of bounds"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ScanError> {
+    read(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ScanError> {
+    read(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ScanError> {
+    read(data, offset).map(u64::from_le_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT_ADDRESS: u64 = 0x401000;
+
+    /// A minimal ELF file with `code` in `.text` and `symbols` as `(name, offset, size)`.
+    fn elf(code: &[u8], symbols: &[(&str, u64, u64)]) -> Vec<u8> {
+        let names = b"\0.text\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+        let mut strings = vec![0];
+        let mut symtab = vec![0; SYMBOL_LEN];
+        for &(name, offset, size) in symbols {
+            symtab.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&[STT_FUNC, 0, 1, 0]);
+            symtab.extend_from_slice(&(TEXT_ADDRESS + offset).to_le_bytes());
+            symtab.extend_from_slice(&size.to_le_bytes());
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+
+        let mut data = vec![0; 64];
+        let mut sections = vec![[0u8; SECTION_HEADER_LEN]];
+        let mut add =
+            |data: &mut Vec<u8>, name: u32, kind: u32, flags: u64, link: u32, body: &[u8]| {
+                let mut header = [0; SECTION_HEADER_LEN];
+                header[..4].copy_from_slice(&name.to_le_bytes());
+                header[4..8].copy_from_slice(&kind.to_le_bytes());
+                header[8..16].copy_from_slice(&flags.to_le_bytes());
+                if flags & SHF_EXECINSTR != 0 {
+                    header[16..24].copy_from_slice(&TEXT_ADDRESS.to_le_bytes());
+                }
+                header[24..32].copy_from_slice(&(data.len() as u64).to_le_bytes());
+                header[32..40].copy_from_slice(&(body.len() as u64).to_le_bytes());
+                header[40..44].copy_from_slice(&link.to_le_bytes());
+                data.extend_from_slice(body);
+                sections.push(header);
+            };
+        add(&mut data, 1, 1, SHF_EXECINSTR, 0, code);
+        add(&mut data, 7, SHT_SYMTAB, 0, 3, &symtab);
+        add(&mut data, 15, 3, 0, 0, &strings);
+        add(&mut data, 23, 3, 0, 0, &names);
+
+        let table = data.len() as u64;
+        for header in &sections {
+            data.extend_from_slice(header);
+        }
+        data[..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1]);
+        data[0x28..0x30].copy_from_slice(&table.to_le_bytes());
+        data[0x3a..0x3c].copy_from_slice(&(SECTION_HEADER_LEN as u16).to_le_bytes());
+        data[0x3c..0x3e].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        data[0x3e..0x40].copy_from_slice(&4u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_finds_unaligned_sequences() {
+        let code = [
+            0x90, 0x0f, 0x01, 0xef, // wrpkru at 1
+            0xb8, 0x0f, 0x01, 0xef, 0x00, // hidden in `mov eax, 0xef010f` at 5
+            0x48, 0x0f, 0xae, 0x29, // xrstor64 [rcx] at 10
+            0x0f, 0xae, 0xe8, // lfence
+            0x0f, 0xae, 0x01, // xrstor would need register 5
+            0x0f, 0x01, // truncated
+        ];
+        let gate = "_ZN13pkey_mprotect6wrpkru17h0123456789abcdefE";
+        let findings = scan(&elf(&code, &[(gate, 0, 4), ("main", 4, 5)])).unwrap();
+
+        let found = findings
+            .iter()
+            .map(|finding| (finding.address - TEXT_ADDRESS, finding.instruction))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                (1, Instruction::Wrpkru),
+                (5, Instruction::Wrpkru),
+                (10, Instruction::Xrstor)
+            ]
+        );
+        assert!(findings.iter().all(|finding| !finding.checked));
+        assert_eq!(findings[0].section, ".text");
+        assert_eq!(findings[0].symbol.as_deref(), Some(gate));
+        assert_eq!(findings[1].symbol.as_deref(), Some("main"));
+        assert_eq!(findings[2].symbol, None);
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let mut code = vec![0x0f, 0x01, 0xef];
+        code.extend_from_slice(&CHECK);
+        code.extend_from_slice(&[0x0f, 0x01, 0xef, 0x0f, 0x01, 0xef]);
+        let elf = elf(
+            &code,
+            &[
+                ("_ZN13pkey_mprotect6wrpkru17h0123456789abcdefE", 0, 9),
+                ("_ZN13pkey_mprotect6wrpkru_evil17h0123456789abcdefE", 9, 6),
+            ],
+        );
+
+        let allowed = AllowList::new().symbol("pkey_mprotect::wrpkru");
+        let found = unauthorised(&elf, &allowed).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(symbol_matches(
+            "_RNvCs8rLeugXMVs2_13pkey_mprotect6wrpkru",
+            "pkey_mprotect::wrpkru"
+        ));
+        assert!(!symbol_matches(
+            "_RNvCs8rLeugXMVs2_13pkey_mprotect6wrpkru",
+            "mprotect::wrpkru"
+        ));
+
+        let allowed = allowed.address(TEXT_ADDRESS + 9).symbol("nothing");
+        let found = unauthorised(&elf, &allowed).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].address, TEXT_ADDRESS + 12);
+
+        // Without its check, the gate's own sequence is no longer allowed
+        let elf = self::elf(
+            &[0x0f, 0x01, 0xef, 0x90],
+            &[("_ZN13pkey_mprotect6wrpkru17h0123456789abcdefE", 0, 4)],
+        );
+        let allowed = AllowList::new().symbol("pkey_mprotect::wrpkru");
+        assert_eq!(unauthorised(&elf, &allowed).unwrap().len(), 1);
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn test_only_gate_in_own_binary() {
+        let binary = std::env::current_exe().unwrap();
+        let findings = scan_file(&binary).unwrap();
+        assert!(findings
+            .iter()
+            .any(|finding| finding.instruction == Instruction::Wrpkru));
+
+        // The byte patterns of these tests end up in the binary as immediates
+        let tests = "4scan5tests";
+        let allowed = AllowList::new().symbol("pkey_mprotect::wrpkru");
+        let found = findings
+            .iter()
+            .filter(|finding| !allowed.allows(finding))
+            .filter(|finding| {
+                !finding
+                    .symbol
+                    .as_deref()
+                    .unwrap_or_default()
+                    .contains(tests)
+            })
+            .collect::<Vec<_>>();
+        assert!(found.is_empty(), "{:?}", found);
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        assert!(matches!(scan(b"#!/bin/sh\n"), Err(ScanError::Unsupported)));
+        let mut truncated = elf(&[0x90], &[]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(matches!(scan(&truncated), Err(ScanError::Malformed(_))));
+    }
+}
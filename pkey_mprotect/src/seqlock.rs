@@ -0,0 +1,331 @@
+//! Values published by one writer at a time and read by many without locks.
+//!
+//! A [`Published`] region starts with a version counter, followed by the
+//! value. Publishing makes the version odd, writes the value and makes it even
+//! again; readers copy the value between two reads of the version and retry
+//! if it was odd or changed in between, since the copy may then be torn. The
+//! writer never waits for readers, and readers only wait while a value is
+//! being written.
+//!
+//! Writers take turns through the pid stored next to the version. If a writer
+//! dies while publishing, the next one takes over once it finds that process
+//! gone; readers wait until then, since the value it left may be torn.
+//!
+//! Mapped from a file descriptor, any number of processes can read what one
+//! of them publishes, e.g. configuration or cached results:
+//!
+//! ```ignore
+//! let config = pkey.make_published_fd::<Config>(fd)?;
+//! config.publish(&Config { workers: 4, ..old });
+//!
+//! // In every other process
+//! let snapshot = config.read();
+//! if snapshot.version > seen {
+//!     apply(&snapshot.value);
+//! }
+//! ```
+//!
+//! Readers only get read access to the region while they copy the value.
+
+use std::mem::{align_of, size_of, MaybeUninit};
+use std::os::fd::RawFd;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::shared::Relocatable;
+use crate::{
+    backoff, is_alive, map_anonymous, map_shared, ProtectionError, ProtectionKeys, PAGE_SIZE,
+    PKEY_DISABLE_WRITE, SPINS_BEFORE_YIELD,
+};
+
+/// Start of the mapping, followed by the value.
+#[repr(C, align(64))]
+struct Header {
+    /// Odd while a value is being written, 0 until the first one is published.
+    version: AtomicU64,
+    /// Pid of the process publishing, 0 if none.
+    writer: AtomicU32,
+}
+
+/// A value read from a [`Published`] region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot<T> {
+    /// How often the value was published before, 0 for the initial zeroes of a file.
+    pub version: u64,
+    pub value: T,
+}
+
+/// A protected region holding the last published `T`, see the [module docs](self).
+pub struct Published<T> {
+    pkey: Arc<ProtectionKeys>,
+    ptr: *mut libc::c_void,
+    len: usize,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T: Relocatable + Copy> Published<T> {
+    /// Offset of the value from the start of the mapping.
+    const VALUE_OFFSET: usize = if align_of::<T>() > size_of::<Header>() {
+        align_of::<T>()
+    } else {
+        size_of::<Header>()
+    };
+
+    /// Bytes of the mapping, which a file has to be at least as long as.
+    pub const LEN: usize =
+        (Self::VALUE_OFFSET + size_of::<T>() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
+    pub(crate) fn new(
+        pkey: &Arc<ProtectionKeys>,
+        initial: &T,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        let ptr = map_anonymous(pkey, Self::LEN)?;
+        let published = Arc::new(Self {
+            pkey: pkey.clone(),
+            ptr,
+            len: Self::LEN,
+            _marker: std::marker::PhantomData,
+        });
+        published.publish(initial);
+        Ok(published)
+    }
+
+    pub(crate) fn new_fd(
+        pkey: &Arc<ProtectionKeys>,
+        fd: RawFd,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        // A fresh file is zeroed, which is version 0 of a zeroed value
+        let ptr = map_shared(pkey, Self::LEN, fd)?;
+        Ok(Arc::new(Self {
+            pkey: pkey.clone(),
+            ptr,
+            len: Self::LEN,
+            _marker: std::marker::PhantomData,
+        }))
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: the mapping starts with the header, which is only accessed
+        // while the key allows it
+        unsafe { &*(self.ptr as *const Header) }
+    }
+
+    fn value(&self) -> *mut T {
+        (self.ptr as usize + Self::VALUE_OFFSET) as *mut T
+    }
+
+    /// Publishes `value` as the next version and returns that version.
+    ///
+    /// Waits while another writer publishes, in this process or any other, and
+    /// takes over from one whose process died meanwhile.
+    pub fn publish(&self, value: &T) -> u64 {
+        let rights = self.pkey.rights();
+        self.pkey.set(0);
+
+        let header = self.header();
+        let pid = std::process::id();
+        let mut spins = 0;
+        let mut owner = 0;
+        while let Err(actual) =
+            header
+                .writer
+                .compare_exchange_weak(owner, pid, Ordering::Acquire, Ordering::Relaxed)
+        {
+            owner = if actual != 0 && spins >= SPINS_BEFORE_YIELD && !is_alive(actual) {
+                actual
+            } else {
+                backoff(&mut spins);
+                0
+            };
+        }
+
+        // Still odd if the last writer died while writing
+        let mut current = header.version.load(Ordering::Relaxed);
+        if current % 2 == 0 {
+            current += 1;
+            header.version.store(current, Ordering::Relaxed);
+            // Readers that see the new value also see the odd version
+            fence(Ordering::Release);
+        }
+
+        // SAFETY: inside the mapping and aligned, and the odd version makes
+        // readers discard what they copy meanwhile
+        unsafe { std::ptr::write_volatile(self.value(), *value) };
+        header.version.store(current + 1, Ordering::Release);
+        header.writer.store(0, Ordering::Release);
+
+        self.pkey.set(rights);
+        (current + 1) / 2
+    }
+
+    /// Copies the last published value, retrying while it's being written.
+    pub fn read(&self) -> Snapshot<T> {
+        let rights = self.pkey.rights();
+        self.pkey.set(PKEY_DISABLE_WRITE);
+
+        let version = &self.header().version;
+        let mut spins = 0;
+        let snapshot = loop {
+            let before = version.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                backoff(&mut spins);
+                continue;
+            }
+            // SAFETY: inside the mapping and aligned; the copy may be torn, so
+            // it's only assumed to be a `T` once the version shows it isn't
+            let value = unsafe { std::ptr::read_volatile(self.value() as *const MaybeUninit<T>) };
+            fence(Ordering::Acquire);
+            if version.load(Ordering::Relaxed) == before {
+                break Snapshot {
+                    version: before / 2,
+                    // SAFETY: nothing was written while copying, and every bit pattern is a `T`
+                    value: unsafe { value.assume_init() },
+                };
+            }
+        };
+
+        self.pkey.set(rights);
+        snapshot
+    }
+}
+
+impl<T> Drop for Published<T> {
+    fn drop(&mut self) {
+        // SAFETY: region still exists, ptr and length were initialized once on creation
+        if unsafe { libc::munmap(self.ptr, self.len) } < 0 {
+            log::error!(
+                "failed to unmap published region: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Published<T> {}
+unsafe impl<T: Send> Sync for Published<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PKEY_DISABLE_ACCESS;
+
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    struct Pair {
+        a: u64,
+        b: [u64; 255],
+    }
+
+    unsafe impl Relocatable for Pair {}
+
+    impl Pair {
+        fn new(n: u64) -> Self {
+            Self { a: n, b: [n; 255] }
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let published = pkey.make_published(&7u32).unwrap();
+        assert_eq!(
+            published.read(),
+            Snapshot {
+                version: 1,
+                value: 7
+            }
+        );
+        assert_eq!(published.publish(&8), 2);
+        assert_eq!(
+            published.read(),
+            Snapshot {
+                version: 2,
+                value: 8
+            }
+        );
+        assert_eq!(Published::<u32>::LEN, PAGE_SIZE);
+        assert_eq!(Published::<[u8; PAGE_SIZE]>::LEN, 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_no_torn_reads() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let published = pkey.make_published(&Pair::new(0)).unwrap();
+
+        let readers = (0..3)
+            .map(|_| {
+                let published = published.clone();
+                std::thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 1000 {
+                        let snapshot = published.read();
+                        assert!(snapshot.value.b.iter().all(|&b| b == snapshot.value.a));
+                        assert!(snapshot.version >= last);
+                        assert_eq!(snapshot.value.a + 1, snapshot.version);
+                        last = snapshot.version;
+                        std::thread::yield_now();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for n in 1..1000 {
+            published.publish(&Pair::new(n));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_shared_mappings() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let (writer, reader) = crate::map_twice(Published::<u64>::LEN, |fd| {
+            pkey.make_published_fd::<u64>(fd).unwrap()
+        });
+        assert_ne!(writer.ptr, reader.ptr);
+
+        assert_eq!(
+            reader.read(),
+            Snapshot {
+                version: 0,
+                value: 0
+            }
+        );
+        writer.publish(&42);
+        assert_eq!(
+            reader.read(),
+            Snapshot {
+                version: 1,
+                value: 42
+            }
+        );
+    }
+
+    #[test]
+    fn test_dead_writer() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let published = pkey.make_published(&1u64).unwrap();
+
+        // A writer that died halfway through publishing
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        pkey.set(0);
+        published.header().writer.store(dead, Ordering::Relaxed);
+        published.header().version.store(3, Ordering::Relaxed);
+        pkey.set(PKEY_DISABLE_ACCESS);
+
+        assert_eq!(published.publish(&2), 2);
+        assert_eq!(
+            published.read(),
+            Snapshot {
+                version: 2,
+                value: 2
+            }
+        );
+        pkey.set(0);
+        assert_eq!(published.header().writer.load(Ordering::Relaxed), 0);
+        pkey.set(PKEY_DISABLE_ACCESS);
+    }
+}
@@ -0,0 +1,753 @@
+//! Pointers and collections that live inside an [`Arena`](crate::Arena).
+//!
+//! A pointer written into shared memory means nothing in another process,
+//! which maps the memory at another address. The types here store offsets
+//! from the start of the arena instead, and every access goes through an
+//! [`ArenaGuard`] that checks the offsets against the bounds of the arena, so
+//! a corrupt or hostile structure fails with [`SharedError::OutOfBounds`]
+//! rather than reading outside of it.
+//!
+//! Structures are built by value and moved into the arena with
+//! [`RelPtr::new()`], whose offset is all the other side needs:
+//!
+//! ```ignore
+//! let guard = arena.lock();
+//! let mut counts = SharedHashMap::<SharedString, u64>::new();
+//! counts.insert(&guard, "hello", 2)?;
+//! let root = RelPtr::new(&guard, counts)?.offset();
+//!
+//! // In the other process, with its own mapping of the arena
+//! let counts = RelPtr::<SharedHashMap<SharedString, u64>>::from_offset(root);
+//! assert_eq!(counts.get(&guard)?.get(&guard, "hello")?, Some(&2));
+//! ```
+//!
+//! Offsets are only checked for bounds, not for what they point to: as with
+//! any memory other processes can write, readers must not look at a structure
+//! while anyone still changes it. Hand the offset over once it's complete.
+
+use std::alloc::Layout;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+
+use crate::arena::{AllocError, ArenaGuard, Block};
+
+/// Failure to build or read a shared structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum SharedError {
+    #[error("Arena is out of memory")]
+    OutOfMemory,
+    #[error("Shared structure points outside of the arena")]
+    OutOfBounds,
+    #[error("Shared string is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+impl From<AllocError> for SharedError {
+    fn from(_: AllocError) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+/// Types that can be placed in an arena and read back by another process.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value, since other processes can write
+/// anything. The type must not hold absolute pointers, and must not need to
+/// be dropped, since the arena never drops what it holds.
+pub unsafe trait Relocatable: Sized {}
+
+macro_rules! relocatable {
+    ($($ty:ty),*) => {
+        $(unsafe impl Relocatable for $ty {})*
+    };
+}
+
+relocatable!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Relocatable, const N: usize> Relocatable for [T; N] {}
+
+/// Address of `len` values of `T` at `offset`, if they are inside the arena and aligned.
+fn locate<T>(guard: &ArenaGuard<'_>, offset: usize, len: usize) -> Result<*mut T, SharedError> {
+    let block = Block {
+        offset,
+        len: len
+            .checked_mul(size_of::<T>())
+            .ok_or(SharedError::OutOfBounds)?,
+    };
+    let ptr = guard.checked(block).ok_or(SharedError::OutOfBounds)?;
+    if ptr as usize % align_of::<T>() != 0 {
+        return Err(SharedError::OutOfBounds);
+    }
+    Ok(ptr as *mut T)
+}
+
+/// Offset of a `T` in an arena; 0 is null.
+#[repr(transparent)]
+pub struct RelPtr<T> {
+    offset: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Relocatable> Relocatable for RelPtr<T> {}
+
+impl<T> Clone for RelPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for RelPtr<T> {}
+
+impl<T> std::fmt::Debug for RelPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("RelPtr").field(&self.offset).finish()
+    }
+}
+
+impl<T> Default for RelPtr<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> RelPtr<T> {
+    pub fn null() -> Self {
+        Self::from_offset(0)
+    }
+
+    /// A pointer to the `T` at `offset`, e.g. one received from another process.
+    ///
+    /// The offset is only checked once the pointer is dereferenced.
+    pub fn from_offset(offset: usize) -> Self {
+        Self {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.offset == 0
+    }
+}
+
+impl<T: Relocatable> RelPtr<T> {
+    /// Moves `value` into the arena.
+    pub fn new(guard: &ArenaGuard<'_>, value: T) -> Result<Self, SharedError> {
+        let block = guard.allocate_block(Layout::new::<T>())?;
+        let ptr = locate::<T>(guard, block.offset, 1)?;
+        // SAFETY: the block was just allocated for a `T`, so nobody else refers to it
+        unsafe { ptr.write(value) };
+        Ok(Self::from_offset(block.offset))
+    }
+
+    /// The value pointed to, if it's inside the arena.
+    pub fn get<'g>(&self, guard: &'g ArenaGuard<'_>) -> Result<&'g T, SharedError> {
+        let ptr = locate::<T>(guard, self.offset, 1)?;
+        // SAFETY: inside the arena and aligned, and every bit pattern is a valid `T`
+        Ok(unsafe { &*ptr })
+    }
+}
+
+/// A growable array in an arena.
+///
+/// Growing moves the elements unless nothing was allocated after them, and
+/// leaves the old elements allocated unless they were the last allocation.
+#[repr(C)]
+pub struct SharedVec<T> {
+    offset: usize,
+    len: usize,
+    capacity: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Relocatable> Relocatable for SharedVec<T> {}
+
+impl<T> std::fmt::Debug for SharedVec<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedVec")
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl<T: Relocatable> Default for SharedVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Relocatable> SharedVec<T> {
+    pub fn new() -> Self {
+        Self {
+            offset: 0,
+            len: 0,
+            capacity: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_capacity(guard: &ArenaGuard<'_>, capacity: usize) -> Result<Self, SharedError> {
+        let mut vec = Self::new();
+        vec.reserve(guard, capacity)?;
+        Ok(vec)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Makes room for at least `additional` more elements.
+    pub fn reserve(
+        &mut self,
+        guard: &ArenaGuard<'_>,
+        additional: usize,
+    ) -> Result<(), SharedError> {
+        let needed = self
+            .len
+            .checked_add(additional)
+            .ok_or(SharedError::OutOfMemory)?;
+        if needed <= self.capacity {
+            return Ok(());
+        }
+
+        let capacity = needed.max(self.capacity.saturating_mul(2)).max(4);
+        let layout = Layout::array::<T>(capacity).map_err(|_| SharedError::OutOfMemory)?;
+        let old = Block {
+            offset: self.offset,
+            len: self.capacity * size_of::<T>(),
+        };
+        if self.capacity == 0 || !guard.grow_in_place(old, layout.size()) {
+            let new = guard.allocate_block(layout)?;
+            if self.len > 0 {
+                let from = locate::<T>(guard, self.offset, self.len)?;
+                let to = locate::<T>(guard, new.offset, self.len)?;
+                // SAFETY: both are inside the arena, and the new block was just allocated
+                unsafe { std::ptr::copy_nonoverlapping(from, to, self.len) };
+            }
+            if self.capacity > 0 {
+                guard.deallocate_block(old);
+            }
+            self.offset = new.offset;
+        }
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    pub fn push(&mut self, guard: &ArenaGuard<'_>, value: T) -> Result<(), SharedError> {
+        self.reserve(guard, 1)?;
+        let ptr = locate::<T>(guard, self.offset, self.capacity)?;
+        // SAFETY: the slot is inside the elements, which only this vector refers to
+        unsafe { ptr.add(self.len).write(value) };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// The elements, if they are inside the arena.
+    pub fn as_slice<'a>(&'a self, guard: &'a ArenaGuard<'_>) -> Result<&'a [T], SharedError> {
+        if self.len == 0 {
+            return Ok(&[]);
+        }
+        let ptr = locate::<T>(guard, self.offset, self.len)?;
+        // SAFETY: inside the arena and aligned, and every bit pattern is a valid `T`
+        Ok(unsafe { std::slice::from_raw_parts(ptr, self.len) })
+    }
+
+    /// The elements to change, if they are inside the arena.
+    pub fn as_mut_slice<'a>(
+        &'a mut self,
+        guard: &'a ArenaGuard<'_>,
+    ) -> Result<&'a mut [T], SharedError> {
+        if self.len == 0 {
+            return Ok(&mut []);
+        }
+        let ptr = locate::<T>(guard, self.offset, self.len)?;
+        // SAFETY: as in `as_slice`, and borrowing `self` mutably keeps other slices away
+        Ok(unsafe { std::slice::from_raw_parts_mut(ptr, self.len) })
+    }
+}
+
+impl<T: Relocatable + Copy> SharedVec<T> {
+    pub fn from_slice(guard: &ArenaGuard<'_>, values: &[T]) -> Result<Self, SharedError> {
+        let mut vec = Self::new();
+        vec.extend_from_slice(guard, values)?;
+        Ok(vec)
+    }
+
+    pub fn extend_from_slice(
+        &mut self,
+        guard: &ArenaGuard<'_>,
+        values: &[T],
+    ) -> Result<(), SharedError> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        self.reserve(guard, values.len())?;
+        let ptr = locate::<T>(guard, self.offset, self.capacity)?;
+        // SAFETY: the slots are inside the elements, which only this vector refers to
+        unsafe { std::ptr::copy_nonoverlapping(values.as_ptr(), ptr.add(self.len), values.len()) };
+        self.len += values.len();
+        Ok(())
+    }
+
+    /// Grows the vector to `len` copies of `value`.
+    fn resize(&mut self, guard: &ArenaGuard<'_>, len: usize, value: T) -> Result<(), SharedError> {
+        self.reserve(guard, len.saturating_sub(self.len))?;
+        while self.len < len {
+            self.push(guard, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A UTF-8 string in an arena, checked to be valid whenever it's read.
+#[repr(transparent)]
+#[derive(Debug, Default)]
+pub struct SharedString {
+    bytes: SharedVec<u8>,
+}
+
+unsafe impl Relocatable for SharedString {}
+
+impl SharedString {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_str(guard: &ArenaGuard<'_>, s: &str) -> Result<Self, SharedError> {
+        Ok(Self {
+            bytes: SharedVec::from_slice(guard, s.as_bytes())?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn push_str(&mut self, guard: &ArenaGuard<'_>, s: &str) -> Result<(), SharedError> {
+        self.bytes.extend_from_slice(guard, s.as_bytes())
+    }
+
+    pub fn as_str<'a>(&'a self, guard: &'a ArenaGuard<'_>) -> Result<&'a str, SharedError> {
+        std::str::from_utf8(self.bytes.as_slice(guard)?).map_err(|_| SharedError::InvalidUtf8)
+    }
+}
+
+/// Keys of a [`SharedHashMap`], looked up by a borrowed form.
+pub trait SharedKey: Relocatable {
+    type Borrowed: ?Sized + Eq + Hash;
+
+    /// The key as it's looked up, if it's inside the arena.
+    fn borrow<'a>(&'a self, guard: &'a ArenaGuard<'_>) -> Result<&'a Self::Borrowed, SharedError>;
+
+    /// Copies a key into the arena.
+    fn store(key: &Self::Borrowed, guard: &ArenaGuard<'_>) -> Result<Self, SharedError>;
+}
+
+impl SharedKey for SharedString {
+    type Borrowed = str;
+
+    fn borrow<'a>(&'a self, guard: &'a ArenaGuard<'_>) -> Result<&'a str, SharedError> {
+        self.as_str(guard)
+    }
+
+    fn store(key: &str, guard: &ArenaGuard<'_>) -> Result<Self, SharedError> {
+        Self::from_str(guard, key)
+    }
+}
+
+macro_rules! shared_key {
+    ($($ty:ty),*) => {
+        $(impl SharedKey for $ty {
+            type Borrowed = $ty;
+
+            fn borrow<'a>(&'a self, _: &'a ArenaGuard<'_>) -> Result<&'a $ty, SharedError> {
+                Ok(self)
+            }
+
+            fn store(key: &$ty, _: &ArenaGuard<'_>) -> Result<Self, SharedError> {
+                Ok(*key)
+            }
+        })*
+    };
+}
+
+shared_key!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// FNV-1a, which hashes the same in every process, unlike `RandomState`.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+fn hash<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[repr(C)]
+struct Entry<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+}
+
+unsafe impl<K: Relocatable, V: Relocatable> Relocatable for Entry<K, V> {}
+
+/// Where a key is, or would be, in the slots of a [`SharedHashMap`].
+enum Slot {
+    Occupied(usize),
+    Vacant(usize),
+}
+
+/// A hash map in an arena.
+///
+/// Entries are kept in insertion order, next to a table of slots that holds
+/// the index of an entry plus one, or 0 for an empty slot, probed linearly.
+/// Both sides of a map have to be built from the same version of this crate
+/// for lookups to agree on the hashes; iterating doesn't need them.
+#[repr(C)]
+pub struct SharedHashMap<K, V> {
+    entries: SharedVec<Entry<K, V>>,
+    slots: SharedVec<u32>,
+}
+
+unsafe impl<K: SharedKey, V: Relocatable> Relocatable for SharedHashMap<K, V> {}
+
+impl<K, V> std::fmt::Debug for SharedHashMap<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedHashMap")
+            .field("entries", &self.entries)
+            .field("slots", &self.slots)
+            .finish()
+    }
+}
+
+impl<K: SharedKey, V: Relocatable> Default for SharedHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: SharedKey, V: Relocatable> SharedHashMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            entries: SharedVec::new(),
+            slots: SharedVec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Finds the slot of `key`, or `None` if there is no room for it.
+    fn find(
+        &self,
+        guard: &ArenaGuard<'_>,
+        hash: u64,
+        key: &K::Borrowed,
+    ) -> Result<Option<Slot>, SharedError> {
+        let slots = self.slots.as_slice(guard)?;
+        let entries = self.entries.as_slice(guard)?;
+        if slots.is_empty() {
+            return Ok(None);
+        }
+        if !slots.len().is_power_of_two() {
+            return Err(SharedError::OutOfBounds);
+        }
+
+        let mask = slots.len() - 1;
+        for probe in 0..slots.len() {
+            let slot = (hash as usize).wrapping_add(probe) & mask;
+            let index = match slots[slot] {
+                0 => return Ok(Some(Slot::Vacant(slot))),
+                index => index as usize - 1,
+            };
+            let entry = entries.get(index).ok_or(SharedError::OutOfBounds)?;
+            if entry.hash == hash && entry.key.borrow(guard)? == key {
+                return Ok(Some(Slot::Occupied(index)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get<'a>(
+        &'a self,
+        guard: &'a ArenaGuard<'_>,
+        key: &K::Borrowed,
+    ) -> Result<Option<&'a V>, SharedError> {
+        match self.find(guard, hash(key), key)? {
+            Some(Slot::Occupied(index)) => Ok(Some(&self.entries.as_slice(guard)?[index].value)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn get_mut<'a>(
+        &'a mut self,
+        guard: &'a ArenaGuard<'_>,
+        key: &K::Borrowed,
+    ) -> Result<Option<&'a mut V>, SharedError> {
+        match self.find(guard, hash(key), key)? {
+            Some(Slot::Occupied(index)) => {
+                Ok(Some(&mut self.entries.as_mut_slice(guard)?[index].value))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaced.
+    pub fn insert(
+        &mut self,
+        guard: &ArenaGuard<'_>,
+        key: &K::Borrowed,
+        value: V,
+    ) -> Result<Option<V>, SharedError> {
+        let hash = hash(key);
+        // Kept at most three quarters full, so probes stay short
+        if (self.entries.len() + 1) * 4 > self.slots.len() * 3 {
+            self.rehash(guard, (self.slots.len() * 2).max(8))?;
+        }
+
+        match self.find(guard, hash, key)? {
+            Some(Slot::Occupied(index)) => {
+                let entry = &mut self.entries.as_mut_slice(guard)?[index];
+                Ok(Some(std::mem::replace(&mut entry.value, value)))
+            }
+            Some(Slot::Vacant(slot)) => {
+                let index =
+                    u32::try_from(self.entries.len() + 1).map_err(|_| SharedError::OutOfMemory)?;
+                let key = K::store(key, guard)?;
+                self.entries.push(guard, Entry { hash, key, value })?;
+                self.slots.as_mut_slice(guard)?[slot] = index;
+                Ok(None)
+            }
+            None => Err(SharedError::OutOfBounds),
+        }
+    }
+
+    /// Moves the slots to a table of `len`, which has to be a power of two.
+    fn rehash(&mut self, guard: &ArenaGuard<'_>, len: usize) -> Result<(), SharedError> {
+        let mut slots = SharedVec::with_capacity(guard, len)?;
+        slots.resize(guard, len, 0)?;
+        {
+            let table = slots.as_mut_slice(guard)?;
+            for (index, entry) in self.entries.as_slice(guard)?.iter().enumerate() {
+                let mut slot = entry.hash as usize & (len - 1);
+                while table[slot] != 0 {
+                    slot = (slot + 1) & (len - 1);
+                }
+                table[slot] = index as u32 + 1;
+            }
+        }
+        self.slots = slots;
+        Ok(())
+    }
+
+    /// Entries in the order they were inserted.
+    pub fn iter<'a>(&'a self, guard: &'a ArenaGuard<'_>) -> Result<Iter<'a, K, V>, SharedError> {
+        Ok(Iter {
+            guard,
+            entries: self.entries.as_slice(guard)?.iter(),
+        })
+    }
+}
+
+/// See [`SharedHashMap::iter()`]
+pub struct Iter<'a, K, V> {
+    guard: &'a ArenaGuard<'a>,
+    entries: std::slice::Iter<'a, Entry<K, V>>,
+}
+
+impl<'a, K: SharedKey, V> Iterator for Iter<'a, K, V> {
+    type Item = Result<(&'a K::Borrowed, &'a V), SharedError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.next()?;
+        Some(entry.key.borrow(self.guard).map(|key| (key, &entry.value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProtectionKeys, PAGE_SIZE};
+
+    #[test]
+    fn test_vec_and_string() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let arena = pkey.make_arena(PAGE_SIZE).unwrap();
+        let guard = arena.lock();
+
+        let mut numbers = SharedVec::<u64>::new();
+        for i in 0..100 {
+            numbers.push(&guard, i).unwrap();
+        }
+        let mut words = SharedVec::new();
+        words
+            .push(&guard, SharedString::from_str(&guard, "grüße").unwrap())
+            .unwrap();
+        let mut word = SharedString::new();
+        word.push_str(&guard, "hello").unwrap();
+        word.push_str(&guard, " world").unwrap();
+        words.push(&guard, word).unwrap();
+
+        let numbers = RelPtr::new(&guard, numbers).unwrap();
+        let words = RelPtr::new(&guard, words).unwrap();
+        let numbers = RelPtr::<SharedVec<u64>>::from_offset(numbers.offset());
+        assert_eq!(
+            numbers.get(&guard).unwrap().as_slice(&guard).unwrap(),
+            &(0..100).collect::<Vec<u64>>()[..]
+        );
+        let words = words.get(&guard).unwrap().as_slice(&guard).unwrap();
+        assert_eq!(words[0].as_str(&guard), Ok("grüße"));
+        assert_eq!(words[1].as_str(&guard), Ok("hello world"));
+
+        let invalid = SharedVec::from_slice(&guard, b"caf\xc3").unwrap();
+        let invalid =
+            RelPtr::<SharedString>::from_offset(RelPtr::new(&guard, invalid).unwrap().offset());
+        assert_eq!(
+            invalid.get(&guard).unwrap().as_str(&guard),
+            Err(SharedError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn test_bounds() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let arena = pkey.make_arena(PAGE_SIZE).unwrap();
+        let guard = arena.lock();
+
+        let value = RelPtr::new(&guard, 7u64).unwrap();
+        assert_eq!(value.get(&guard), Ok(&7));
+        for offset in [0, 8, value.offset() + 1, 2 * PAGE_SIZE - 4, usize::MAX] {
+            assert_eq!(
+                RelPtr::<u64>::from_offset(offset).get(&guard),
+                Err(SharedError::OutOfBounds),
+                "offset {}",
+                offset
+            );
+        }
+
+        // A vector that claims more elements than fit into the arena
+        let mut forged = [0usize; 3];
+        forged[0] = value.offset();
+        forged[1] = usize::MAX / 2;
+        let forged = RelPtr::new(&guard, forged).unwrap();
+        let forged = RelPtr::<SharedVec<u64>>::from_offset(forged.offset());
+        assert_eq!(
+            forged.get(&guard).unwrap().as_slice(&guard),
+            Err(SharedError::OutOfBounds)
+        );
+
+        let mut full = SharedVec::<u8>::new();
+        assert_eq!(
+            full.reserve(&guard, arena.capacity()),
+            Err(SharedError::OutOfMemory)
+        );
+    }
+
+    #[test]
+    fn test_hash_map() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let arena = pkey.make_arena(16 * PAGE_SIZE).unwrap();
+        let guard = arena.lock();
+
+        let mut counts = SharedHashMap::<SharedString, u64>::new();
+        for i in 0..500 {
+            let word = format!("word{}", i % 200);
+            match counts.get_mut(&guard, &word).unwrap() {
+                Some(count) => *count += 1,
+                None => assert_eq!(counts.insert(&guard, &word, 1), Ok(None)),
+            }
+        }
+        assert_eq!(counts.insert(&guard, "word0", 10), Ok(Some(3)));
+        assert_eq!(counts.len(), 200);
+
+        let root = RelPtr::new(&guard, counts).unwrap();
+        let counts = root.get(&guard).unwrap();
+        assert_eq!(counts.get(&guard, "word0"), Ok(Some(&10)));
+        assert_eq!(counts.get(&guard, "word199"), Ok(Some(&2)));
+        assert_eq!(counts.get(&guard, "word200"), Ok(None));
+        let words = counts
+            .iter(&guard)
+            .unwrap()
+            .map(|entry| entry.unwrap().0.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(words.len(), 200);
+        assert_eq!(words[..2], ["word0", "word1"]);
+
+        let mut squares = SharedHashMap::<u32, u64>::new();
+        for i in 0..100u32 {
+            squares.insert(&guard, &i, (i * i).into()).unwrap();
+        }
+        assert_eq!(squares.get(&guard, &9), Ok(Some(&81)));
+    }
+
+    #[test]
+    fn test_shared_mappings() {
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let (writer, reader) = crate::map_twice(2 * PAGE_SIZE, |fd| {
+            pkey.make_arena_fd(PAGE_SIZE, fd).unwrap()
+        });
+
+        let root = {
+            let guard = writer.lock();
+            let mut counts = SharedHashMap::<SharedString, u64>::new();
+            counts.insert(&guard, "hello", 2).unwrap();
+            counts.insert(&guard, "world", 1).unwrap();
+            RelPtr::new(&guard, counts).unwrap().offset()
+        };
+
+        // Offsets mean the same at the other address
+        let guard = reader.lock();
+        let counts = RelPtr::<SharedHashMap<SharedString, u64>>::from_offset(root);
+        let counts = counts.get(&guard).unwrap();
+        assert_eq!(counts.get(&guard, "hello"), Ok(Some(&2)));
+        assert_eq!(
+            counts
+                .iter(&guard)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            [("hello", &2), ("world", &1)]
+        );
+    }
+}
@@ -0,0 +1,490 @@
+//! More protection domains than the CPU has keys, in the style of libmpk.
+//!
+//! [`VirtualKeys`] owns a fixed number of hardware keys and hands out any
+//! number of [`VirtualKey`]s. A virtual key is only backed by a hardware key
+//! while one of its regions is locked or until the hardware key is needed
+//! elsewhere: locking a region of a key that isn't resident takes a free
+//! hardware key, or evicts the key that was used least recently by tagging all
+//! of its pages with a reserved key that no thread ever gets rights for.
+//!
+//! Every eviction costs a `pkey_mprotect` call per page on both sides, which
+//! [`VirtualKeys::stats()`] keeps track of.
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use crate::{map_anonymous, protect, ProtectionError, ProtectionKeys, PAGE_SIZE};
+
+/// Counters of [`VirtualKeys`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VirtualKeyStats {
+    /// Virtual keys alive.
+    pub keys: usize,
+    /// Virtual keys backed by a hardware key.
+    pub resident: usize,
+    /// Locks of a resident key.
+    pub hits: u64,
+    /// Locks that had to make a key resident first.
+    pub misses: u64,
+    /// Keys that lost their hardware key to another.
+    pub evictions: u64,
+    /// Pages tagged with another key while making keys resident.
+    pub retagged_pages: u64,
+    /// Time spent tagging those pages.
+    pub retag_ns: u64,
+}
+
+struct Slot {
+    key: Arc<ProtectionKeys>,
+    /// The virtual key using the hardware key.
+    owner: Option<usize>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct KeyState {
+    slot: Option<usize>,
+    /// Addresses of the key's pages.
+    pages: Vec<usize>,
+    /// Regions that are locked right now, which keep the key resident.
+    pins: usize,
+}
+
+struct State {
+    slots: Vec<Slot>,
+    keys: HashMap<usize, KeyState>,
+    next_id: usize,
+    clock: u64,
+    stats: VirtualKeyStats,
+}
+
+/// Hardware keys shared by any number of [`VirtualKey`]s.
+pub struct VirtualKeys {
+    /// Tags the pages of keys that aren't resident.
+    locked: Arc<ProtectionKeys>,
+    state: Mutex<State>,
+}
+
+impl VirtualKeys {
+    /// Allocates `hardware_keys` keys for virtual keys and one to lock away evicted ones.
+    ///
+    /// Keys are allocated like [`ProtectionKeys::new`] does, so without
+    /// protection keys nothing is protected unless `require_protected` makes
+    /// that an error.
+    ///
+    /// # Panics
+    ///
+    /// If `hardware_keys` is zero.
+    pub fn new(
+        require_protected: bool,
+        hardware_keys: usize,
+    ) -> Result<Arc<Self>, ProtectionError> {
+        assert!(hardware_keys > 0, "virtual keys need a hardware key");
+        let locked = ProtectionKeys::new(require_protected)?;
+        let slots = (0..hardware_keys)
+            .map(|_| {
+                Ok(Slot {
+                    key: ProtectionKeys::new(require_protected)?,
+                    owner: None,
+                    last_used: 0,
+                })
+            })
+            .collect::<Result<Vec<_>, ProtectionError>>()?;
+
+        Ok(Arc::new(Self {
+            locked,
+            state: Mutex::new(State {
+                slots,
+                keys: HashMap::new(),
+                next_id: 0,
+                clock: 0,
+                stats: VirtualKeyStats::default(),
+            }),
+        }))
+    }
+
+    /// Creates a virtual key, which takes no hardware key until one of its regions is locked.
+    pub fn key(self: &Arc<Self>) -> Arc<VirtualKey> {
+        let mut state = self.state();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.keys.insert(id, KeyState::default());
+        Arc::new(VirtualKey {
+            keys: self.clone(),
+            id,
+        })
+    }
+
+    pub fn stats(&self) -> VirtualKeyStats {
+        let state = self.state();
+        VirtualKeyStats {
+            keys: state.keys.len(),
+            resident: state
+                .slots
+                .iter()
+                .filter(|slot| slot.owner.is_some())
+                .count(),
+            ..state.stats
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Makes `id` resident and keeps it so until [`unpin`](Self::unpin), returning its hardware key.
+    fn pin(&self, id: usize) -> Result<Arc<ProtectionKeys>, ProtectionError> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        state.clock += 1;
+        let clock = state.clock;
+
+        if let Some(index) = state.keys[&id].slot {
+            state.stats.hits += 1;
+            return Ok(state.pin(id, index, clock));
+        }
+        state.stats.misses += 1;
+
+        // A free hardware key, or else the least recently used one that isn't locked
+        let keys = &state.keys;
+        let index = state
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.owner.map_or(true, |owner| keys[&owner].pins == 0))
+            .min_by_key(|(_, slot)| (slot.owner.is_some(), slot.last_used))
+            .map(|(index, _)| index)
+            .ok_or(ProtectionError::KeysInUse)?;
+
+        let start = Instant::now();
+        let hardware = &state.slots[index].key;
+        // The victim keeps its hardware key unless all of its pages could be locked away
+        if let Some(victim) = state.slots[index].owner {
+            let pages = &state.keys[&victim].pages;
+            retag(pages, &self.locked, hardware, &mut state.stats)?;
+            state
+                .keys
+                .get_mut(&victim)
+                .expect("slot owned by a dropped key")
+                .slot = None;
+            state.slots[index].owner = None;
+            state.stats.evictions += 1;
+        }
+        let hardware = &state.slots[index].key;
+        let key = state.keys.get_mut(&id).expect("pinned a dropped key");
+        retag(&key.pages, hardware, &self.locked, &mut state.stats)?;
+        key.slot = Some(index);
+        state.slots[index].owner = Some(id);
+        state.stats.retag_ns += start.elapsed().as_nanos() as u64;
+
+        Ok(state.pin(id, index, clock))
+    }
+
+    fn unpin(&self, id: usize) {
+        let mut state = self.state();
+        if let Some(key) = state.keys.get_mut(&id) {
+            key.pins -= 1;
+        }
+    }
+}
+
+impl State {
+    fn pin(&mut self, id: usize, index: usize, clock: u64) -> Arc<ProtectionKeys> {
+        self.keys.get_mut(&id).expect("pinned a dropped key").pins += 1;
+        let slot = &mut self.slots[index];
+        slot.last_used = clock;
+        slot.key.clone()
+    }
+}
+
+/// Tags every page at `pages` with `key`, or none of them: if a page fails, the
+/// pages before it are tagged with `previous` again.
+fn retag(
+    pages: &[usize],
+    key: &ProtectionKeys,
+    previous: &ProtectionKeys,
+    stats: &mut VirtualKeyStats,
+) -> Result<(), ProtectionError> {
+    for (done, &page) in pages.iter().enumerate() {
+        if let Err(e) = protect(page as *mut libc::c_void, PAGE_SIZE, key) {
+            for &page in &pages[..done] {
+                if let Err(e) = protect(page as *mut libc::c_void, PAGE_SIZE, previous) {
+                    log::error!("failed to restore the key of a page: {}", e);
+                }
+            }
+            return Err(e);
+        }
+        stats.retagged_pages += 1;
+    }
+    Ok(())
+}
+
+/// A protection domain of [`VirtualKeys`], which can be backed by any of its hardware keys.
+pub struct VirtualKey {
+    keys: Arc<VirtualKeys>,
+    id: usize,
+}
+
+impl VirtualKey {
+    /// Arc with the virtual key is cloned so it is safe to keep only the region.
+    pub fn make_region<T>(
+        self: &Arc<Self>,
+        initial: T,
+    ) -> Result<Arc<VirtualRegion<T>>, ProtectionError>
+    where
+        T: Sized,
+    {
+        VirtualRegion::new(self, initial)
+    }
+
+    /// Keeps the key resident while the returned pin lives.
+    fn pin(&self) -> Result<Pin<'_>, ProtectionError> {
+        Ok(Pin {
+            hardware: self.keys.pin(self.id)?,
+            key: self,
+        })
+    }
+}
+
+impl Drop for VirtualKey {
+    fn drop(&mut self) {
+        let mut state = self.keys.state();
+        if let Some(index) = state.keys.remove(&self.id).and_then(|key| key.slot) {
+            state.slots[index].owner = None;
+        }
+    }
+}
+
+struct Pin<'a> {
+    key: &'a VirtualKey,
+    hardware: Arc<ProtectionKeys>,
+}
+
+impl Drop for Pin<'_> {
+    fn drop(&mut self) {
+        self.key.keys.unpin(self.key.id);
+    }
+}
+
+/// Protected memory page of a [`VirtualKey`] with typed access to its data
+pub struct VirtualRegion<T> {
+    key: Arc<VirtualKey>,
+    ptr: *mut libc::c_void,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> VirtualRegion<T> {
+    const _ASSERT: () = assert!(std::mem::size_of::<T>() <= PAGE_SIZE);
+
+    fn new(key: &Arc<VirtualKey>, initial: T) -> Result<Arc<Self>, ProtectionError> {
+        let () = Self::_ASSERT;
+        let pin = key.pin()?;
+        let ptr = map_anonymous(&pin.hardware, PAGE_SIZE)?;
+        {
+            let mut state = key.keys.state();
+            let pages = &mut state.keys.get_mut(&key.id).expect("key is alive").pages;
+            pages.push(ptr as usize);
+        }
+
+        // SAFETY: ptr is always aligned to PAGE_SIZE (4KB) and not null
+        pin.hardware
+            .with_access(|| unsafe { (ptr as *mut T).write(initial) });
+
+        Ok(Arc::new(Self {
+            key: key.clone(),
+            ptr,
+            _marker: std::marker::PhantomData,
+        }))
+    }
+
+    /// Creates region guard with read-only access to the data.
+    ///
+    /// Fails with [`ProtectionError::KeysInUse`] if the key isn't resident and
+    /// every hardware key has a locked region.
+    pub fn lock(&'_ self) -> Result<VirtualRegionGuard<'_, T>, ProtectionError> {
+        let pin = self.key.pin()?;
+        let rights = pin.hardware.rights();
+        pin.hardware.set(0);
+        Ok(VirtualRegionGuard {
+            region: self,
+            pin,
+            rights,
+            _marker: std::marker::PhantomData,
+        })
+    }
+}
+
+impl<T> Drop for VirtualRegion<T> {
+    fn drop(&mut self) {
+        match self.key.pin() {
+            // SAFETY: region still exists, properly aligned and accessible to read/write
+            Ok(pin) => pin
+                .hardware
+                .with_access(|| unsafe { std::ptr::drop_in_place(self.ptr as *mut T) }),
+            Err(e) => log::error!("leaking region data: {}", e),
+        }
+
+        {
+            let mut state = self.key.keys.state();
+            if let Some(key) = state.keys.get_mut(&self.key.id) {
+                key.pages.retain(|&page| page != self.ptr as usize);
+            }
+        }
+
+        // SAFETY: region still exists, ptr and length were initialized once on creation
+        if unsafe { libc::munmap(self.ptr, PAGE_SIZE) } < 0 {
+            log::error!(
+                "failed to unmap region: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+unsafe impl<T: Sync> Sync for VirtualRegion<T> {}
+unsafe impl<T: Send> Send for VirtualRegion<T> {}
+
+/// See [`VirtualRegion::lock()`]
+pub struct VirtualRegionGuard<'a, T> {
+    region: &'a VirtualRegion<T>,
+    pin: Pin<'a>,
+    /// Rights of the hardware key before the guard, restored after it so guards
+    /// of regions sharing the key can nest.
+    rights: usize,
+    _marker: std::marker::PhantomData<*const u8>,
+}
+
+impl<T> Deref for VirtualRegionGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: ptr always points to the allocated page
+        unsafe { &*(self.region.ptr as *const T) }
+    }
+}
+
+impl<T> Drop for VirtualRegionGuard<'_, T> {
+    fn drop(&mut self) {
+        self.pin.hardware.set(self.rights);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The protection key of the mapping at `ptr`, as the kernel reports it.
+    fn key_of(ptr: *const libc::c_void) -> Option<i32> {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut inside = false;
+        for line in smaps.lines() {
+            let range = line.split(' ').next().unwrap_or_default();
+            if let Some((start, end)) = range.split_once('-') {
+                if let (Ok(start), Ok(end)) = (
+                    usize::from_str_radix(start, 16),
+                    usize::from_str_radix(end, 16),
+                ) {
+                    inside = (start..end).contains(&(ptr as usize));
+                    continue;
+                }
+            }
+            if let (true, Some(key)) = (inside, line.strip_prefix("ProtectionKey:")) {
+                return key.trim().parse().ok();
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let keys = VirtualKeys::new(false, 2).unwrap();
+        let regions = (0..5u64)
+            .map(|i| keys.key().make_region(i).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(keys.stats().keys, 5);
+        assert_eq!(keys.stats().resident, 2);
+
+        for _ in 0..2 {
+            for (i, region) in regions.iter().enumerate() {
+                assert_eq!(*region.lock().unwrap(), i as u64);
+            }
+        }
+        // Only the last two regions were used since, so locking the last is a hit
+        let before = keys.stats();
+        assert_eq!(*regions[4].lock().unwrap(), 4);
+        let after = keys.stats();
+        assert_eq!(after.hits, before.hits + 1);
+        assert_eq!(after.evictions, before.evictions);
+
+        assert_eq!(*regions[0].lock().unwrap(), 0);
+        let stats = keys.stats();
+        assert_eq!(stats.evictions, after.evictions + 1);
+        assert_eq!(stats.retagged_pages, after.retagged_pages + 2);
+
+        if ProtectionKeys::is_supported() {
+            let locked = keys.locked.handle;
+            let resident = regions
+                .iter()
+                .filter(|region| key_of(region.ptr) != locked)
+                .count();
+            assert_eq!(resident, 2);
+            assert_ne!(key_of(regions[0].ptr), locked);
+            assert_eq!(key_of(regions[3].ptr), locked);
+        }
+
+        drop(regions);
+        assert_eq!(keys.stats().keys, 0);
+        assert_eq!(keys.stats().resident, 0);
+    }
+
+    #[test]
+    fn test_locked_keys_stay_resident() {
+        let keys = VirtualKeys::new(false, 1).unwrap();
+        let first = keys.key().make_region(1u8).unwrap();
+        let second = keys.key().make_region(2u8).unwrap();
+
+        let guard = first.lock().unwrap();
+        assert!(matches!(second.lock(), Err(ProtectionError::KeysInUse)));
+        // Regions of the same key share the hardware key
+        let sibling = first.key.make_region(3u8).unwrap();
+        assert_eq!(*sibling.lock().unwrap(), 3);
+        // Dropping the inner guard leaves the outer one its access
+        assert_eq!(*guard, 1);
+        drop(guard);
+
+        assert_eq!(*second.lock().unwrap(), 2);
+        assert_eq!(*first.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_failed_evictions_keep_the_victim() {
+        let keys = VirtualKeys::new(false, 1).unwrap();
+        let victim = keys.key().make_region(1u8).unwrap();
+        let other = keys.key().make_region(2u8).unwrap();
+        assert_eq!(*victim.lock().unwrap(), 1);
+
+        // A page that can't be tagged keeps the victim from being locked away
+        let mut state = keys.state();
+        state
+            .keys
+            .get_mut(&victim.key.id)
+            .unwrap()
+            .pages
+            .push(PAGE_SIZE);
+        drop(state);
+        let before = keys.stats();
+        assert!(other.lock().is_err());
+        assert_eq!(keys.stats().evictions, before.evictions);
+        assert_eq!(keys.stats().resident, 1);
+        assert_eq!(*victim.lock().unwrap(), 1);
+        assert_eq!(keys.stats().hits, before.hits + 1);
+
+        let mut state = keys.state();
+        state.keys.get_mut(&victim.key.id).unwrap().pages.pop();
+        drop(state);
+        assert_eq!(*other.lock().unwrap(), 2);
+        assert_eq!(*victim.lock().unwrap(), 1);
+    }
+}
@@ -1,174 +1,96 @@
-use shared_memory::{Shmem, ShmemConf, ShmemError};
-use libc::{ftruncate, shm_open};
-use libc::{O_RDWR, O_CREAT, S_IRUSR, S_IWUSR, S_IRGRP, S_IWGRP};
-use std::io::{Read, BufReader};
-use std::ffi::CString;
 use std::io;
-use std::collections::HashMap;
-use serde_json::Value;
+use std::time::Instant;
 
-use pkey_mprotect::*;
-
-const SHMEM_REQUEST_FLINK: &str = "/request_mem";
-const SHMEM_RESPONSE_FLINK: &str = "/response_mem";
-
-const SHMEM_REQUESTMPK_FLINK: &str = "/request_mpk";
-const SHMEM_RESPONSEMPK_FLINK: &str = "/response_mpk";
+use mpklink::cli::CalculatorCli;
+use mpklink::mpk::*;
+use wordcount::{Metadata, Output, Response};
 
 // Service 2 Functions
-fn create_shared_memory(id: &str, length: usize, ) -> Result<Shmem, std::io::Error> {
-    // println!("Creating shared memory...");
-    match ShmemConf::new().size(length).os_id(id).create() {
-        Ok(m) => Ok(m),
-        Err(ShmemError::MappingIdExists) => {
-            println!("Opened mapping ID...");
-            Ok(ShmemConf::new().os_id(id).open().unwrap())
-        },
-        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
-    }
-}
-
-fn check_request_mpk(shmem: &Shmem) -> Result<bool, std::io::Error> {
-    let mut request = String::new();
-    let raw_ptr = shmem.as_ptr();
-    let reader = unsafe { std::slice::from_raw_parts(raw_ptr, shmem.len()) };
-    let mut buf_reader = BufReader::new(reader);
-    buf_reader.read_to_string(&mut request)?;
-
-    // Read the first two bytes to check if the data is ready ("D" if ready.)
-    if request.len() >= 1 && request.as_bytes()[0] == 68 {
-        return Ok(true);
-    }
-    return Ok(false)
+fn send_response(responses: &mut Channel, s: &str) -> Result<(), std::io::Error> {
+    responses.send_bytes(s.as_bytes(), None)?;
+    log::debug!("Sent response: {:?}", s);
+    Ok(())
 }
 
-fn recv_request(protected_region: &ProtectedRegion<&String>, mpkshmem: &Shmem) -> Result<String, std::io::Error> {
-    let mut ready = false;
-    let mut request = String::new();
-
-    while !ready {
-        ready = check_request_mpk(&mpkshmem).unwrap();
+// Answers every request of a batch, packing the responses into as few batches as fit and
+// sending those that don't fit into one on their own, in order
+fn send_batch(responses: &mut Channel, requests: Vec<Vec<u8>>) -> Result<(), std::io::Error> {
+    let count = requests.len();
+    let mut batch = Message::empty();
+    for request in requests {
+        let start = Instant::now();
+        let response = wordcount::handle_request(&String::from_utf8_lossy(&request));
+        // Counts go as JSON, the arena only holds those of one response at a time
+        let json = response.to_json_with(&Metadata::since(start));
+        if json.len() > FRAME_CAPACITY {
+            if batch.frames() > 0 {
+                deliver(responses, batch)?;
+                batch = Message::empty();
+            }
+            responses.send_frame(json.as_bytes())?;
+        } else if !batch.push_frame(json.as_bytes()) {
+            deliver(responses, batch)?;
+            batch = Message::empty();
+            batch.push_frame(json.as_bytes());
+        }
     }
-    
-    {
-        // Lock the region
-        let locked_region = protected_region.lock();
-        // Read from the locked region
-        request = locked_region.clone().to_string();
+    if batch.frames() > 0 {
+        deliver(responses, batch)?;
     }
-
-    Ok(request)
-}
-
-fn send_response(protected_region: &ProtectedRegion<&String>, s: &String, mpkshmem: &Shmem) -> Result<(), std::io::Error> {
-    // write "N" to mpkshmem to indicate not ready
-    let mpk_raw_ptr = mpkshmem.as_ptr();
-    let mpk_writer = unsafe { std::slice::from_raw_parts_mut(mpk_raw_ptr, 1) };
-    let metadata = [78] as [u8; 1];
-    mpk_writer.copy_from_slice(&metadata);
-
-    // Write data
-    protected_region.modify(s);
-
-    // write "D" to mpkshmem to indicate ready.
-    let new_metadata = [68] as [u8; 1];
-    mpk_writer.copy_from_slice(&new_metadata);
-
-    
-    println!("Sent response: {:?}", s);
+    log::debug!("Answered a batch of {} requests", count);
     Ok(())
 }
 
-fn process_request(request: String) -> String {
-    println!("Processing request: {}", request);
-    match serde_json::from_str::<Value>(&request) {
-        Ok(parsed) => {
-            let req_type = parsed["type"].as_str().unwrap_or_default();
-            let input = parsed["string"].as_str().unwrap_or_default();
-            match req_type {
-                "total" => {
-                    let total_count = input.split_whitespace().count() as i32;
-                    total_count.to_string()
-                }
-                "counts" => {
-                    let mut counts: HashMap<String, i32> = HashMap::new();
-                    for word in input.split_whitespace() {
-                        *counts.entry(word.to_string()).or_insert(0) += 1;
-                    }
-                    serde_json::to_string(&counts).unwrap_or_default()
-                }
-                _ => "Unknown request type".to_string(),
-            }
+// Waits for the manager to make room rather than overwriting responses it hasn't read
+fn deliver(responses: &mut Channel, message: Message) -> Result<(), std::io::Error> {
+    match responses.try_send(message) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            log::debug!("Response channel full, waiting: {:?}", responses.stats());
+            responses.send(message)
         }
-        Err(e) => e.to_string(),
+        result => result,
     }
 }
 
-
-fn main() -> Result<(), std::io::Error> {
-    println!("Starting request-calculator...");
-
-    let shmem_request_mpk = create_shared_memory(SHMEM_REQUESTMPK_FLINK, 1)?; // read only
-
-    let shm_name = CString::new(SHMEM_REQUEST_FLINK).expect("CString::new failed");
-    let shm_size: libc::size_t = 4096;
-
-    // Create and open the shared memory object
-    let fd = unsafe {
-        shm_open(
-            shm_name.as_ptr(),
-            O_CREAT | O_RDWR,
-            S_IRUSR | S_IWUSR | S_IRGRP | S_IWGRP,
-        )
-    };
-
-    if fd == -1 {
-        return Err(io::Error::last_os_error());
-    }
-    
-    // Resize the shared memory segment
-    let result = unsafe { ftruncate(fd, shm_size as libc::off_t) };
-    if result == -1 {
-        return Err(io::Error::last_os_error());
-    }
-
-    let pkey = ProtectionKeys::new(false).unwrap();
-    let test = "test string".to_string();
-    let protected_region = pkey.make_region_fd(&test, fd).unwrap();
-    
-    let request = recv_request(&protected_region, &shmem_request_mpk)?;
-    println!("Received request: {}", request);
-    let response = process_request(request);
-
-    // fix response shenanigans
-
-    let resp_shm_name = CString::new(SHMEM_RESPONSE_FLINK).expect("CString::new failed");
-
-    // Create and open the shared memory object
-    let ffd = unsafe {
-        shm_open(
-            resp_shm_name.as_ptr(),
-            O_CREAT | O_RDWR,
-            S_IRUSR | S_IWUSR | S_IRGRP | S_IWGRP,
-        )
-    };
-
-    if ffd == -1 {
-        return Err(io::Error::last_os_error());
-    }
-    
-    // Resize the shared memory segment
-    let res = unsafe { ftruncate(ffd, shm_size as libc::off_t) };
-    if res == -1 {
-        return Err(io::Error::last_os_error());
+fn main() -> Result<(), io::Error> {
+    let cli = CalculatorCli::parse_args();
+    env_logger::init();
+    log::info!("Starting request-calculator...");
+
+    // The calculator owns every segment, managers come and go
+    let capacity = cli.capacity_or(CHANNEL_CAPACITY);
+    let requests = Channel::create(SHMEM_REQUEST_CREDITS_FLINK, SHMEM_REQUEST_FLINK, capacity)?;
+    let mut responses =
+        Channel::create(SHMEM_RESPONSE_CREDITS_FLINK, SHMEM_RESPONSE_FLINK, capacity)?;
+    let counts_arena = map_counts()?;
+
+    // Process requests in a loop
+    loop {
+        // A region is copied out under one guard, the rest of a long message chunk by chunk
+        let message = requests.recv_message();
+        // Requests of a manager that died before the current one connected are left unanswered
+        if message.session != requests.claimed_session() {
+            log::debug!("Dropped a request of session {}", message.session);
+            continue;
+        }
+        responses.set_session(message.session);
+        if message.frames > 0 {
+            match message.payloads() {
+                Ok(batch) => send_batch(&mut responses, batch)?,
+                Err(e) => log::warn!("Dropped a malformed batch: {}", e),
+            }
+            continue;
+        }
+        let request = String::from_utf8_lossy(&message.bytes);
+        log::debug!("Received request: {}", request);
+        let start = Instant::now();
+        let response = wordcount::handle_request(&request);
+        let meta = Metadata::since(start);
+        match response {
+            Response::Ok { result: Output::Counts(counts) } => {
+                send_counts(&mut responses, &counts_arena, counts, &meta)?
+            }
+            response => send_response(&mut responses, &response.to_json_with(&meta))?,
+        }
     }
-
-    let new_pkey = ProtectionKeys::new(false).unwrap();
-    let new_protected_region = pkey.make_region_fd(&response, ffd).unwrap();
-    
-    
-    let shmem_response_mpk = create_shared_memory(SHMEM_RESPONSEMPK_FLINK, 1)?; // write to when ready
-    send_response(&new_protected_region, &response, &shmem_response_mpk)?;
-
-    Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,355 @@
+//! Reproducible word-count inputs with their expected answers.
+//!
+//! Words are drawn from `tests/words`, shuffled by the seed so the most
+//! frequent ones differ between seeds, and picked with a Zipf or a uniform
+//! distribution. Every word may be followed by a punctuation mark, which then
+//! is part of it as far as word counting goes, and lines hold a random number
+//! of words.
+//!
+//! For every size `N` the output directory gets:
+//!
+//! - `N.in`: the input of `N` words
+//! - `N.total.json`, `N.counts.json`, `N.top.json`: the results of `total`,
+//!   `counts` and `top --n <top>` on it, as printed by `request-manager --format json`
+//!
+//! An input only depends on the seed, its size and the other options, so the
+//! same command always writes the same files.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use wordcount::Output;
+
+const PUNCTUATION: [char; 6] = [',', '.', ';', ':', '!', '?'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Distribution {
+    /// The k-th most frequent word occurs about 1/k^exponent as often as the first
+    Zipf,
+    /// Every word is equally likely
+    Uniform,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Number of words of each input, sizes from 100 to 100M words by default
+    #[arg(short = 'n', long = "words", value_parser = clap::value_parser!(u64).range(1..))]
+    sizes: Vec<u64>,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    #[arg(short, long, value_enum, default_value_t = Distribution::Zipf)]
+    distribution: Distribution,
+
+    /// Exponent of the Zipf distribution
+    #[arg(long, default_value_t = 1.0)]
+    exponent: f64,
+
+    /// Number of distinct words to draw from, all of `--words-file` by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    vocabulary: Option<u64>,
+
+    /// Fewest words on a line
+    #[arg(long, default_value_t = 5)]
+    min_line_words: usize,
+
+    /// Most words on a line, `0` to put every word on one line
+    #[arg(long, default_value_t = 15)]
+    max_line_words: usize,
+
+    /// Probability of a word being followed by one of `,.;:!?`
+    #[arg(long, default_value_t = 0.05)]
+    punctuation: f64,
+
+    /// Number of words in the expected `top` answer
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+
+    /// Whitespace-separated words to draw from
+    #[arg(long, default_value = "tests/words")]
+    words_file: PathBuf,
+
+    /// Directory to write the inputs and answers to
+    #[arg(short, long, default_value = "tests")]
+    output: PathBuf,
+}
+
+/// How an input's words are picked and laid out.
+#[derive(Debug, Clone)]
+pub struct Params {
+    pub seed: u64,
+    pub distribution: Distribution,
+    pub exponent: f64,
+    pub min_line_words: usize,
+    pub max_line_words: usize,
+    pub punctuation: f64,
+    pub top: usize,
+}
+
+/// The expected results of an input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answers {
+    pub total: Output,
+    pub counts: Output,
+    pub top: Output,
+}
+
+/// Picks word indices, most frequent first.
+enum Sampler {
+    /// Cumulative weights of every rank.
+    Zipf(Vec<f64>),
+    Uniform(usize),
+}
+
+impl Sampler {
+    fn new(distribution: Distribution, exponent: f64, len: usize) -> Self {
+        match distribution {
+            Distribution::Uniform => Self::Uniform(len),
+            Distribution::Zipf => {
+                let mut total = 0.0;
+                let cdf = (1..=len)
+                    .map(|rank| {
+                        total += (rank as f64).powf(-exponent);
+                        total
+                    })
+                    .collect();
+                Self::Zipf(cdf)
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            Self::Uniform(len) => rng.gen_range(0..*len),
+            Self::Zipf(cdf) => {
+                let target = rng.gen::<f64>() * cdf[cdf.len() - 1];
+                cdf.partition_point(|&weight| weight <= target)
+                    .min(cdf.len() - 1)
+            }
+        }
+    }
+}
+
+/// The words of `words_file` in the order given by `seed`, keeping the first `limit`.
+pub fn vocabulary(words_file: &Path, seed: u64, limit: Option<usize>) -> io::Result<Vec<String>> {
+    let text = fs::read_to_string(words_file)?;
+    let mut words = text
+        .split_whitespace()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} holds no words", words_file.display()),
+        ));
+    }
+    words.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+    if let Some(limit) = limit {
+        words.truncate(limit);
+    }
+    Ok(words)
+}
+
+/// Writes an input of `size` words to `out` and returns its answers.
+pub fn generate(
+    params: &Params,
+    vocabulary: &[String],
+    size: u64,
+    out: &mut impl Write,
+) -> io::Result<Answers> {
+    // Inputs of different sizes use different streams of the same seed
+    let mut rng = ChaCha8Rng::seed_from_u64(params.seed);
+    rng.set_stream(size);
+    let sampler = Sampler::new(params.distribution, params.exponent, vocabulary.len());
+
+    // Occurrences of every word with every ending, no punctuation first
+    let endings = PUNCTUATION.len() + 1;
+    let mut occurrences = vec![0usize; vocabulary.len() * endings];
+
+    let mut line_left = line_length(params, &mut rng);
+    for i in 0..size {
+        let word = sampler.sample(&mut rng);
+        let ending = if rng.gen_bool(params.punctuation) {
+            rng.gen_range(1..endings)
+        } else {
+            0
+        };
+        occurrences[word * endings + ending] += 1;
+
+        out.write_all(vocabulary[word].as_bytes())?;
+        if ending > 0 {
+            write!(out, "{}", PUNCTUATION[ending - 1])?;
+        }
+        if i + 1 == size {
+            out.write_all(b"\n")?;
+        } else if line_left == 1 {
+            out.write_all(b"\n")?;
+            line_left = line_length(params, &mut rng);
+        } else {
+            out.write_all(b" ")?;
+            line_left = line_left.saturating_sub(1);
+        }
+    }
+
+    // The same word may appear more than once in the list
+    let mut counts = BTreeMap::new();
+    for (index, &count) in occurrences.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        let mut word = vocabulary[index / endings].clone();
+        if index % endings > 0 {
+            word.push(PUNCTUATION[index % endings - 1]);
+        }
+        *counts.entry(word).or_insert(0) += count;
+    }
+
+    let mut top = counts
+        .iter()
+        .map(|(word, &count)| (word.clone(), count))
+        .collect::<Vec<_>>();
+    top.sort_by(|(a_word, a_count), (b_word, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_word.cmp(b_word))
+    });
+    top.truncate(params.top);
+
+    Ok(Answers {
+        total: Output::Count(size as usize),
+        counts: Output::Counts(counts),
+        top: Output::Ranking(top),
+    })
+}
+
+/// Number of words on the next line, `0` if lines never end.
+fn line_length(params: &Params, rng: &mut impl Rng) -> usize {
+    if params.max_line_words == 0 {
+        return 0;
+    }
+    rng.gen_range(params.min_line_words.clamp(1, params.max_line_words)..=params.max_line_words)
+}
+
+/// Path of the expected answer of `kind` next to `input`.
+pub fn answer_path(input: &Path, kind: &str) -> PathBuf {
+    input.with_extension(format!("{}.json", kind))
+}
+
+pub fn run(args: &Args) -> io::Result<()> {
+    if !(0.0..=1.0).contains(&args.punctuation) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--punctuation must be a probability",
+        ));
+    }
+    let params = Params {
+        seed: args.seed,
+        distribution: args.distribution,
+        exponent: args.exponent,
+        min_line_words: args.min_line_words,
+        max_line_words: args.max_line_words,
+        punctuation: args.punctuation,
+        top: args.top,
+    };
+    let vocabulary = vocabulary(
+        &args.words_file,
+        args.seed,
+        args.vocabulary.map(|limit| limit as usize),
+    )?;
+    let sizes = if args.sizes.is_empty() {
+        vec![
+            100,
+            1_000,
+            10_000,
+            100_000,
+            1_000_000,
+            10_000_000,
+            100_000_000,
+        ]
+    } else {
+        args.sizes.clone()
+    };
+
+    fs::create_dir_all(&args.output)?;
+    for size in sizes {
+        let input = args.output.join(format!("{}.in", size));
+        let mut out = BufWriter::new(File::create(&input)?);
+        let answers = generate(&params, &vocabulary, size, &mut out)?;
+        out.flush()?;
+
+        for (kind, answer) in [
+            ("total", &answers.total),
+            ("counts", &answers.counts),
+            ("top", &answers.top),
+        ] {
+            fs::write(answer_path(&input, kind), format!("{}\n", answer))?;
+        }
+        println!("Generated {}", input.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wordcount::{analyze, RequestKind, Ties};
+
+    fn params(distribution: Distribution) -> Params {
+        Params {
+            seed: 7,
+            distribution,
+            exponent: 1.2,
+            min_line_words: 1,
+            max_line_words: 4,
+            punctuation: 0.3,
+            top: 3,
+        }
+    }
+
+    #[test]
+    fn test_answers_match_the_analysis() {
+        let vocabulary = ["apple", "pear", "fig", "plum", "pear"].map(String::from);
+        for distribution in [Distribution::Zipf, Distribution::Uniform] {
+            let mut input = Vec::new();
+            let answers = generate(&params(distribution), &vocabulary, 500, &mut input).unwrap();
+            let input = String::from_utf8(input).unwrap();
+
+            assert_eq!(answers.total, analyze(&RequestKind::Total, &input));
+            assert_eq!(answers.counts, analyze(&RequestKind::Counts, &input));
+            let top = RequestKind::Top {
+                n: Some(3),
+                ties: Ties::Alphabetical,
+            };
+            assert_eq!(answers.top, analyze(&top, &input));
+            assert!(input
+                .lines()
+                .all(|line| (1..=4).contains(&line.split(' ').count())));
+        }
+    }
+
+    #[test]
+    fn test_inputs_are_reproducible() {
+        let vocabulary = ["a", "b", "c"].map(String::from);
+        let input = |params: &Params, size| {
+            let mut input = Vec::new();
+            generate(params, &vocabulary, size, &mut input).unwrap();
+            input
+        };
+
+        let first = params(Distribution::Zipf);
+        assert_eq!(input(&first, 100), input(&first, 100));
+        // Sizes don't share a prefix
+        assert!(!input(&first, 101).starts_with(&input(&first, 100)[..150]));
+
+        let second = Params {
+            seed: 8,
+            ..first.clone()
+        };
+        assert_ne!(input(&first, 100), input(&second, 100));
+    }
+}
@@ -0,0 +1,72 @@
+//! Benchmarks of the transports between request-managers and request-calculators.
+//!
+//! ```text
+//! mpklink-bench [--transport T]... [--input FILE]...    word-count requests, see `requests`
+//! mpklink-bench ping-pong [--channel C]... [--size N]... fixed-size messages, see `pingpong`
+//! mpklink-bench generate [--words N]... [--seed S]       inputs and answers, see `generate`
+//! ```
+
+mod generate;
+mod pingpong;
+mod plot;
+mod requests;
+mod stats;
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(
+    about = "Benchmarks the transports between request-managers and request-calculators",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    requests: requests::Args,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Measures round trips of fixed-size messages to an echo server
+    PingPong(pingpong::Args),
+    /// Writes reproducible inputs and their expected answers
+    Generate(generate::Args),
+    /// Echoes messages on a channel until killed; started by `ping-pong`
+    #[command(hide = true)]
+    Echo {
+        #[arg(value_enum)]
+        channel: pingpong::Channel,
+    },
+}
+
+/// A child process, killed when dropped.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// `benchmark/<timestamp>` below `root`.
+fn default_output(root: &Path) -> PathBuf {
+    let timestamp = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S");
+    root.join("benchmark").join(timestamp.to_string())
+}
+
+fn main() -> Result<(), io::Error> {
+    let cli = Cli::parse();
+    match &cli.command {
+        None => requests::run(&cli.requests),
+        Some(Command::PingPong(args)) => pingpong::run(args),
+        Some(Command::Generate(args)) => generate::run(args),
+        Some(Command::Echo { channel }) => pingpong::echo(*channel),
+    }
+}
@@ -0,0 +1,498 @@
+//! Round-trip latency of fixed-size messages between two persistent processes.
+//!
+//! For every channel the bench starts itself as an echo server
+//! (`mpklink-bench echo <channel>`), connects once and then, for every message
+//! size, sends `--warmup` unmeasured and `--iterations` measured pings, each
+//! waiting for its echo. Nothing is parsed or computed on either side, so the
+//! latencies are those of the transports alone.
+//!
+//! The channels use their transports' own mechanisms on endpoints of their own,
+//! so calculators may keep running meanwhile:
+//!
+//! - `pipe`: length-prefixed frames over a FIFO pair
+//! - `uds`: length-prefixed frames over a socket
+//! - `shm`: a slot of a one-slot table, an empty header followed by the message
+//! - `mpk`: a region per direction under its own protection key; messages larger
+//!   than a region take one handoff per region's worth
+//! - `mpk-no-pkru`: the same regions without protection keys, so no PKRU switches
+//!
+//! Results go to `benchmark/<timestamp>/`:
+//!
+//! - `pingpong.csv`: latency statistics per channel and size
+//! - `pingpong-histogram.csv`: the non-empty histogram buckets per channel and size
+//! - `pingpong.json`: all of the above
+
+use std::fmt::{self, Write as _};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use clap::ValueEnum;
+use mpklink::frame::{read_frame, write_frame};
+use mpklink::mpk::{self, Message, MESSAGE_CAPACITY, NOT_READY, READY};
+use mpklink::shm::{ClientSlot, RequestStream, Server, SlotTable, CELL_COUNT, CELL_SIZE};
+use pkey_mprotect::{ProtectedRegion, ProtectionKeys};
+use serde::Serialize;
+use shared_memory::Shmem;
+
+use crate::stats::{Histogram, Summary};
+use crate::Process;
+
+/// Largest message size that can be measured.
+pub const MAX_SIZE: usize = 64 << 10;
+
+const PIPE_PING: &str = "/tmp/mpklink-pingpong-ping";
+const PIPE_PONG: &str = "/tmp/mpklink-pingpong-pong";
+const SOCKET: &str = "/tmp/mpklink-pingpong.sock";
+const SHM_FLINK: &str = "/tmp/mpklink-pingpong.shm";
+const REGION_PING: &str = "/mpklink-pingpong-ping";
+const REGION_PONG: &str = "/mpklink-pingpong-pong";
+const FLAG_PING: &str = "/mpklink-pingpong-ping-flag";
+const FLAG_PONG: &str = "/mpklink-pingpong-pong-flag";
+
+// Printed by the echo server once its endpoints exist
+const READY_LINE: &str = "ready";
+const SPINS_BEFORE_YIELD: usize = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Channel {
+    Pipe,
+    Uds,
+    Shm,
+    Mpk,
+    MpkNoPkru,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [Self::Pipe, Self::Uds, Self::Shm, Self::Mpk, Self::MpkNoPkru];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pipe => "pipe",
+            Self::Uds => "uds",
+            Self::Shm => "shm",
+            Self::Mpk => "mpk",
+            Self::MpkNoPkru => "mpk-no-pkru",
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Channels to measure, all of them by default
+    #[arg(short, long, value_enum)]
+    channel: Vec<Channel>,
+
+    /// Message sizes in bytes, 8 B, 64 B, 512 B, 4 KiB, 32 KiB and 64 KiB by default
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..=MAX_SIZE as u64))]
+    size: Vec<u64>,
+
+    /// Unmeasured round trips per size
+    #[arg(short, long, default_value_t = 10_000)]
+    warmup: usize,
+
+    /// Measured round trips per size
+    #[arg(short = 'n', long, default_value_t = 1_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    iterations: u64,
+
+    /// Directory for the results, `benchmark/<timestamp>` by default
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Root of the repository
+    #[arg(long, default_value = ".")]
+    root: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+struct SizeResult {
+    channel: Channel,
+    size: usize,
+    latency: Summary,
+    /// Round trips per second of mean latency.
+    rate: f64,
+    histogram: Histogram,
+}
+
+/// One end of a connection that carries whole messages.
+trait Link {
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+}
+
+/// Frames over a pair of byte streams.
+struct StreamLink<R, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+}
+
+impl<R: Read, W: Write> StreamLink<R, W> {
+    fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        }
+    }
+}
+
+impl<R: Read, W: Write> Link for StreamLink<R, W> {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.writer, message)?;
+        self.writer.flush()
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        read_frame(&mut self.reader, MAX_SIZE)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+/// A slot claimed for the whole run.
+struct ShmLink<'a>(ClientSlot<'a>);
+
+impl Link for ShmLink<'_> {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.0.send(&[])?;
+        self.0.send(message)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.0.recv()
+    }
+}
+
+/// Answers every request with its body.
+struct Echo(Vec<u8>);
+
+impl RequestStream for Echo {
+    fn start(_header: &[u8]) -> Self {
+        Echo(Vec::new())
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        self.0.extend_from_slice(chunk);
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// A region and its flag per direction.
+///
+/// A message is sent as consecutive region-sized chunks and ends with the
+/// first chunk that doesn't fill a region, which may be empty.
+struct MpkLink {
+    outgoing: (Arc<ProtectedRegion<Message>>, Shmem),
+    incoming: (Arc<ProtectedRegion<Message>>, Shmem),
+}
+
+impl MpkLink {
+    fn new(channel: Channel, server: bool) -> io::Result<Self> {
+        let pkey = match channel {
+            Channel::MpkNoPkru => ProtectionKeys::unprotected(),
+            _ => ProtectionKeys::new(false).map_err(io::Error::other)?,
+        };
+        let ping = mpk::map_region_with(REGION_PING, &pkey)?;
+        let pong = mpk::map_region_with(REGION_PONG, &pkey)?;
+
+        // The server creates the flags, which the client waits for
+        let (ping_flag, pong_flag) = if server {
+            let flags = (mpk::create_flag(FLAG_PING)?, mpk::create_flag(FLAG_PONG)?);
+            mpk::flag(&flags.0).store(NOT_READY, Ordering::Release);
+            mpk::flag(&flags.1).store(NOT_READY, Ordering::Release);
+            flags
+        } else {
+            (mpk::open_flag(FLAG_PING)?, mpk::open_flag(FLAG_PONG)?)
+        };
+
+        Ok(if server {
+            Self {
+                outgoing: (pong, pong_flag),
+                incoming: (ping, ping_flag),
+            }
+        } else {
+            Self {
+                outgoing: (ping, ping_flag),
+                incoming: (pong, pong_flag),
+            }
+        })
+    }
+}
+
+impl Link for MpkLink {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let (region, flag) = &self.outgoing;
+        let mut rest = message;
+        loop {
+            let len = rest.len().min(MESSAGE_CAPACITY);
+            let chunk = Message::from_bytes(&rest[..len]).expect("chunk fits into a region");
+            mpk::send(region, chunk, flag)?;
+            rest = &rest[len..];
+            if len < MESSAGE_CAPACITY {
+                return Ok(());
+            }
+        }
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let (region, flag) = &self.incoming;
+        let mut message = Vec::new();
+        loop {
+            wait_for(mpk::flag(flag), |value| value == READY);
+            let len = {
+                let chunk = region.lock();
+                message.extend_from_slice(chunk.bytes());
+                chunk.bytes().len()
+            };
+            mpk::flag(flag).store(NOT_READY, Ordering::Release);
+            if len < MESSAGE_CAPACITY {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+/// Spins until `ready` accepts the flag's value, giving up the core now and then.
+fn wait_for(flag: &AtomicU8, ready: impl Fn(u8) -> bool) {
+    let mut spins = 0;
+    while !ready(flag.load(Ordering::Acquire)) {
+        spins += 1;
+        if spins < SPINS_BEFORE_YIELD {
+            std::hint::spin_loop();
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
+/// Serves `channel` until killed, announcing on stdout when clients can connect.
+pub fn echo(channel: Channel) -> io::Result<()> {
+    let ready = || {
+        let mut stdout = io::stdout();
+        writeln!(stdout, "{}", READY_LINE)?;
+        stdout.flush()
+    };
+
+    match channel {
+        Channel::Pipe => {
+            mpklink::pipe::create_pipe(PIPE_PING)?;
+            mpklink::pipe::create_pipe(PIPE_PONG)?;
+            ready()?;
+            let reader = OpenOptions::new().read(true).open(PIPE_PING)?;
+            let writer = OpenOptions::new().write(true).open(PIPE_PONG)?;
+            echo_link(&mut StreamLink::new(reader, writer))
+        }
+        Channel::Uds => {
+            if let Err(e) = fs::remove_file(SOCKET) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+            let listener = UnixListener::bind(SOCKET)?;
+            ready()?;
+            let (stream, _) = listener.accept()?;
+            echo_link(&mut StreamLink::new(stream.try_clone()?, stream))
+        }
+        Channel::Shm => {
+            let mut server =
+                Server::<Echo>::new(SlotTable::create_with(SHM_FLINK, 1, CELL_COUNT, CELL_SIZE)?);
+            ready()?;
+            let mut spins = 0;
+            loop {
+                if server.poll() > 0 {
+                    spins = 0;
+                } else if spins < SPINS_BEFORE_YIELD {
+                    spins += 1;
+                    std::hint::spin_loop();
+                } else {
+                    std::thread::yield_now();
+                }
+            }
+        }
+        Channel::Mpk | Channel::MpkNoPkru => {
+            let mut link = MpkLink::new(channel, true)?;
+            ready()?;
+            echo_link(&mut link)
+        }
+    }
+}
+
+fn echo_link(link: &mut dyn Link) -> io::Result<()> {
+    loop {
+        let message = match link.recv() {
+            Ok(message) => message,
+            Err(e) if mpklink::pipe::is_disconnect(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        link.send(&message)?;
+    }
+}
+
+fn start_echo(channel: Channel) -> io::Result<Process> {
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(["echo", channel.name()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let process = Process(child);
+
+    let mut line = String::new();
+    BufReader::new(stdout).read_line(&mut line)?;
+    if line.trim_end() != READY_LINE {
+        return Err(io::Error::other(format!(
+            "{} echo server failed to start",
+            channel
+        )));
+    }
+    Ok(process)
+}
+
+fn measure(
+    link: &mut dyn Link,
+    channel: Channel,
+    sizes: &[usize],
+    args: &Args,
+) -> io::Result<Vec<SizeResult>> {
+    let mut results = Vec::new();
+    for &size in sizes {
+        let message = vec![0xa5; size];
+        let mut samples = Vec::with_capacity(args.iterations as usize);
+        for i in 0..args.warmup + args.iterations as usize {
+            let start = Instant::now();
+            link.send(&message)?;
+            let echo = link.recv()?;
+            let elapsed = start.elapsed();
+
+            if echo.len() != size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("sent {} bytes, {} came back", size, echo.len()),
+                ));
+            }
+            if i >= args.warmup {
+                samples.push(elapsed);
+            }
+        }
+
+        let latency = Summary::new(&samples);
+        eprintln!(
+            "{:>11} {:>6} B  mean {:>9.0}ns  p50 {:>9.0}ns  p99 {:>9.0}ns",
+            channel.name(),
+            size,
+            latency.mean * 1e9,
+            latency.p50 * 1e9,
+            latency.p99 * 1e9
+        );
+        results.push(SizeResult {
+            channel,
+            size,
+            rate: 1.0 / latency.mean,
+            histogram: Histogram::new(&samples),
+            latency,
+        });
+    }
+    Ok(results)
+}
+
+fn bench_channel(channel: Channel, sizes: &[usize], args: &Args) -> io::Result<Vec<SizeResult>> {
+    let _server = start_echo(channel)?;
+    match channel {
+        Channel::Pipe => {
+            let writer = OpenOptions::new().write(true).open(PIPE_PING)?;
+            let reader = OpenOptions::new().read(true).open(PIPE_PONG)?;
+            measure(&mut StreamLink::new(reader, writer), channel, sizes, args)
+        }
+        Channel::Uds => {
+            let stream = UnixStream::connect(SOCKET)?;
+            let mut link = StreamLink::new(stream.try_clone()?, stream);
+            measure(&mut link, channel, sizes, args)
+        }
+        Channel::Shm => {
+            let table = SlotTable::open(SHM_FLINK)?;
+            let mut link = ShmLink(table.claim());
+            measure(&mut link, channel, sizes, args)
+        }
+        Channel::Mpk | Channel::MpkNoPkru => {
+            measure(&mut MpkLink::new(channel, false)?, channel, sizes, args)
+        }
+    }
+}
+
+fn write_results(dir: &Path, results: &[SizeResult]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut csv = String::from("channel,size,iterations,mean,stddev,p50,p99,max,rate\n");
+    let mut histogram = String::from("channel,size,lower_ns,upper_ns,count\n");
+    for result in results {
+        let latency = &result.latency;
+        writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{},{}",
+            result.channel.name(),
+            result.size,
+            latency.iterations,
+            latency.mean,
+            latency.stddev,
+            latency.p50,
+            latency.p99,
+            latency.max,
+            result.rate
+        )
+        .unwrap();
+        for bucket in &result.histogram.buckets {
+            writeln!(
+                histogram,
+                "{},{},{},{},{}",
+                result.channel.name(),
+                result.size,
+                bucket.lower_ns,
+                bucket.upper_ns,
+                bucket.count
+            )
+            .unwrap();
+        }
+    }
+    fs::write(dir.join("pingpong.csv"), csv)?;
+    fs::write(dir.join("pingpong-histogram.csv"), histogram)?;
+
+    let json = serde_json::to_string_pretty(results).map_err(io::Error::other)?;
+    fs::write(dir.join("pingpong.json"), json)
+}
+
+pub fn run(args: &Args) -> io::Result<()> {
+    let channels = if args.channel.is_empty() {
+        Channel::ALL.to_vec()
+    } else {
+        args.channel.clone()
+    };
+    let sizes = if args.size.is_empty() {
+        vec![8, 64, 512, 4 << 10, 32 << 10, MAX_SIZE]
+    } else {
+        args.size.iter().map(|&size| size as usize).collect()
+    };
+
+    let mut results = Vec::new();
+    for &channel in &channels {
+        results.extend(bench_channel(channel, &sizes, args)?);
+    }
+
+    let dir = args
+        .output
+        .clone()
+        .unwrap_or_else(|| crate::default_output(&args.root));
+    write_results(&dir, &results)?;
+    println!("{}", dir.display());
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Plots of word counts against latency, as `benchmark.py` drew them.
+
+use std::io;
+use std::path::Path;
+
+use plotters::prelude::*;
+
+/// Mean seconds per word count of one service.
+pub struct Series<'a> {
+    pub label: &'a str,
+    pub points: Vec<(f64, f64)>,
+}
+
+/// Draws `series` into an SVG at `path`, with a log scale for the words.
+///
+/// Series without points are left out; if none has any, nothing is written.
+pub fn draw(path: &Path, title: &str, series: &[Series<'_>]) -> io::Result<()> {
+    let points = || series.iter().flat_map(|series| &series.points);
+    let (Some(min_words), Some(max_words)) = (
+        points().map(|&(words, _)| words).reduce(f64::min),
+        points().map(|&(words, _)| words).reduce(f64::max),
+    ) else {
+        return Ok(());
+    };
+    let max_seconds = points().map(|&(_, seconds)| seconds).fold(0.0, f64::max);
+
+    let root = SVGBackend::new(path, (800, 600)).into_drawing_area();
+    root.fill(&WHITE).map_err(io::Error::other)?;
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 24))
+        .margin(16)
+        .x_label_area_size(48)
+        .y_label_area_size(80)
+        .build_cartesian_2d(
+            (min_words * 0.8..max_words * 1.25).log_scale(),
+            0.0..max_seconds * 1.1,
+        )
+        .map_err(io::Error::other)?;
+    chart
+        .configure_mesh()
+        .x_desc("Number of Words")
+        .y_desc("Time (s)")
+        .draw()
+        .map_err(io::Error::other)?;
+
+    for (i, series) in series.iter().enumerate() {
+        if series.points.is_empty() {
+            continue;
+        }
+        let color = Palette99::pick(i).to_rgba();
+        chart
+            .draw_series(LineSeries::new(series.points.iter().copied(), color))
+            .map_err(io::Error::other)?
+            .label(series.label)
+            .legend(move |(x, y)| PathElement::new([(x, y), (x + 20, y)], color));
+        chart
+            .draw_series(
+                series
+                    .points
+                    .iter()
+                    .map(|&point| Circle::new(point, 3, color.filled())),
+            )
+            .map_err(io::Error::other)?;
+    }
+    if series.len() > 1 {
+        chart
+            .configure_series_labels()
+            .position(SeriesLabelPosition::UpperLeft)
+            .background_style(WHITE)
+            .border_style(BLACK)
+            .draw()
+            .map_err(io::Error::other)?;
+    }
+    root.present().map_err(io::Error::other)
+}
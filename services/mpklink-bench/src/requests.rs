@@ -0,0 +1,468 @@
+//! Latency of word-count requests sent to each transport's request-calculator.
+//!
+//! The calculators are built once and kept running while each input is sent
+//! `--warmup` times unmeasured and `--iterations` times measured over one
+//! connection, so the latencies don't include process startup.
+//!
+//! `services/mpk-thread` runs both sides in one process, so it is benchmarked
+//! in this one: `mpk-thread` with the calculator on a thread of its own,
+//! `mpk-gate` with the calculator entered through a gate.
+//!
+//! Results go to `benchmark/<timestamp>/`:
+//!
+//! - `<service>.txt`: `words mean_seconds` per input, as plotted by earlier runs
+//! - `<service>.csv`: every statistic per input
+//! - `<service>.svg`: words against mean seconds, and `overview.svg` with all of them
+//! - `results.json`: all of the above for every transport
+//!
+//! Latencies are measured by the client API with a monotonic clock, which also
+//! splits them into phases; the mean of every phase is reported next to them,
+//! with the compute time as reported by the calculator, so what's left is the
+//! cost of the transport.
+//!
+//! Inputs written by `generate` have their word counts checked against the
+//! expected total; a wrong count is reported instead of a latency.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use mpklink::client::{self, Client, Timings, Transport};
+use serde::Serialize;
+use wordcount::{Output, RequestKind, Response};
+
+use crate::generate;
+use crate::plot::{self, Series};
+use crate::stats::Summary;
+use crate::Process;
+
+// The mpk calculator resets its segments on startup, dropping requests sent before that
+const STARTUP_DELAY: Duration = Duration::from_millis(500);
+// How long a freshly started calculator gets to accept connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A transport's calculator, or `services/mpk-thread` run in this process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Service {
+    Pipe,
+    Uds,
+    Shm,
+    Mpk,
+    MpkThread,
+    MpkGate,
+}
+
+impl Service {
+    pub const ALL: [Service; 6] = [
+        Self::Pipe,
+        Self::Uds,
+        Self::Shm,
+        Self::Mpk,
+        Self::MpkThread,
+        Self::MpkGate,
+    ];
+
+    /// The transport to the calculator, `None` for those in this process.
+    fn transport(self) -> Option<Transport> {
+        match self {
+            Self::Pipe => Some(Transport::Pipe),
+            Self::Uds => Some(Transport::Uds),
+            Self::Shm => Some(Transport::Shm),
+            Self::Mpk => Some(Transport::Mpk),
+            Self::MpkThread | Self::MpkGate => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MpkThread => "mpk-thread",
+            Self::MpkGate => "mpk-gate",
+            _ => self.transport().unwrap().name(),
+        }
+    }
+
+    /// Name of the result files: the directory below `services/`, with `-gate`
+    /// for `mpk-gate`.
+    fn label(self) -> &'static str {
+        match self {
+            Self::MpkThread => "mpk-thread",
+            Self::MpkGate => "mpk-thread-gate",
+            _ => self.transport().unwrap().service(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    /// Services to benchmark, all of them by default
+    #[arg(short, long, value_enum)]
+    transport: Vec<Service>,
+
+    /// Inputs to send, `tests/*.in` by default
+    #[arg(short, long)]
+    input: Vec<PathBuf>,
+
+    /// Unmeasured requests per input
+    #[arg(short, long, default_value_t = 3)]
+    warmup: usize,
+
+    /// Measured requests per input
+    #[arg(short = 'n', long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    iterations: u64,
+
+    /// Directory for the results, `benchmark/<timestamp>` by default
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Root of the repository
+    #[arg(long, default_value = ".")]
+    root: PathBuf,
+
+    /// Run the calculators as they are instead of building them first
+    #[arg(long)]
+    no_build: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TransportResult {
+    transport: Service,
+    service: &'static str,
+    inputs: Vec<InputResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct InputResult {
+    input: PathBuf,
+    bytes: usize,
+    /// Number of words the calculator counted.
+    words: Option<usize>,
+    latency: Option<Summary>,
+    phases: Option<Phases>,
+    /// Input bytes per second of mean latency.
+    throughput: Option<f64>,
+    error: Option<String>,
+}
+
+/// Mean seconds of every phase of the measured requests, see [`Timings`].
+#[derive(Debug, Serialize)]
+struct Phases {
+    serialise: f64,
+    send: f64,
+    /// `None` unless the calculator reported it for every request.
+    compute: Option<f64>,
+    receive: f64,
+    deserialise: f64,
+}
+
+impl Phases {
+    fn new(timings: &[Timings]) -> Self {
+        let mean = |phase: fn(&Timings) -> u64| {
+            timings.iter().map(|t| phase(t) as f64 / 1e9).sum::<f64>() / timings.len() as f64
+        };
+        let compute = timings
+            .iter()
+            .map(|t| t.compute_ns)
+            .collect::<Option<Vec<_>>>()
+            .map(|compute| compute.iter().sum::<u64>() as f64 / 1e9 / timings.len() as f64);
+        Self {
+            serialise: mean(|t| t.serialise_ns),
+            send: mean(|t| t.send_ns),
+            compute,
+            receive: mean(|t| t.receive_ns),
+            deserialise: mean(|t| t.deserialise_ns),
+        }
+    }
+}
+
+fn start_calculator(root: &Path, transport: Transport) -> io::Result<Process> {
+    // A link left behind by a killed calculator would be opened before the new one replaces it
+    if transport == Transport::Shm {
+        match fs::remove_file(mpklink::shm::CONTROL_FLINK) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    let binary = target_dir(root, transport).join("release/request-calculator");
+    let child = Command::new(&binary)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", binary.display(), e)))?;
+    std::thread::sleep(STARTUP_DELAY);
+    Ok(Process(child))
+}
+
+fn calculator_dir(root: &Path, transport: Transport) -> PathBuf {
+    root.join("services")
+        .join(transport.service())
+        .join("request-calculator")
+}
+
+/// Where calculators are built to and run from, whatever `CARGO_TARGET_DIR` says.
+fn target_dir(root: &Path, transport: Transport) -> PathBuf {
+    calculator_dir(root, transport).join("target")
+}
+
+fn build(root: &Path, transport: Transport) -> io::Result<()> {
+    let manifest = calculator_dir(root, transport).join("Cargo.toml");
+    let status = Command::new("cargo")
+        .args(["build", "--release", "--quiet", "--manifest-path"])
+        .arg(&manifest)
+        .arg("--target-dir")
+        .arg(target_dir(root, transport))
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "building {} failed",
+            manifest.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Connects to a calculator that may still be starting up.
+fn connect(transport: Transport) -> io::Result<Box<dyn Client>> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        match client::connect(transport) {
+            Ok(client) => return Ok(client),
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// The `tests/*.in` inputs, smallest first.
+fn default_inputs(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for entry in fs::read_dir(root.join("tests"))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "in") {
+            inputs.push((fs::metadata(&path)?.len(), path));
+        }
+    }
+    inputs.sort();
+    Ok(inputs.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The total `generate` wrote next to `input`, if there is one.
+fn expected_total(input: &Path) -> io::Result<Option<usize>> {
+    let text = match fs::read_to_string(generate::answer_path(input, "total")) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let value = serde_json::from_str(&text);
+    match value.and_then(|value| Output::from_value(&RequestKind::Total, value)) {
+        Ok(Output::Count(total)) => Ok(Some(total)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} holds no total", input.display()),
+        )),
+    }
+}
+
+fn bench_input(client: &mut dyn Client, input: &Path, args: &Args) -> io::Result<InputResult> {
+    let bytes = fs::read(input)?;
+    let expected = expected_total(input)?;
+    let mut result = InputResult {
+        input: input.to_path_buf(),
+        bytes: bytes.len(),
+        words: None,
+        latency: None,
+        phases: None,
+        throughput: None,
+        error: None,
+    };
+
+    let mut samples = Vec::with_capacity(args.iterations as usize);
+    for i in 0..args.warmup + args.iterations as usize {
+        let (response, timings) = client.call_reader_timed(&RequestKind::Total, &mut &bytes[..])?;
+
+        match response {
+            Response::Ok {
+                result: Output::Count(words),
+            } => {
+                if let Some(expected) = expected.filter(|&expected| expected != words) {
+                    result.error = Some(format!("counted {} words, {} expected", words, expected));
+                    return Ok(result);
+                }
+                result.words = Some(words);
+            }
+            Response::Ok { result: output } => {
+                result.error = Some(format!("unexpected result {}", output));
+                return Ok(result);
+            }
+            // Too large for the transport, most likely; there's nothing to measure
+            Response::Error { error } => {
+                result.error = Some(error.to_string());
+                return Ok(result);
+            }
+        }
+        if i >= args.warmup {
+            samples.push(timings);
+        }
+    }
+
+    let latencies = samples
+        .iter()
+        .map(|timings| Duration::from_nanos(timings.total_ns))
+        .collect::<Vec<_>>();
+    let summary = Summary::new(&latencies);
+    result.phases = Some(Phases::new(&samples));
+    result.throughput = Some(bytes.len() as f64 / summary.mean);
+    result.latency = Some(summary);
+    Ok(result)
+}
+
+fn bench_service(service: Service, inputs: &[PathBuf], args: &Args) -> io::Result<TransportResult> {
+    let results = match service.transport() {
+        Some(transport) => {
+            let _calculator = start_calculator(&args.root, transport)?;
+            let mut client = connect(transport)?;
+            bench_inputs(service, client.as_mut(), inputs, args)?
+        }
+        None => {
+            let (inputs, args) = (inputs.to_vec(), args.clone());
+            mpklink::thread::run(service == Service::MpkGate, move |client| {
+                bench_inputs(service, client, &inputs, &args)
+            })??
+        }
+    };
+
+    Ok(TransportResult {
+        transport: service,
+        service: service.label(),
+        inputs: results,
+    })
+}
+
+fn bench_inputs(
+    service: Service,
+    client: &mut dyn Client,
+    inputs: &[PathBuf],
+    args: &Args,
+) -> io::Result<Vec<InputResult>> {
+    let mut results = Vec::new();
+    for input in inputs {
+        let result = bench_input(client, input, args)?;
+        match (&result.latency, &result.error) {
+            (Some(latency), _) => eprintln!(
+                "{:>10} {:>12} B  mean {:.6}s  p99 {:.6}s",
+                service.name(),
+                result.bytes,
+                latency.mean,
+                latency.p99
+            ),
+            (None, error) => eprintln!(
+                "{:>10} {:>12} B  skipped: {}",
+                service.name(),
+                result.bytes,
+                error.as_deref().unwrap_or_default()
+            ),
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+fn write_results(dir: &Path, results: &[TransportResult]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut overview = Vec::new();
+    for result in results {
+        let mut points = Vec::new();
+        let mut txt = String::new();
+        let mut csv =
+            String::from("input,bytes,words,iterations,mean,stddev,p50,p99,max,throughput,serialise,send,compute,receive,deserialise\n");
+        for input in &result.inputs {
+            let (Some(words), Some(latency), Some(phases), Some(throughput)) =
+                (input.words, &input.latency, &input.phases, input.throughput)
+            else {
+                continue;
+            };
+            writeln!(txt, "{} {}", words, latency.mean).unwrap();
+            points.push((words as f64, latency.mean));
+            writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                input.input.display(),
+                input.bytes,
+                words,
+                latency.iterations,
+                latency.mean,
+                latency.stddev,
+                latency.p50,
+                latency.p99,
+                latency.max,
+                throughput,
+                phases.serialise,
+                phases.send,
+                phases
+                    .compute
+                    .map(|compute| compute.to_string())
+                    .unwrap_or_default(),
+                phases.receive,
+                phases.deserialise
+            )
+            .unwrap();
+        }
+        fs::write(dir.join(format!("{}.txt", result.service)), txt)?;
+        fs::write(dir.join(format!("{}.csv", result.service)), csv)?;
+
+        let series = Series {
+            label: result.service,
+            points,
+        };
+        let title = format!("{} benchmark", result.service);
+        let svg = dir.join(format!("{}.svg", result.service));
+        plot::draw(&svg, &title, std::slice::from_ref(&series))?;
+        overview.push(series);
+    }
+    plot::draw(&dir.join("overview.svg"), "Benchmark", &overview)?;
+
+    let json = serde_json::to_string_pretty(results).map_err(io::Error::other)?;
+    fs::write(dir.join("results.json"), json)
+}
+
+pub fn run(args: &Args) -> io::Result<()> {
+    let services = if args.transport.is_empty() {
+        Service::ALL.to_vec()
+    } else {
+        args.transport.clone()
+    };
+    let inputs = if args.input.is_empty() {
+        default_inputs(&args.root)?
+    } else {
+        args.input.clone()
+    };
+    if inputs.is_empty() {
+        eprintln!("No inputs found in tests/, run `mpklink-bench generate` or pass --input");
+        std::process::exit(1);
+    }
+
+    if !args.no_build {
+        for transport in services.iter().filter_map(|service| service.transport()) {
+            build(&args.root, transport)?;
+        }
+    }
+
+    let mut results = Vec::new();
+    for &service in &services {
+        results.push(bench_service(service, &inputs, args)?);
+    }
+
+    let dir = args
+        .output
+        .clone()
+        .unwrap_or_else(|| crate::default_output(&args.root));
+    write_results(&dir, &results)?;
+    println!("{}", dir.display());
+    Ok(())
+}
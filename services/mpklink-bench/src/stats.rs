@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Summary of the latencies measured for one payload, in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub iterations: usize,
+    pub mean: f64,
+    /// Sample standard deviation, `0` for a single iteration.
+    pub stddev: f64,
+    pub p50: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    /// Summarizes `samples`, which must not be empty.
+    pub fn new(samples: &[Duration]) -> Self {
+        assert!(!samples.is_empty(), "no samples to summarize");
+        let mut seconds = samples
+            .iter()
+            .map(Duration::as_secs_f64)
+            .collect::<Vec<_>>();
+        seconds.sort_by(f64::total_cmp);
+
+        let n = seconds.len() as f64;
+        let mean = seconds.iter().sum::<f64>() / n;
+        let stddev = if seconds.len() > 1 {
+            let variance = seconds.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1.0);
+            variance.sqrt()
+        } else {
+            0.0
+        };
+
+        Self {
+            iterations: seconds.len(),
+            mean,
+            stddev,
+            p50: percentile(&seconds, 50.0),
+            p99: percentile(&seconds, 99.0),
+            max: seconds[seconds.len() - 1],
+        }
+    }
+}
+
+/// Latency counts in logarithmic buckets, [`SUB_BUCKETS`] per power of two nanoseconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Histogram {
+    /// Non-empty buckets, shortest latencies first.
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    /// Inclusive lower bound.
+    pub lower_ns: u64,
+    /// Exclusive upper bound.
+    pub upper_ns: u64,
+    pub count: u64,
+}
+
+pub const SUB_BUCKETS: u64 = 4;
+const SUB_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+impl Histogram {
+    pub fn new(samples: &[Duration]) -> Self {
+        let mut counts = BTreeMap::new();
+        for sample in samples {
+            let ns = u64::try_from(sample.as_nanos()).unwrap_or(u64::MAX);
+            *counts.entry(bucket_index(ns)).or_insert(0) += 1;
+        }
+        let buckets = counts
+            .into_iter()
+            .map(|(index, count)| {
+                let (lower_ns, upper_ns) = bucket_bounds(index);
+                Bucket {
+                    lower_ns,
+                    upper_ns,
+                    count,
+                }
+            })
+            .collect();
+        Self { buckets }
+    }
+}
+
+fn bucket_index(ns: u64) -> u64 {
+    if ns < SUB_BUCKETS {
+        return ns;
+    }
+    // The top `SUB_BITS + 1` bits pick the bucket
+    let exponent = u64::from(63 - ns.leading_zeros());
+    let shift = exponent - u64::from(SUB_BITS);
+    (shift + 1) * SUB_BUCKETS + ((ns >> shift) - SUB_BUCKETS)
+}
+
+fn bucket_bounds(index: u64) -> (u64, u64) {
+    if index < SUB_BUCKETS {
+        return (index, index + 1);
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let lower = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    (lower, lower.saturating_add(1 << shift))
+}
+
+/// Nearest-rank percentile of sorted samples.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let samples = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        let summary = Summary::new(&samples);
+        assert_eq!(summary.iterations, 100);
+        assert!((summary.mean - 0.0505).abs() < 1e-9);
+        assert!((summary.stddev - 0.029011).abs() < 1e-6);
+        assert_eq!(summary.p50, 0.05);
+        assert_eq!(summary.p99, 0.099);
+        assert_eq!(summary.max, 0.1);
+
+        let summary = Summary::new(&[Duration::from_secs(2)]);
+        assert_eq!((summary.stddev, summary.p50, summary.p99), (0.0, 2.0, 2.0));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let samples = [1, 5, 5, 9, 1000, 1023].map(Duration::from_nanos);
+        let histogram = Histogram::new(&samples);
+        let buckets = histogram
+            .buckets
+            .iter()
+            .map(|b| (b.lower_ns, b.upper_ns, b.count))
+            .collect::<Vec<_>>();
+        assert_eq!(buckets, [(1, 2, 1), (5, 6, 2), (8, 10, 1), (896, 1024, 2)]);
+
+        // Every latency falls into the bucket bounding it
+        for ns in (0..5000).chain([u64::MAX / 3, u64::MAX]) {
+            let (lower, upper) = bucket_bounds(bucket_index(ns));
+            assert!(lower <= ns && (ns < upper || upper == u64::MAX), "{}", ns);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! End-to-end checks that every transport returns the right answers.
+//!
+//! Each test starts the request-calculator of one transport as a child process
+//! and sends it every request type over a set of inputs through the client API
+//! the request-managers use: inputs written by `mpklink-bench generate` with
+//! their expected answers, empty input, Unicode and invalid UTF-8, and payloads
+//! at the size limits of the transports. Requests the calculators have to turn
+//! down, malformed ones and those just past the request size limit, have to come
+//! back with the right error code. Any answer that differs from the expected
+//! one, byte for byte, fails the test.
+//!
+//! `services/mpk-thread` runs both sides in one process, so the same requests go
+//! to a calculator compartment of the test itself, with and without a call
+//! gate, and its binary is run once per input on top.
+//!
+//! Every transport also gets all requests of all cases in one batch call, which
+//! the mpk transport sends in batches of frames.
+//!
+//! The calculators share fixed endpoints, so the tests take turns.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use mpklink::client::{self, Client, Transport};
+use mpklink::mpk::{BatchConfig, MESSAGE_CAPACITY};
+use wordcount::{analyze, ErrorCode, GrepMode, Output, Request, RequestKind, Response, Ties};
+
+// The mpk calculator resets its segments on startup, dropping requests sent before that
+const STARTUP_DELAY: Duration = Duration::from_millis(500);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Larger than a shared-memory slot's ring and a pipe's buffer, so both wrap several times
+const LARGE_LEN: usize = 4 << 20;
+
+// The calculators' request size limit, lowered so requests at and past it stay cheap
+const REQUEST_LIMIT: usize = 2 * LARGE_LEN;
+
+const UNICODE: &str = "Grüße aus Zürich, naïve café!\r\n\
+    日本語の 文章 と\u{3000}全角スペース\n\
+    emoji 👩‍👩‍👧 🇨🇭 ok👍🏽 👩‍👩‍👧\n\
+    combining e\u{301} vs é, e\u{301}\n\
+    right-to-left שלום עולם مرحبا بالعالم\n\
+    non\u{a0}breaking\u{2003}em\u{2028}separated\ttab\n\
+    \u{feff}bom ∑∫√ Ελληνικά кириллица Ελληνικά\n";
+
+/// Held by every test while it runs a calculator.
+static SERVICES: Mutex<()> = Mutex::new(());
+
+/// An input and the answers every transport has to give for it.
+struct Case {
+    name: String,
+    input: Vec<u8>,
+    answers: Vec<(RequestKind, Output)>,
+}
+
+/// A child process, killed when dropped.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../..")
+}
+
+fn scratch_dir() -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join("transports")
+}
+
+/// Every request type, searching for `word` with `grep`.
+fn kinds(word: &str) -> Vec<RequestKind> {
+    vec![
+        RequestKind::Total,
+        RequestKind::Counts,
+        RequestKind::Unique,
+        RequestKind::Top {
+            n: Some(10),
+            ties: Ties::Alphabetical,
+        },
+        RequestKind::Top {
+            n: Some(10),
+            ties: Ties::Include,
+        },
+        RequestKind::Top {
+            n: None,
+            ties: Ties::Alphabetical,
+        },
+        RequestKind::Histogram,
+        RequestKind::Ngrams { n: 1 },
+        RequestKind::Ngrams { n: 3 },
+        RequestKind::Grep {
+            word: word.to_string(),
+            mode: GrepMode::Lines,
+        },
+        RequestKind::Grep {
+            word: word.to_string(),
+            mode: GrepMode::Positions,
+        },
+    ]
+}
+
+/// A case whose answers come from the reference analysis.
+fn case(name: &str, input: Vec<u8>) -> Case {
+    let text = String::from_utf8_lossy(&input).into_owned();
+    let word = text
+        .split_whitespace()
+        .next()
+        .unwrap_or("missing")
+        .to_string();
+    let answers = kinds(&word)
+        .into_iter()
+        .map(|kind| {
+            let output = analyze(&kind, &text);
+            (kind, output)
+        })
+        .collect();
+    Case {
+        name: name.to_string(),
+        input,
+        answers,
+    }
+}
+
+/// A case written by `mpklink-bench generate`, checked against its answer files too.
+fn generated_case(dir: &Path, words: u64) -> Case {
+    let input_path = dir.join(format!("{}.in", words));
+    let case = case(
+        &format!("generated {}", words),
+        fs::read(&input_path).unwrap(),
+    );
+
+    let answer = |kind: &str| {
+        let path = input_path.with_extension(format!("{}.json", kind));
+        fs::read_to_string(path).unwrap().trim_end().to_string()
+    };
+    for (kind, expected) in [
+        (RequestKind::Total, answer("total")),
+        (RequestKind::Counts, answer("counts")),
+        (
+            RequestKind::Top {
+                n: Some(10),
+                ties: Ties::Alphabetical,
+            },
+            answer("top"),
+        ),
+    ] {
+        let (_, output) = case.answers.iter().find(|(k, _)| *k == kind).unwrap();
+        assert_eq!(
+            output.to_string(),
+            expected,
+            "{}: generated answer of {:?} differs from the analysis",
+            case.name,
+            kind
+        );
+    }
+    case
+}
+
+/// Input of `len` bytes less `overhead`, made of words that need no escaping.
+fn input_of_len(len: usize, overhead: usize) -> Vec<u8> {
+    let mut input = "word ".repeat((len - overhead) / 5).into_bytes();
+    input.resize(len - overhead, b'x');
+    input
+}
+
+/// Input whose `total` request takes up exactly `len` bytes.
+fn request_of_len(len: usize) -> Vec<u8> {
+    let overhead = Request::new(RequestKind::Total, "").to_json().len();
+    let input = input_of_len(len, overhead);
+    assert_eq!(
+        Request::new(RequestKind::Total, input.clone())
+            .to_json()
+            .len(),
+        len
+    );
+    input
+}
+
+/// Generated words interleaved with Unicode, shifting where multi-byte characters fall.
+fn large_input(dir: &Path) -> Vec<u8> {
+    let words = fs::read_to_string(dir.join("1000.in")).unwrap();
+    let mut input = String::with_capacity(LARGE_LEN + words.len());
+    let mut lines = words.lines().cycle();
+    while input.len() < LARGE_LEN {
+        input.push_str(lines.next().unwrap());
+        input.push('\n');
+        input.push_str(UNICODE);
+    }
+    input.into_bytes()
+}
+
+fn cases() -> &'static [Case] {
+    static CASES: OnceLock<Vec<Case>> = OnceLock::new();
+    CASES.get_or_init(|| {
+        // Calculators started from here inherit it, and the in-process one reads it first thing
+        std::env::set_var("WORDCOUNT_MAX_REQUEST_LEN", REQUEST_LIMIT.to_string());
+
+        let dir = scratch_dir().join("generated");
+        let status = Command::new(env!("CARGO_BIN_EXE_mpklink-bench"))
+            .args(["generate", "-n", "100", "-n", "1000", "--seed", "39", "-o"])
+            .arg(&dir)
+            .arg("--words-file")
+            .arg(root().join("tests/words"))
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "generate failed");
+
+        vec![
+            generated_case(&dir, 100),
+            generated_case(&dir, 1000),
+            case("empty", Vec::new()),
+            case("whitespace", b" \n\t\r\n  \n".to_vec()),
+            case("unicode", UNICODE.as_bytes().to_vec()),
+            case(
+                "invalid utf-8",
+                b"caf\xc3 na\xefve \xff\xfe\n\xf0\x9f\x98 word word\xe2\x82".to_vec(),
+            ),
+            case("region-sized", request_of_len(MESSAGE_CAPACITY)),
+            case("region + 1", request_of_len(MESSAGE_CAPACITY + 1)),
+            case("large", large_input(&dir)),
+        ]
+    })
+}
+
+/// Builds the crate in `dir` and returns the path of its `binary`.
+///
+/// The target directory is given explicitly, so a `CARGO_TARGET_DIR` can't leave a
+/// stale binary where the test looks for it.
+fn build(dir: &Path, binary: &str) -> PathBuf {
+    let manifest = dir.join("Cargo.toml");
+    let target_dir = dir.join("target");
+    let status = Command::new("cargo")
+        .args(["build", "--quiet", "--manifest-path"])
+        .arg(&manifest)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "building {} failed", manifest.display());
+    target_dir.join("debug").join(binary)
+}
+
+fn start_calculator(transport: Transport) -> Process {
+    let dir = root()
+        .join("services")
+        .join(transport.service())
+        .join("request-calculator");
+    let binary = build(&dir, "request-calculator");
+
+    // A link left behind by a killed calculator would be opened before the new one replaces it
+    if transport == Transport::Shm {
+        match fs::remove_file(mpklink::shm::CONTROL_FLINK) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => panic!("{}", e),
+            _ => {}
+        }
+    }
+
+    let child = Command::new(binary)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(STARTUP_DELAY);
+    Process(child)
+}
+
+fn connect(transport: Transport) -> Box<dyn Client> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        match client::connect(transport) {
+            Ok(client) => return client,
+            Err(e) if Instant::now() >= deadline => panic!("connecting to {}: {}", transport, e),
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// Requests around the calculators' limits, named, with the answers they have to get.
+///
+/// Streamed requests count their header and input towards the size limit, the
+/// others the whole JSON request.
+fn limit_checks(streamed: bool) -> Vec<(String, Request, Response)> {
+    let overhead = if streamed {
+        RequestKind::Total.header().len()
+    } else {
+        Request::new(RequestKind::Total, "").to_json().len()
+    };
+    let at_limit = input_of_len(REQUEST_LIMIT, overhead);
+    let total = analyze(&RequestKind::Total, &String::from_utf8_lossy(&at_limit));
+    let grep = RequestKind::Grep {
+        word: "two words".to_string(),
+        mode: GrepMode::Lines,
+    };
+    let top = RequestKind::Top {
+        n: Some(0),
+        ties: Ties::Alphabetical,
+    };
+    vec![
+        (
+            "malformed grep".to_string(),
+            Request::new(grep, "two words"),
+            Response::error(ErrorCode::ParseFailure, ""),
+        ),
+        (
+            "top 0".to_string(),
+            Request::new(top, "a b"),
+            Response::error(ErrorCode::ParseFailure, ""),
+        ),
+        (
+            "at the limit".to_string(),
+            Request::new(RequestKind::Total, at_limit),
+            Response::Ok { result: total },
+        ),
+        (
+            "past the limit".to_string(),
+            Request::new(
+                RequestKind::Total,
+                input_of_len(REQUEST_LIMIT + 1, overhead),
+            ),
+            Response::error(ErrorCode::PayloadTooLarge, ""),
+        ),
+    ]
+}
+
+fn assert_answer(expected: Response, response: Response, context: &str) {
+    match (expected, response) {
+        // Only the code matters, messages may say anything
+        (Response::Error { error: expected }, Response::Error { error }) => {
+            assert_eq!(expected.code, error.code, "{}: {}", context, error)
+        }
+        (expected, response) => {
+            assert_eq!(expected.to_json(), response.to_json(), "{}", context)
+        }
+    }
+}
+
+fn check_transport(transport: Transport) {
+    let _services = SERVICES.lock().unwrap_or_else(|e| e.into_inner());
+    let cases = cases();
+    let _calculator = start_calculator(transport);
+    let mut client = connect(transport);
+    let streamed = transport == Transport::Shm;
+    check_client(transport.name(), client.as_mut(), cases, streamed);
+}
+
+/// Sends every request of `cases` and [`limit_checks`] through `client`, one by one
+/// and then in batches.
+fn check_client(transport: &str, client: &mut dyn Client, cases: &[Case], streamed: bool) {
+    let checks = cases
+        .iter()
+        .flat_map(|case| {
+            case.answers.iter().map(move |(kind, output)| {
+                let request = Request::new(kind.clone(), case.input.clone());
+                let expected = Response::Ok {
+                    result: output.clone(),
+                };
+                (case.name.clone(), request, expected)
+            })
+        })
+        .chain(limit_checks(streamed))
+        .collect::<Vec<_>>();
+
+    for (name, request, expected) in &checks {
+        let kind = &request.kind;
+        let response = client
+            .call(request)
+            .unwrap_or_else(|e| panic!("{} {} {:?}: {}", transport, name, kind, e));
+
+        // Results are compared as the JSON they are sent as, which `Output` can't always
+        // be parsed back from: a histogram and word counts look the same
+        let context = format!("{} answered {} {:?} wrongly", transport, name, kind);
+        assert_answer(expected.clone(), response, &context);
+    }
+
+    // Fewer requests per batch than the calculator's channels hold, so batches queue up
+    let config = BatchConfig {
+        max_requests: 3,
+        linger: Duration::from_micros(100),
+    };
+    let requests = checks
+        .iter()
+        .map(|(_, request, _)| request.clone())
+        .collect::<Vec<_>>();
+    let responses = client
+        .call_batch(&requests, &config)
+        .unwrap_or_else(|e| panic!("{} batch: {}", transport, e));
+    assert_eq!(responses.len(), requests.len());
+    for ((name, request, expected), response) in checks.iter().zip(responses) {
+        let context = format!(
+            "{} answered {} {:?} in a batch wrongly",
+            transport, name, request.kind
+        );
+        assert_answer(expected.clone(), response, &context);
+    }
+}
+
+#[test]
+fn test_pipe() {
+    check_transport(Transport::Pipe);
+}
+
+#[test]
+fn test_uds() {
+    check_transport(Transport::Uds);
+}
+
+#[test]
+fn test_shm() {
+    check_transport(Transport::Shm);
+}
+
+#[test]
+fn test_mpk() {
+    check_transport(Transport::Mpk);
+}
+
+#[test]
+fn test_mpk_thread() {
+    check_mpk_thread(false);
+}
+
+#[test]
+fn test_mpk_thread_gate() {
+    check_mpk_thread(true);
+}
+
+fn check_mpk_thread(gated: bool) {
+    let _services = SERVICES.lock().unwrap_or_else(|e| e.into_inner());
+    let cases = cases();
+    mpklink::thread::run(gated, move |client| {
+        check_client("mpk-thread", client, cases, false)
+    })
+    .unwrap();
+
+    let flags: &[&str] = if gated { &["--gate"] } else { &[] };
+    let dir = root().join("services/mpk-thread");
+    let binary = build(&dir, "mpk-thread");
+
+    let inputs = scratch_dir().join("mpk-thread");
+    fs::create_dir_all(&inputs).unwrap();
+    for (index, case) in cases.iter().enumerate() {
+        let input = inputs.join(format!("{}.in", index));
+        fs::write(&input, &case.input).unwrap();
+        let output = Command::new(&binary)
+            .args(flags)
+            .arg("--file")
+            .arg(&input)
+            .arg("total")
+            .stderr(Stdio::inherit())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "mpk-thread {:?} failed on {}",
+            flags,
+            case.name
+        );
+
+        // Logging goes to stderr, the result is all there is on stdout
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let result = stdout
+            .lines()
+            .last()
+            .unwrap_or_else(|| panic!("mpk-thread printed no result for {}", case.name));
+        let (_, total) = &case.answers[0];
+        assert_eq!(
+            result,
+            total.to_string(),
+            "mpk-thread {:?} answered {} wrongly",
+            flags,
+            case.name
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Event fan-out from one producer process to any number of subscriber processes.
+//!
+//! Unlike the manager/calculator pair of [`crate::mpk`], a broadcast has one
+//! producer appending to a ring and subscribers that each read all of it at
+//! their own pace, joining and leaving whenever they like. The ring is a
+//! [`pkey_mprotect::Broadcast`] mapped from a POSIX shared memory object, so
+//! every process has to open it with the same [`BroadcastConfig`].
+
+use std::io;
+use std::sync::Arc;
+
+use pkey_mprotect::broadcast::{BroadcastError, Producer, SlowSubscribers, Start, Subscriber};
+use pkey_mprotect::{Broadcast, BroadcastConfig, ProtectionKeys};
+
+use crate::mpk::open_shm_fd;
+
+pub const SHMEM_BROADCAST_FLINK: &str = "/broadcast_mpk";
+
+/// Maps the broadcast ring named `name` under a protection key of its own.
+pub fn open(name: &str, config: &BroadcastConfig) -> io::Result<Arc<Broadcast>> {
+    let pkey = ProtectionKeys::new(false).map_err(io::Error::other)?;
+    let fd = open_shm_fd(name, config.mapping_len())?;
+    let broadcast = pkey.make_broadcast_fd(config, fd);
+    // SAFETY: fd was opened above and the mapping doesn't need it anymore
+    unsafe { libc::close(fd) };
+    broadcast.map_err(io::Error::other)
+}
+
+/// Attaches to the ring named `name` as its producer.
+pub fn produce(
+    name: &str,
+    config: &BroadcastConfig,
+    policy: SlowSubscribers,
+) -> io::Result<Producer> {
+    open(name, config)?.producer(policy).map_err(to_io)
+}
+
+/// Subscribes to the ring named `name`, reading from `start` on.
+pub fn subscribe(name: &str, config: &BroadcastConfig, start: Start) -> io::Result<Subscriber> {
+    open(name, config)?.subscribe(start).map_err(to_io)
+}
+
+fn to_io(e: BroadcastError) -> io::Error {
+    let kind = match e {
+        BroadcastError::ProducerExists => io::ErrorKind::AddrInUse,
+        BroadcastError::TooManySubscribers => io::ErrorKind::ConnectionRefused,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fan_out_between_mappings() {
+        let name = format!("/mpklink-test-broadcast-{}", std::process::id());
+        let config = BroadcastConfig {
+            slots: 8,
+            slot_size: 256,
+        };
+        let mut producer = produce(&name, &config, SlowSubscribers::Overwrite).unwrap();
+        let error = produce(&name, &config, SlowSubscribers::Block)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        let mut subscribers = (0..3)
+            .map(|_| subscribe(&name, &config, Start::Latest).unwrap())
+            .collect::<Vec<_>>();
+        producer.send(b"to everyone").unwrap();
+        for subscriber in &mut subscribers {
+            assert_eq!(subscriber.recv().unwrap(), b"to everyone");
+        }
+
+        let name = std::ffi::CString::new(name).unwrap();
+        unsafe { libc::shm_unlink(name.as_ptr()) };
+    }
+}
@@ -0,0 +1,493 @@
+//! Command-line interface shared by every request-manager.
+//!
+//! ```text
+//! request-manager [--transport T] (--file PATH | --stdin | --string TEXT)
+//!                 [--format text|json] [--repeat N] [--timings] <TYPE> [--n N]
+//!                 [--ties include] [--word WORD] [--mode positions]
+//!                 [--batch N [--linger-us US]]
+//! ```
+//!
+//! Each manager defaults to the transport of its service directory.
+//! `services/mpk-thread` takes the same options, except `--transport`, and
+//! sends the requests to a calculator in its own process through [`run`].
+//!
+//! With `--timings`, every request prints a JSON line with its [`Timings`] to
+//! standard error, e.g.
+//! `{"request":1,"transport":"pipe","type":"total","serialise_ns":812,...,"total_ns":90210}`.
+//!
+//! The calculators that queue messages in shared memory take their options
+//! from [`CalculatorCli`]:
+//!
+//! ```text
+//! request-calculator [--capacity N]
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, ValueEnum};
+use serde::Serialize;
+use wordcount::{GrepMode, Output, Request, RequestKind, Response, Ties};
+
+use crate::client::{self, Client, Timings, Transport};
+use crate::mpk::BatchConfig;
+
+#[derive(Debug, Parser)]
+#[command(about = "Sends a word-analysis request to a request-calculator")]
+pub struct Cli {
+    /// Analysis to request
+    #[arg(value_name = "TYPE")]
+    pub kind: Kind,
+
+    /// Number of words for `top` or words per n-gram for `ngrams`
+    #[arg(short, long)]
+    pub n: Option<usize>,
+
+    /// How `top` treats words tied with the last one [default: alphabetical]
+    #[arg(long, value_enum)]
+    pub ties: Option<TiesArg>,
+
+    /// Word to look for with `grep`
+    #[arg(short, long, required_if_eq("kind", "grep"))]
+    pub word: Option<String>,
+
+    /// What `grep` reports for every match [default: lines]
+    #[arg(long, value_enum)]
+    pub mode: Option<ModeArg>,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// Transport to reach the calculator through
+    #[arg(short, long, value_enum)]
+    pub transport: Option<Transport>,
+
+    /// How to print the result
+    #[arg(long, value_enum, default_value_t)]
+    pub format: Format,
+
+    /// Number of times to send the request; the last result is printed
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub repeat: u32,
+
+    /// Print the phases of every request as a JSON line to standard error
+    #[arg(long, conflicts_with = "batch")]
+    pub timings: bool,
+
+    /// Send the repeats in batches of up to N requests, where the transport supports it
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub batch: Option<u32>,
+
+    /// Microseconds a batch waits for more requests before it is sent
+    #[arg(long, requires = "batch")]
+    pub linger_us: Option<u64>,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Answers word-analysis requests of request-managers")]
+pub struct CalculatorCli {
+    /// Messages a channel holds before senders have to wait; defaults to the transport's
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub capacity: Option<u32>,
+}
+
+impl CalculatorCli {
+    /// Parses the calculator's arguments, exiting with a usage message if they are wrong.
+    pub fn parse_args() -> Self {
+        Self::parse()
+    }
+
+    /// The capacity asked for, or `default`.
+    pub fn capacity_or(&self, default: usize) -> usize {
+        self.capacity.map_or(default, |capacity| capacity as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Total,
+    Counts,
+    Unique,
+    Top,
+    Histogram,
+    Ngrams,
+    Grep,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TiesArg {
+    #[default]
+    Alphabetical,
+    Include,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ModeArg {
+    #[default]
+    Lines,
+    Positions,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One value, or one `key<TAB>value` pair per line
+    #[default]
+    Text,
+    /// The result as JSON
+    Json,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+pub struct InputArgs {
+    /// Read the input from a file
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+
+    /// Read the input from standard input
+    #[arg(long)]
+    pub stdin: bool,
+
+    /// Use the given text as input
+    #[arg(short, long)]
+    pub string: Option<String>,
+}
+
+impl Cli {
+    pub fn request_kind(&self) -> RequestKind {
+        match self.kind {
+            Kind::Total => RequestKind::Total,
+            Kind::Counts => RequestKind::Counts,
+            Kind::Unique => RequestKind::Unique,
+            Kind::Top => RequestKind::Top {
+                n: self.n,
+                ties: match self.ties.unwrap_or_default() {
+                    TiesArg::Alphabetical => Ties::Alphabetical,
+                    TiesArg::Include => Ties::Include,
+                },
+            },
+            Kind::Histogram => RequestKind::Histogram,
+            Kind::Ngrams => RequestKind::Ngrams {
+                n: self.n.unwrap_or(2),
+            },
+            Kind::Grep => RequestKind::Grep {
+                word: self.word.clone().unwrap_or_default(),
+                mode: match self.mode.unwrap_or_default() {
+                    ModeArg::Lines => GrepMode::Lines,
+                    ModeArg::Positions => GrepMode::Positions,
+                },
+            },
+        }
+    }
+
+    /// Rejects options the kind doesn't use, which clap can't tie to the kind.
+    pub fn check(&self) -> Result<(), String> {
+        let used_by = [
+            ("--n", self.n.is_some(), &[Kind::Top, Kind::Ngrams][..]),
+            ("--ties", self.ties.is_some(), &[Kind::Top]),
+            ("--word", self.word.is_some(), &[Kind::Grep]),
+            ("--mode", self.mode.is_some(), &[Kind::Grep]),
+        ];
+        for (option, given, kinds) in used_by {
+            if given && !kinds.contains(&self.kind) {
+                let kinds = kinds
+                    .iter()
+                    .map(|kind| kind.to_possible_value().unwrap().get_name().to_string())
+                    .collect::<Vec<_>>();
+                return Err(format!(
+                    "{} only applies to {}",
+                    option,
+                    kinds.join(" and ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// How `--batch` and `--linger-us` batch requests, if they do.
+    pub fn batch_config(&self) -> Option<BatchConfig> {
+        let batch = self.batch?;
+        let mut config = BatchConfig {
+            max_requests: batch as usize,
+            ..BatchConfig::default()
+        };
+        if let Some(linger_us) = self.linger_us {
+            config.linger = Duration::from_micros(linger_us);
+        }
+        Some(config)
+    }
+}
+
+/// Where the input comes from; standard input is read once and kept for repeats.
+enum Source {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl Source {
+    fn new(input: &InputArgs) -> io::Result<Self> {
+        if let Some(file) = &input.file {
+            return Ok(Self::File(file.clone()));
+        }
+        if let Some(string) = &input.string {
+            return Ok(Self::Bytes(string.clone().into_bytes()));
+        }
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        Ok(Self::Bytes(bytes))
+    }
+
+    fn open(&self) -> io::Result<Box<dyn Read + '_>> {
+        Ok(match self {
+            Self::File(path) => Box::new(File::open(path)?),
+            Self::Bytes(bytes) => Box::new(&bytes[..]),
+        })
+    }
+}
+
+/// One line of `--timings`.
+#[derive(Serialize)]
+struct TimingLine {
+    /// 1-based number of the request among `--repeat`.
+    request: u32,
+    transport: &'static str,
+    #[serde(rename = "type")]
+    kind: Kind,
+    #[serde(flatten)]
+    timings: Timings,
+}
+
+/// Runs a request-manager whose transport defaults to `transport`.
+///
+/// Failed requests exit with their [`wordcount::ErrorCode::exit_code`].
+pub fn main(transport: Transport) -> io::Result<()> {
+    let cli = Cli::parse();
+    if let Err(e) = cli.check() {
+        Cli::command().error(ErrorKind::ArgumentConflict, e).exit();
+    }
+    let transport = cli.transport.unwrap_or(transport);
+    let mut client = client::connect(transport)?;
+    run(&cli, transport.name(), client.as_mut())
+}
+
+/// Sends the requests `cli` asks for through `client` and prints the result like [`main`].
+///
+/// `transport` names the transport in `--timings` lines.
+pub fn run(cli: &Cli, transport: &'static str, client: &mut dyn Client) -> io::Result<()> {
+    let kind = cli.request_kind();
+    let source = Source::new(&cli.input)?;
+
+    let mut response = None;
+    if let Some(config) = cli.batch_config() {
+        response = call_batches(client, &kind, &source, cli.repeat, &config)?;
+    } else {
+        for request in 1..=cli.repeat {
+            let (result, timings) = client.call_reader_timed(&kind, &mut source.open()?)?;
+            if cli.timings {
+                let line = TimingLine {
+                    request,
+                    transport,
+                    kind: cli.kind,
+                    timings,
+                };
+                eprintln!(
+                    "{}",
+                    serde_json::to_string(&line).map_err(io::Error::other)?
+                );
+            }
+            response = Some(result);
+        }
+    }
+
+    match response.expect("at least one request is sent") {
+        Response::Ok { result } => print_result(&result, cli.format),
+        Response::Error { error } => {
+            eprintln!("Request failed: {}", error);
+            std::process::exit(error.code.exit_code());
+        }
+    }
+    Ok(())
+}
+
+/// Sends `repeat` requests in batches and returns the last response.
+fn call_batches(
+    client: &mut dyn Client,
+    kind: &RequestKind,
+    source: &Source,
+    repeat: u32,
+    config: &BatchConfig,
+) -> io::Result<Option<Response>> {
+    // Every request of every batch carries the same input
+    let mut input = Vec::new();
+    source.open()?.read_to_end(&mut input)?;
+
+    let mut response = None;
+    let mut remaining = repeat as usize;
+    while remaining > 0 {
+        let len = remaining.min(config.max_requests);
+        let requests = (0..len)
+            .map(|_| Request::new(kind.clone(), input.clone()))
+            .collect::<Vec<_>>();
+        response = client.call_batch(&requests, config)?.pop();
+        remaining -= len;
+    }
+    Ok(response)
+}
+
+fn print_result(result: &Output, format: Format) {
+    if format == Format::Json {
+        println!("{}", result);
+        return;
+    }
+    match result {
+        Output::Count(count) => println!("{}", count),
+        Output::Counts(counts) => {
+            for (word, count) in counts {
+                println!("{}\t{}", word, count);
+            }
+        }
+        Output::Ranking(ranking) => {
+            for (word, count) in ranking {
+                println!("{}\t{}", word, count);
+            }
+        }
+        Output::Histogram(histogram) => {
+            for (len, count) in histogram {
+                println!("{}\t{}", len, count);
+            }
+        }
+        Output::Matches(matches) => {
+            for position in matches {
+                println!("{}", position);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("request-manager").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_request_kinds() {
+        let cli = parse(&["top", "--n", "3", "--string", "a b", "--ties", "include"]).unwrap();
+        assert_eq!(
+            cli.request_kind(),
+            RequestKind::Top {
+                n: Some(3),
+                ties: Ties::Include
+            }
+        );
+
+        let cli = parse(&["--file", "in.txt", "ngrams"]).unwrap();
+        assert_eq!(cli.request_kind(), RequestKind::Ngrams { n: 2 });
+        assert_eq!(cli.input.file, Some(PathBuf::from("in.txt")));
+
+        let cli = parse(&[
+            "grep",
+            "-w",
+            "a",
+            "--mode",
+            "positions",
+            "--stdin",
+            "-t",
+            "shm",
+        ])
+        .unwrap();
+        assert_eq!(cli.transport, Some(Transport::Shm));
+        assert!(matches!(
+            cli.request_kind(),
+            RequestKind::Grep {
+                mode: GrepMode::Positions,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_calculator_capacity() {
+        let parse = |args: &[&str]| {
+            CalculatorCli::try_parse_from(
+                std::iter::once("request-calculator").chain(args.iter().copied()),
+            )
+        };
+        assert_eq!(parse(&[]).unwrap().capacity_or(4), 4);
+        assert_eq!(parse(&["--capacity", "32"]).unwrap().capacity_or(4), 32);
+        assert!(parse(&["--capacity", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+        // Exactly one input is required
+        assert!(parse(&["total"]).is_err());
+        assert!(parse(&["total", "--stdin", "--string", "a"]).is_err());
+        // grep needs a word
+        assert!(parse(&["grep", "--stdin"]).is_err());
+        assert!(parse(&["total", "--stdin", "--repeat", "0"]).is_err());
+        assert!(parse(&["median", "--stdin"]).is_err());
+        assert!(parse(&["total", "--stdin", "--batch", "4", "--timings"]).is_err());
+        assert!(parse(&["total", "--stdin", "--linger-us", "10"]).is_err());
+    }
+
+    #[test]
+    fn test_options_of_other_kinds() {
+        let check = |args: &[&str]| parse(args).unwrap().check();
+        assert_eq!(check(&["ngrams", "--stdin", "--n", "3"]), Ok(()));
+        assert_eq!(
+            check(&["grep", "--stdin", "-w", "a", "--mode", "lines"]),
+            Ok(())
+        );
+        assert_eq!(
+            check(&["total", "--stdin", "--n", "3"]),
+            Err("--n only applies to top and ngrams".to_string())
+        );
+        assert_eq!(
+            check(&["ngrams", "--stdin", "--ties", "include"]),
+            Err("--ties only applies to top".to_string())
+        );
+        assert!(check(&["top", "--stdin", "--word", "a"]).is_err());
+        assert!(check(&["counts", "--stdin", "--mode", "positions"]).is_err());
+    }
+
+    #[test]
+    fn test_batch_config() {
+        assert_eq!(parse(&["total", "--stdin"]).unwrap().batch_config(), None);
+        let cli = parse(&["total", "--stdin", "--batch", "8", "--linger-us", "250"]).unwrap();
+        assert_eq!(
+            cli.batch_config(),
+            Some(BatchConfig {
+                max_requests: 8,
+                linger: Duration::from_micros(250),
+            })
+        );
+    }
+
+    #[test]
+    fn test_timing_line() {
+        let line = TimingLine {
+            request: 2,
+            transport: Transport::Uds.name(),
+            kind: Kind::Histogram,
+            timings: Timings {
+                serialise_ns: 1,
+                send_ns: 2,
+                compute_ns: None,
+                receive_ns: 3,
+                deserialise_ns: 4,
+                total_ns: 10,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            r#"{"request":2,"transport":"uds","type":"histogram","serialise_ns":1,"send_ns":2,"compute_ns":null,"receive_ns":3,"deserialise_ns":4,"total_ns":10}"#
+        );
+    }
+}
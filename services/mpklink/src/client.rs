@@ -0,0 +1,354 @@
+//! One way for managers to talk to the calculator of any transport.
+//!
+//! Every request is timed with a monotonic clock and split into the phases of
+//! [`Timings`], with the compute time taken from the response metadata, so
+//! what the transport itself costs can be told apart from the analysis.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::time::Instant;
+
+use wordcount::{ErrorCode, Metadata, Output, Request, RequestKind, Response, MAX_RESPONSE_LEN};
+
+use crate::mpk::BatchConfig;
+use crate::shm::{SlotTable, CONTROL_FLINK};
+use crate::{mpk, pipe, thread, uds};
+
+/// The IPC mechanisms a calculator can be reached through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// `services/os-pipe`: length-prefixed frames over a FIFO pair.
+    Pipe,
+    /// `services/unix-domain-sockets`: JSON lines over a socket.
+    Uds,
+    /// `services/shared-memory`: streamed through a slot of the control segment.
+    Shm,
+    /// `services/mpk`: protection-key regions of one page each.
+    Mpk,
+}
+
+impl Transport {
+    pub const ALL: [Transport; 4] = [Self::Pipe, Self::Uds, Self::Shm, Self::Mpk];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pipe => "pipe",
+            Self::Uds => "uds",
+            Self::Shm => "shm",
+            Self::Mpk => "mpk",
+        }
+    }
+
+    /// Directory of the transport's services below `services/`.
+    pub fn service(self) -> &'static str {
+        match self {
+            Self::Pipe => "os-pipe",
+            Self::Uds => "unix-domain-sockets",
+            Self::Shm => "shared-memory",
+            Self::Mpk => "mpk",
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Where the time of one request went.
+///
+/// `receive_ns` is the wait for the response minus the compute time the
+/// calculator reported, i.e. what the transport costs on the way back. The
+/// calculator may compute while the manager is still sending, as streamed
+/// requests do or as happens when both share a core, so `receive_ns` only
+/// covers what is left of the wait and stops at zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Timings {
+    /// Turning the request into the bytes sent.
+    pub serialise_ns: u64,
+    /// Handing the request to the transport.
+    pub send_ns: u64,
+    /// Answering the request, as reported by the calculator; `None` if it didn't say.
+    pub compute_ns: Option<u64>,
+    /// Waiting for the response and reading it, minus the compute time.
+    pub receive_ns: u64,
+    /// Parsing the response.
+    pub deserialise_ns: u64,
+    /// From the start of serialising to the end of deserialising.
+    pub total_ns: u64,
+}
+
+impl Timings {
+    /// Splits `wait_ns` into compute and receive time using `meta`.
+    fn with_wait(mut self, wait_ns: u64, meta: Option<Metadata>) -> Self {
+        self.compute_ns = meta.map(|meta| meta.compute_ns);
+        self.receive_ns = wait_ns.saturating_sub(self.compute_ns.unwrap_or(0));
+        self
+    }
+}
+
+/// Laps of a monotonic clock.
+struct Stopwatch {
+    start: Instant,
+    last: Instant,
+}
+
+impl Stopwatch {
+    fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+        }
+    }
+
+    /// Nanoseconds since the previous lap.
+    fn lap(&mut self) -> u64 {
+        let now = Instant::now();
+        let lap = now - self.last;
+        self.last = now;
+        lap.as_nanos() as u64
+    }
+
+    /// Nanoseconds since the start.
+    fn total(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+/// A connection to a calculator.
+///
+/// Errors are I/O failures of the transport; failed requests come back as
+/// [`Response::Error`].
+pub trait Client {
+    /// Sends a request and waits for its response, timing every phase.
+    fn call_timed(&mut self, request: &Request) -> io::Result<(Response, Timings)>;
+
+    /// Sends a request and waits for its response.
+    fn call(&mut self, request: &Request) -> io::Result<Response> {
+        Ok(self.call_timed(request)?.0)
+    }
+
+    /// Sends `requests` and waits for all of their responses, which come in the same order.
+    ///
+    /// Transports that can batch requests do so as `config` says, the others send them one by one.
+    fn call_batch(
+        &mut self,
+        requests: &[Request],
+        config: &BatchConfig,
+    ) -> io::Result<Vec<Response>> {
+        let _ = config;
+        requests.iter().map(|request| self.call(request)).collect()
+    }
+
+    /// Sends a request whose input is read from `input`, timing every phase.
+    ///
+    /// Transports that can stream do so without holding the whole input.
+    /// Others read it all before the clock starts.
+    fn call_reader_timed(
+        &mut self,
+        kind: &RequestKind,
+        input: &mut dyn Read,
+    ) -> io::Result<(Response, Timings)> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        self.call_timed(&Request::new(kind.clone(), bytes))
+    }
+
+    /// Sends a request whose input is read from `input`.
+    fn call_reader(&mut self, kind: &RequestKind, input: &mut dyn Read) -> io::Result<Response> {
+        Ok(self.call_reader_timed(kind, input)?.0)
+    }
+}
+
+/// Connects to the calculator behind `transport`, waiting for it if needed.
+pub fn connect(transport: Transport) -> io::Result<Box<dyn Client>> {
+    Ok(match transport {
+        Transport::Pipe => Box::new(pipe::Connection::connect()?),
+        Transport::Uds => Box::new(uds::Client::connect()?),
+        Transport::Shm => Box::new(ShmClient(SlotTable::open(CONTROL_FLINK)?)),
+        Transport::Mpk => Box::new(mpk::Client::connect()?),
+    })
+}
+
+/// A transport that carries a serialized request and its response as one message each.
+trait Exchange {
+    fn send(&mut self, request: &[u8]) -> io::Result<()>;
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+
+    /// Parses the response to a request of `kind`, with the metadata the calculator sent.
+    fn parse(&mut self, kind: &RequestKind, response: &[u8]) -> (Response, Option<Metadata>) {
+        Response::parse_with_metadata(kind, &String::from_utf8_lossy(response))
+    }
+}
+
+/// Sends `request` over `link` and waits for the response.
+fn exchange(link: &mut impl Exchange, request: &Request) -> io::Result<(Response, Timings)> {
+    let mut watch = Stopwatch::start();
+    let mut timings = Timings::default();
+    let bytes = request.to_json();
+    timings.serialise_ns = watch.lap();
+
+    link.send(bytes.as_bytes())?;
+    timings.send_ns = watch.lap();
+    let response = link.recv()?;
+    let wait_ns = watch.lap();
+    let (response, meta) = link.parse(&request.kind, &response);
+    timings.deserialise_ns = watch.lap();
+
+    timings.total_ns = watch.total();
+    Ok((response, timings.with_wait(wait_ns, meta)))
+}
+
+impl Exchange for pipe::Connection {
+    fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        pipe::Connection::send(self, request)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        pipe::Connection::recv(self, MAX_RESPONSE_LEN)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "calculator closed the connection",
+            )
+        })
+    }
+}
+
+impl Client for pipe::Connection {
+    /// If the calculator went away before the request went out, e.g. because it
+    /// restarted, the request is sent over a new connection. Once any of it went
+    /// out, the calculator may have answered it, so the error is returned instead.
+    fn call_timed(&mut self, request: &Request) -> io::Result<(Response, Timings)> {
+        match exchange(self, request) {
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+                self.reconnect()?;
+                exchange(self, request)
+            }
+            result => result,
+        }
+    }
+}
+
+impl Exchange for uds::Client {
+    fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        uds::Client::send(self, request)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        uds::Client::recv(self)
+    }
+}
+
+impl Client for uds::Client {
+    fn call_timed(&mut self, request: &Request) -> io::Result<(Response, Timings)> {
+        exchange(self, request)
+    }
+}
+
+impl Exchange for mpk::Client {
+    fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        mpk::Client::send(self, request)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        Ok(mpk::Client::recv(self))
+    }
+
+    /// Word counts come as a map in the counts arena, next to an envelope without them.
+    fn parse(&mut self, kind: &RequestKind, response: &[u8]) -> (Response, Option<Metadata>) {
+        let (response, meta) =
+            Response::parse_with_metadata(kind, &String::from_utf8_lossy(response));
+        let response = match (response, self.counts()) {
+            (Response::Ok { .. }, Ok(Some(counts))) => Response::Ok {
+                result: Output::Counts(counts),
+            },
+            (_, Err(e)) => Response::error(ErrorCode::Internal, format!("malformed counts: {}", e)),
+            (response, _) => response,
+        };
+        (response, meta)
+    }
+}
+
+impl Client for mpk::Client {
+    fn call_timed(&mut self, request: &Request) -> io::Result<(Response, Timings)> {
+        exchange(self, request)
+    }
+
+    /// Requests that don't fit into a batch go on their own between batches.
+    fn call_batch(
+        &mut self,
+        requests: &[Request],
+        config: &BatchConfig,
+    ) -> io::Result<Vec<Response>> {
+        self.set_batching(*config);
+        for request in requests {
+            self.queue(request.to_json().as_bytes())?;
+        }
+        requests
+            .iter()
+            .map(|request| {
+                let response = self.recv_queued()?;
+                let text = String::from_utf8_lossy(&response);
+                Ok(Response::parse_with_metadata(&request.kind, &text).0)
+            })
+            .collect()
+    }
+}
+
+impl Exchange for thread::Client {
+    fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        thread::Client::send(self, request)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        thread::Client::recv(self)
+    }
+}
+
+impl Client for thread::Client {
+    /// Through a gate, `send_ns` only covers keeping the request for the call.
+    fn call_timed(&mut self, request: &Request) -> io::Result<(Response, Timings)> {
+        exchange(self, request)
+    }
+}
+
+/// Claims a slot for every request, so other managers can use it in between.
+struct ShmClient(SlotTable);
+
+impl Client for ShmClient {
+    fn call_timed(&mut self, request: &Request) -> io::Result<(Response, Timings)> {
+        let input = request
+            .input
+            .bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.call_reader_timed(&request.kind, &mut &input[..])
+    }
+
+    /// The input is streamed, so `send_ns` includes reading it.
+    fn call_reader_timed(
+        &mut self,
+        kind: &RequestKind,
+        input: &mut dyn Read,
+    ) -> io::Result<(Response, Timings)> {
+        let mut watch = Stopwatch::start();
+        let mut timings = Timings::default();
+        let header = kind.header();
+        timings.serialise_ns = watch.lap();
+
+        let slot = self.0.claim();
+        slot.send(header.as_bytes())?;
+        slot.send_stream(input)?;
+        timings.send_ns = watch.lap();
+        let response = slot.recv()?;
+        let wait_ns = watch.lap();
+        let (response, meta) =
+            Response::parse_with_metadata(kind, &String::from_utf8_lossy(&response));
+        timings.deserialise_ns = watch.lap();
+
+        timings.total_ns = watch.total();
+        Ok((response, timings.with_wait(wait_ns, meta)))
+    }
+}
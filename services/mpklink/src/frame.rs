@@ -0,0 +1,86 @@
+//! Length-prefixed framing for byte streams.
+//!
+//! A frame is its payload's length as a little-endian `u64` followed by the
+//! payload itself, so payloads may contain any byte, newlines included.
+
+use std::io::{self, Read, Write};
+
+/// Bytes a frame adds to its payload.
+pub const LEN_SIZE: usize = std::mem::size_of::<u64>();
+
+/// Writes `payload` as one frame.
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Reads the next frame, or `None` if the stream ended cleanly before it.
+///
+/// A frame longer than `max_len` is skipped and reported as
+/// [`io::ErrorKind::FileTooLarge`], leaving the stream at the next frame.
+/// A stream that ends inside a frame is reported as [`io::ErrorKind::UnexpectedEof`].
+pub fn read_frame(reader: &mut impl Read, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; LEN_SIZE];
+    let mut filled = 0;
+    while filled < LEN_SIZE {
+        match reader.read(&mut len[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let len = u64::from_le_bytes(len);
+    if len > max_len as u64 {
+        let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+        if skipped < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("frame is {} bytes, at most {} are accepted", len, max_len),
+        ));
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"line one\nline two\0").unwrap();
+        write_frame(&mut stream, b"").unwrap();
+        write_frame(&mut stream, &[0xff; 100]).unwrap();
+        write_frame(&mut stream, b"last").unwrap();
+
+        let mut reader = &stream[..];
+        assert_eq!(
+            read_frame(&mut reader, 64).unwrap().unwrap(),
+            b"line one\nline two\0"
+        );
+        assert_eq!(read_frame(&mut reader, 64).unwrap().unwrap(), b"");
+        let err = read_frame(&mut reader, 64).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        assert_eq!(read_frame(&mut reader, 64).unwrap().unwrap(), b"last");
+        assert!(read_frame(&mut reader, 64).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_truncated_frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"payload").unwrap();
+
+        for len in 1..stream.len() {
+            let err = read_frame(&mut &stream[..len], 64).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{}", len);
+        }
+    }
+}
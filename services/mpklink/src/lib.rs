@@ -0,0 +1,14 @@
+//! Transport plumbing shared by the request-managers and request-calculators.
+//!
+//! Anything whose layout or framing both ends have to agree on lives here,
+//! so the two sides of a transport can't drift apart.
+
+pub mod broadcast;
+pub mod cli;
+pub mod client;
+pub mod frame;
+pub mod mpk;
+pub mod pipe;
+pub mod shm;
+pub mod thread;
+pub mod uds;
@@ -0,0 +1,1361 @@
+//! Protection-key regions shared between the MPK request-manager and request-calculator.
+//!
+//! Requests and responses each go through a [`Channel`]: a ring of one-page
+//! regions mapped from POSIX shared memory objects and tagged with a protection
+//! key per direction, plus a segment counting the messages sent and received.
+//! A sender needs a credit, a region the receiver is done with, so a full
+//! channel makes it wait or fail instead of overwriting unread messages. The
+//! calculator picks the capacity and owns every segment, keeping them across
+//! managers.
+//!
+//! Messages longer than a region are split into chunks of [`MESSAGE_CAPACITY`]
+//! bytes over consecutive regions, each but the last marked as continued, so
+//! the receiver reads a long message while the sender is still writing it.
+//!
+//! One manager at a time holds the sending end of the request channel, see
+//! [`Channel::claim`]. Each claim starts a session whose number every message
+//! carries: the calculator drops requests of an earlier session and answers
+//! with the session of the request, so a manager never reads what was meant
+//! for one that died before it.
+//!
+//! The 1-byte flags of [`send`] and [`recv`] are the same handoff with room
+//! for a single message.
+//!
+//! Small requests can share a region: [`Client::queue`] collects them as
+//! frames of one batch [`Message`] as [`BatchConfig`] says, so a whole batch
+//! costs one region write and one doorbell, i.e. one move of the `sent`
+//! counter. The calculator reads all frames under a single guard and answers
+//! with batches of responses.
+//!
+//! Word counts would take many regions, so the calculator builds them as a
+//! [`SharedCounts`] map in an arena shared with the manager and only sends
+//! where the map starts; see [`write_counts`] and [`read_counts`]. Counts the
+//! arena can't hold are sent as JSON like any other result, see [`send_counts`].
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::CString;
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use libc::{ftruncate, shm_open};
+use libc::{O_CREAT, O_RDWR, S_IRGRP, S_IRUSR, S_IWGRP, S_IWUSR};
+use pkey_mprotect::shared::{RelPtr, SharedError, SharedHashMap, SharedString};
+use pkey_mprotect::{backoff, is_alive, Arena, ProtectedRegion, ProtectionKeys};
+use shared_memory::{Shmem, ShmemConf, ShmemError};
+use wordcount::{Metadata, Output, Response};
+
+use crate::frame::{self, read_frame, write_frame};
+
+pub const SHMEM_REQUEST_FLINK: &str = "/request_mem";
+pub const SHMEM_RESPONSE_FLINK: &str = "/response_mem";
+
+pub const SHMEM_REQUEST_CREDITS_FLINK: &str = "/request_credits";
+pub const SHMEM_RESPONSE_CREDITS_FLINK: &str = "/response_credits";
+
+pub const SHMEM_COUNTS_FLINK: &str = "/counts_mem";
+
+/// Regions per channel unless the calculator is told otherwise.
+pub const CHANNEL_CAPACITY: usize = 4;
+
+// Values of the 1-byte readiness flags
+pub const READY: u8 = b'D';
+pub const NOT_READY: u8 = b'N';
+
+// A protected region maps exactly one page
+pub const PAGE_SIZE: usize = 4096;
+pub const MESSAGE_CAPACITY: usize = PAGE_SIZE - 2 * std::mem::size_of::<usize>();
+/// Longest request or response that fits into a batch.
+pub const FRAME_CAPACITY: usize = MESSAGE_CAPACITY - frame::LEN_SIZE;
+
+/// Bytes of the arena word counts are built in.
+///
+/// Counts that don't fit go as JSON in the response instead, see [`send_counts`].
+pub const COUNTS_CAPACITY: usize = 64 << 20;
+
+/// Word counts as they are built in the counts arena.
+pub type SharedCounts = SharedHashMap<SharedString, u64>;
+
+/// Contents of a protected region: the bytes themselves rather than a pointer,
+/// which would mean nothing in the other process.
+#[derive(Clone, Copy)]
+pub struct Message {
+    len: u32,
+    /// Frames of a batch, 0 if the message is a single request or response.
+    frames: u16,
+    /// Whether the next region holds the next chunk of the same message.
+    more: u16,
+    /// Session of the manager the message comes from or goes to.
+    session: u32,
+    /// Offset of the [`SharedCounts`] that go with the message, 0 if there are none.
+    counts: u32,
+    data: [u8; MESSAGE_CAPACITY],
+}
+
+impl Message {
+    pub fn empty() -> Self {
+        Self {
+            len: 0,
+            frames: 0,
+            more: 0,
+            session: 0,
+            counts: 0,
+            data: [0; MESSAGE_CAPACITY],
+        }
+    }
+
+    /// Copies `s` into a message, or returns `None` if it doesn't fit.
+    pub fn new(s: &str) -> Option<Self> {
+        Self::from_bytes(s.as_bytes())
+    }
+
+    /// Copies `bytes` into a message, or returns `None` if they don't fit.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut message = Self::empty();
+        message.data.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        message.len = bytes.len() as u32;
+        Some(message)
+    }
+
+    /// Appends `payload` as the next frame of a batch, or returns `false` if it doesn't fit.
+    ///
+    /// An empty message turns into a batch with its first frame.
+    pub fn push_frame(&mut self, payload: &[u8]) -> bool {
+        debug_assert!(self.frames > 0 || self.len == 0, "not a batch");
+        let len = self.len as usize;
+        let mut rest = &mut self.data[len..];
+        if rest.len() < frame::LEN_SIZE + payload.len() {
+            return false;
+        }
+        write_frame(&mut rest, payload).expect("frame fits into the message");
+        self.len = (len + frame::LEN_SIZE + payload.len()) as u32;
+        self.frames += 1;
+        true
+    }
+
+    /// Frames of a batch, 0 if the message is a single request or response.
+    pub fn frames(&self) -> usize {
+        self.frames as usize
+    }
+
+    /// Whether the message continues in the next region.
+    pub fn more(&self) -> bool {
+        self.more != 0
+    }
+
+    /// The requests or responses the message holds: its frames, or just its bytes.
+    pub fn payloads(&self) -> io::Result<Vec<Vec<u8>>> {
+        if self.frames == 0 {
+            return Ok(vec![self.bytes().to_vec()]);
+        }
+        split_frames(self.bytes(), self.frames())
+    }
+
+    /// Like [`Message::new`], with the offset of counts written by [`write_counts`].
+    pub fn with_counts(s: &str, counts: usize) -> Option<Self> {
+        let mut message = Self::new(s)?;
+        message.counts = counts_offset(counts);
+        Some(message)
+    }
+
+    /// Offset of the counts that go with the message, if any.
+    pub fn counts(&self) -> Option<usize> {
+        Some(self.counts as usize).filter(|&offset| offset != 0)
+    }
+
+    /// Session of the manager the message comes from or goes to.
+    pub fn session(&self) -> u32 {
+        self.session
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(MESSAGE_CAPACITY)]
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(self.bytes()).into_owned()
+    }
+}
+
+/// Creates a flag segment, or opens the one a previous calculator left behind.
+pub fn create_flag(id: &str) -> io::Result<Shmem> {
+    create_segment(id, 1)
+}
+
+/// Opens a flag segment, waiting for the calculator to create it.
+pub fn open_flag(id: &str) -> io::Result<Shmem> {
+    open_segment(id)
+}
+
+fn create_segment(id: &str, size: usize) -> io::Result<Shmem> {
+    match ShmemConf::new().size(size).os_id(id).create() {
+        Ok(m) => Ok(m),
+        Err(ShmemError::MappingIdExists) => {
+            let mut existing = ShmemConf::new()
+                .os_id(id)
+                .open()
+                .map_err(io::Error::other)?;
+            if existing.len() >= size {
+                return Ok(existing);
+            }
+            // Left behind by a build whose segment was smaller, replace it
+            existing.set_owner(true);
+            drop(existing);
+            ShmemConf::new()
+                .size(size)
+                .os_id(id)
+                .create()
+                .map_err(io::Error::other)
+        }
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+fn open_segment(id: &str) -> io::Result<Shmem> {
+    loop {
+        match ShmemConf::new().os_id(id).open() {
+            Ok(m) => return Ok(m),
+            Err(ShmemError::MapOpenFailed(_)) => std::thread::yield_now(),
+            Err(e) => return Err(io::Error::other(e)),
+        }
+    }
+}
+
+pub fn flag(shmem: &Shmem) -> &AtomicU8 {
+    // SAFETY: the mapping is at least one byte long and lives as long as `shmem`
+    unsafe { &*(shmem.as_ptr() as *const AtomicU8) }
+}
+
+/// Opens the shared memory object backing a region, creating it if needed.
+pub fn open_region_fd(name: &str) -> io::Result<RawFd> {
+    open_shm_fd(name, PAGE_SIZE)
+}
+
+/// Opens a shared memory object of `len` bytes, creating it if needed.
+pub(crate) fn open_shm_fd(name: &str, len: usize) -> io::Result<RawFd> {
+    let shm_name = CString::new(name).expect("CString::new failed");
+
+    // Create and open the shared memory object
+    let fd = unsafe {
+        shm_open(
+            shm_name.as_ptr(),
+            O_CREAT | O_RDWR,
+            S_IRUSR | S_IWUSR | S_IRGRP | S_IWGRP,
+        )
+    };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Resize the shared memory segment
+    let result = unsafe { ftruncate(fd, len as libc::off_t) };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(fd)
+}
+
+/// Maps the region named `name` under a protection key of its own.
+pub fn map_region(name: &str) -> io::Result<Arc<ProtectedRegion<Message>>> {
+    let pkey = ProtectionKeys::new(false).map_err(io::Error::other)?;
+    map_region_with(name, &pkey)
+}
+
+/// Maps a region tagged with `pkey` instead of a key of its own.
+pub fn map_region_with(
+    name: &str,
+    pkey: &Arc<ProtectionKeys>,
+) -> io::Result<Arc<ProtectedRegion<Message>>> {
+    let fd = open_region_fd(name)?;
+    pkey.make_region_fd(Message::empty(), fd)
+        .map_err(io::Error::other)
+}
+
+/// Maps the arena word counts are built in, under a protection key of its own.
+pub fn map_counts() -> io::Result<Arc<Arena>> {
+    let pkey = ProtectionKeys::new(false).map_err(io::Error::other)?;
+    // The arena keeps its header in a page of its own
+    let fd = open_shm_fd(SHMEM_COUNTS_FLINK, COUNTS_CAPACITY + PAGE_SIZE)?;
+    let arena = pkey.make_arena_fd(COUNTS_CAPACITY, fd);
+    // SAFETY: fd was opened above and the mapping doesn't need it anymore
+    unsafe { libc::close(fd) };
+    arena.map_err(io::Error::other)
+}
+
+/// Builds `counts` in the arena, replacing whatever it held, and returns their offset.
+///
+/// The manager reads the counts of a response before it sends the next
+/// request, so nothing still refers to the previous ones.
+pub fn write_counts(arena: &Arena, counts: &BTreeMap<String, usize>) -> Result<usize, SharedError> {
+    let guard = arena.lock();
+    // SAFETY: see above, the counts of the previous response have been read
+    unsafe { guard.reset() };
+    let mut map = SharedCounts::new();
+    for (word, &count) in counts {
+        map.insert(&guard, word, count as u64)?;
+    }
+    Ok(RelPtr::new(&guard, map)?.offset())
+}
+
+/// Sends the counts of a response in the arena, with just the envelope in the channel.
+///
+/// If they don't fit into the arena, the whole response goes as JSON through
+/// the channel instead, as every other result does; the manager reads either.
+pub fn send_counts(
+    responses: &mut Channel,
+    arena: &Arena,
+    counts: BTreeMap<String, usize>,
+    meta: &Metadata,
+) -> io::Result<()> {
+    match write_counts(arena, &counts) {
+        Ok(offset) => {
+            let envelope = Response::Ok {
+                result: Output::Counts(BTreeMap::new()),
+            };
+            responses.send_bytes(envelope.to_json_with(meta).as_bytes(), Some(offset))?;
+            log::debug!("Sent {} counts at offset {}", counts.len(), offset);
+            Ok(())
+        }
+        Err(e) => {
+            log::warn!("Sending counts as JSON, the arena can't hold them: {}", e);
+            let response = Response::Ok {
+                result: Output::Counts(counts),
+            };
+            responses.send_bytes(response.to_json_with(meta).as_bytes(), None)
+        }
+    }
+}
+
+/// Reads the counts at `offset` of the arena, checking every offset they hold.
+pub fn read_counts(arena: &Arena, offset: usize) -> Result<BTreeMap<String, usize>, SharedError> {
+    let guard = arena.lock();
+    let map = RelPtr::<SharedCounts>::from_offset(offset);
+    map.get(&guard)?
+        .iter(&guard)?
+        .map(|entry| entry.map(|(word, &count)| (word.to_string(), count as usize)))
+        .collect()
+}
+
+/// Waits for the peer to read the previous message, writes this one and raises the flag.
+pub fn send(
+    region: &ProtectedRegion<Message>,
+    message: Message,
+    mpkshmem: &Shmem,
+) -> io::Result<()> {
+    let mut spins = 0;
+    while flag(mpkshmem).load(Ordering::Acquire) == READY {
+        backoff(&mut spins);
+    }
+    region.modify(message).map_err(io::Error::other)?;
+    flag(mpkshmem).store(READY, Ordering::Release);
+    Ok(())
+}
+
+/// Like [`send`], failing with [`io::ErrorKind::WouldBlock`] instead of waiting.
+pub fn try_send(
+    region: &ProtectedRegion<Message>,
+    message: Message,
+    mpkshmem: &Shmem,
+) -> io::Result<()> {
+    if flag(mpkshmem).load(Ordering::Acquire) == READY {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "the previous message hasn't been read",
+        ));
+    }
+    send(region, message, mpkshmem)
+}
+
+/// Waits for the flag, reads the message and lowers the flag again.
+pub fn recv(region: &ProtectedRegion<Message>, mpkshmem: &Shmem) -> String {
+    String::from_utf8_lossy(&recv_bytes(region, mpkshmem)).into_owned()
+}
+
+/// Like [`recv`], without assuming the message is text.
+pub fn recv_bytes(region: &ProtectedRegion<Message>, mpkshmem: &Shmem) -> Vec<u8> {
+    recv_with_counts(region, mpkshmem).0
+}
+
+/// Like [`recv_bytes`], also returning the offset of the counts that go with the message.
+pub fn recv_with_counts(
+    region: &ProtectedRegion<Message>,
+    mpkshmem: &Shmem,
+) -> (Vec<u8>, Option<usize>) {
+    while flag(mpkshmem).load(Ordering::Acquire) != READY {
+        std::hint::spin_loop();
+    }
+    let (bytes, counts) = {
+        let message = region.lock();
+        (message.bytes().to_vec(), message.counts())
+    };
+    // The region can take the next message
+    flag(mpkshmem).store(NOT_READY, Ordering::Release);
+    (bytes, counts)
+}
+
+/// Start of a channel's credit segment.
+#[repr(C)]
+struct Credits {
+    /// Regions of the channel, 0 until the calculator has reset the counters.
+    capacity: AtomicU64,
+    /// Messages written, only advanced by the sender.
+    sent: AtomicU64,
+    _sent_line: [u64; 6],
+    /// Messages read, only advanced by the receiver.
+    received: AtomicU64,
+    /// Process holding the sending end, 0 if none does.
+    owner: AtomicU32,
+    /// Last session started by a claim.
+    session: AtomicU32,
+}
+
+/// Credit counters of a [`Channel`] as seen by one side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub capacity: usize,
+    pub sent: u64,
+    pub received: u64,
+    /// Sends of this process that found the channel full.
+    pub full: u64,
+}
+
+impl ChannelStats {
+    /// Messages sent but not read yet.
+    pub fn in_flight(&self) -> u64 {
+        self.sent - self.received
+    }
+
+    /// Messages that can be sent before the sender has to wait.
+    pub fn credits(&self) -> u64 {
+        (self.capacity as u64).saturating_sub(self.in_flight())
+    }
+}
+
+/// A bounded queue of messages in one direction, see the [module docs](self).
+///
+/// Region `i` of a channel is the shared memory object `<regions>.<i>`; all of
+/// them are tagged with the same protection key.
+pub struct Channel {
+    credits: Shmem,
+    regions: Vec<Arc<ProtectedRegion<Message>>>,
+    full: u64,
+    /// Session stamped on the messages sent and, unless 0, asked of those received.
+    session: u32,
+}
+
+/// A message read whole, its chunks joined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    pub session: u32,
+    /// Frames of a batch, 0 if the message is a single request or response.
+    pub frames: usize,
+    /// Offset of the counts that go with the message, if any.
+    pub counts: Option<usize>,
+    pub bytes: Vec<u8>,
+}
+
+impl Received {
+    /// The requests or responses the message holds: its frames, or just its bytes.
+    pub fn payloads(self) -> io::Result<Vec<Vec<u8>>> {
+        match self.frames {
+            0 => Ok(vec![self.bytes]),
+            frames => split_frames(&self.bytes, frames),
+        }
+    }
+}
+
+impl Channel {
+    /// Creates a channel of `capacity` regions, or takes over the one a previous
+    /// calculator left behind, dropping whatever it still held.
+    pub fn create(credits: &str, regions: &str, capacity: usize) -> io::Result<Self> {
+        if capacity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a channel needs at least one region",
+            ));
+        }
+        let credits = create_segment(credits, std::mem::size_of::<Credits>())?;
+        check_credits(&credits)?;
+
+        // Managers wait for the capacity, so they don't see the counters until they're reset
+        let counters = counters(&credits);
+        counters.capacity.store(0, Ordering::Release);
+        counters.sent.store(0, Ordering::Relaxed);
+        counters.received.store(0, Ordering::Relaxed);
+        // Sessions keep counting up, so those of earlier calculators stay stale
+        counters.owner.store(0, Ordering::Relaxed);
+        let regions = map_channel_regions(regions, capacity)?;
+        counters.capacity.store(capacity as u64, Ordering::Release);
+
+        Ok(Self {
+            credits,
+            regions,
+            full: 0,
+            session: 0,
+        })
+    }
+
+    /// Opens the channel a calculator creates, waiting for it.
+    pub fn open(credits: &str, regions: &str) -> io::Result<Self> {
+        let credits = open_segment(credits)?;
+        check_credits(&credits)?;
+
+        let mut spins = 0;
+        let capacity = loop {
+            match counters(&credits).capacity.load(Ordering::Acquire) {
+                0 => backoff(&mut spins),
+                capacity => break capacity as usize,
+            }
+        };
+        let regions = map_channel_regions(regions, capacity)?;
+
+        Ok(Self {
+            credits,
+            regions,
+            full: 0,
+            session: 0,
+        })
+    }
+
+    /// Claims the sending end for this process and starts a new session.
+    ///
+    /// Fails with [`io::ErrorKind::AddrInUse`] while a live process, this one
+    /// included, holds it: the channel has a single sender. The end of one that
+    /// died is taken over.
+    pub fn claim(&mut self) -> io::Result<u32> {
+        let counters = self.counters();
+        let pid = std::process::id();
+        let mut owner = counters.owner.load(Ordering::Acquire);
+        loop {
+            if owner != 0 && is_alive(owner) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("another manager (pid {}) is connected", owner),
+                ));
+            }
+            match counters
+                .owner
+                .compare_exchange(owner, pid, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(current) => owner = current,
+            }
+        }
+        // 0 stands for no session
+        let mut session = 0;
+        while session == 0 {
+            session = counters
+                .session
+                .fetch_add(1, Ordering::AcqRel)
+                .wrapping_add(1);
+        }
+        self.session = session;
+        Ok(session)
+    }
+
+    /// Gives up the sending end if this process claimed it.
+    pub fn release(&self) {
+        let pid = std::process::id();
+        let _ = self
+            .counters()
+            .owner
+            .compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed);
+    }
+
+    /// Session of the last claim, the one a calculator should answer.
+    pub fn claimed_session(&self) -> u32 {
+        self.counters().session.load(Ordering::Acquire)
+    }
+
+    /// Stamps `session` on every message sent from now on and, unless it is 0,
+    /// drops received messages of other sessions.
+    pub fn set_session(&mut self, session: u32) {
+        self.session = session;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.regions.len()
+    }
+
+    fn counters(&self) -> &Credits {
+        counters(&self.credits)
+    }
+
+    fn has_credit(&self) -> bool {
+        let counters = self.counters();
+        let in_flight =
+            counters.sent.load(Ordering::Relaxed) - counters.received.load(Ordering::Acquire);
+        in_flight < self.capacity() as u64
+    }
+
+    /// Writes a message into the next free region, waiting for one while the channel is full.
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        if !self.has_credit() {
+            self.full += 1;
+            let mut spins = 0;
+            while !self.has_credit() {
+                backoff(&mut spins);
+            }
+        }
+        self.write(message)
+    }
+
+    /// Like [`Channel::send`], failing with [`io::ErrorKind::WouldBlock`] while the channel is full.
+    pub fn try_send(&mut self, message: Message) -> io::Result<()> {
+        if !self.has_credit() {
+            self.full += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("all {} regions hold unread messages", self.capacity()),
+            ));
+        }
+        self.write(message)
+    }
+
+    /// Sends `bytes` as one message, split over as many regions as it takes,
+    /// waiting for each of them to be free.
+    pub fn send_bytes(&mut self, bytes: &[u8], counts: Option<usize>) -> io::Result<()> {
+        for chunk in chunks(bytes, 0, counts) {
+            self.send(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Sends `payload` as a batch of its own, for a frame too long to share a region.
+    pub fn send_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        for chunk in chunks(&framed(payload), 1, None) {
+            self.send(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Channel::send_bytes`], failing with [`io::ErrorKind::WouldBlock`] unless
+    /// the channel has a free region for every chunk.
+    pub fn try_send_bytes(&mut self, bytes: &[u8], counts: Option<usize>) -> io::Result<()> {
+        let needed = chunk_count(bytes.len());
+        if needed > self.capacity() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message takes {} regions, the channel has {}",
+                    needed,
+                    self.capacity()
+                ),
+            ));
+        }
+        // Only we send, so the credits can't shrink before we use them
+        if self.stats().credits() < needed as u64 {
+            self.full += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} regions hold unread messages", self.stats().in_flight()),
+            ));
+        }
+        self.send_bytes(bytes, counts)
+    }
+
+    fn write(&self, mut message: Message) -> io::Result<()> {
+        message.session = self.session;
+        let counters = self.counters();
+        let sent = counters.sent.load(Ordering::Relaxed);
+        self.regions[(sent % self.capacity() as u64) as usize]
+            .modify(message)
+            .map_err(io::Error::other)?;
+        counters.sent.store(sent + 1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Waits for the next message and reads all of its chunks.
+    ///
+    /// Messages of other sessions, see [`Channel::set_session`], are dropped,
+    /// as is one cut short by a message of another session.
+    pub fn recv_message(&self) -> Received {
+        let mut spins = 0;
+        loop {
+            if let Some(message) = self.try_recv_message() {
+                return message;
+            }
+            backoff(&mut spins);
+        }
+    }
+
+    /// Like [`Channel::recv_message`], returning `None` if no message has started to arrive.
+    ///
+    /// Once one has, the rest of its chunks are waited for.
+    pub fn try_recv_message(&self) -> Option<Received> {
+        loop {
+            let (mut message, more) = self.try_recv_with(|message| {
+                let received = Received {
+                    session: message.session,
+                    frames: message.frames(),
+                    counts: message.counts(),
+                    bytes: message.bytes().to_vec(),
+                };
+                (received, message.more())
+            })?;
+            if self.session != 0 && message.session != self.session {
+                continue;
+            }
+            if !more || self.recv_rest(&mut message) {
+                return Some(message);
+            }
+        }
+    }
+
+    /// Waits for the remaining chunks of `message`, returning `false` if a message of
+    /// another session comes first.
+    fn recv_rest(&self, message: &mut Received) -> bool {
+        let mut spins = 0;
+        loop {
+            if self.is_empty() {
+                backoff(&mut spins);
+                continue;
+            }
+            let more = self.try_take(|chunk| {
+                if chunk.session != message.session {
+                    return None;
+                }
+                message.bytes.extend_from_slice(chunk.bytes());
+                Some(chunk.more())
+            });
+            match more {
+                Some(true) => spins = 0,
+                Some(false) => return true,
+                None => return false,
+            }
+        }
+    }
+
+    /// Drops the regions of other sessions at the front of the channel, returning how many
+    /// there were.
+    pub fn skip_stale(&self) -> usize {
+        let mut skipped = 0;
+        while self
+            .try_take(|message| {
+                (self.session != 0 && message.session != self.session).then_some(())
+            })
+            .is_some()
+        {
+            skipped += 1;
+        }
+        skipped
+    }
+
+    /// Waits for the next message and reads all of its chunks, along with the offset of its
+    /// counts.
+    pub fn recv(&self) -> (Vec<u8>, Option<usize>) {
+        let message = self.recv_message();
+        (message.bytes, message.counts)
+    }
+
+    /// Like [`Channel::recv`], returning `None` if no message has started to arrive.
+    pub fn try_recv(&self) -> Option<(Vec<u8>, Option<usize>)> {
+        let message = self.try_recv_message()?;
+        Some((message.bytes, message.counts))
+    }
+
+    /// Like [`Channel::recv`], returning the frames of a batch as payloads of their own.
+    pub fn recv_payloads(&self) -> io::Result<Vec<Vec<u8>>> {
+        self.recv_message().payloads()
+    }
+
+    /// Like [`Channel::recv_payloads`], returning `None` if no message has started to arrive.
+    pub fn try_recv_payloads(&self) -> Option<io::Result<Vec<Vec<u8>>>> {
+        Some(self.try_recv_message()?.payloads())
+    }
+
+    /// Waits for the next region and hands it to `read` under a single guard.
+    pub fn recv_with<R>(&self, mut read: impl FnMut(&Message) -> R) -> R {
+        let mut spins = 0;
+        loop {
+            if let Some(result) = self.try_recv_with(&mut read) {
+                return result;
+            }
+            backoff(&mut spins);
+        }
+    }
+
+    /// Like [`Channel::recv_with`], returning `None` if there is no region to read yet.
+    pub fn try_recv_with<R>(&self, read: impl FnOnce(&Message) -> R) -> Option<R> {
+        self.try_take(|message| Some(read(message)))
+    }
+
+    fn is_empty(&self) -> bool {
+        let counters = self.counters();
+        counters.sent.load(Ordering::Acquire) == counters.received.load(Ordering::Relaxed)
+    }
+
+    /// Hands the next region to `read`, leaving it unread if `read` returns `None`.
+    fn try_take<R>(&self, read: impl FnOnce(&Message) -> Option<R>) -> Option<R> {
+        let counters = self.counters();
+        let received = counters.received.load(Ordering::Relaxed);
+        if counters.sent.load(Ordering::Acquire) == received {
+            return None;
+        }
+        let result = read(&self.regions[(received % self.capacity() as u64) as usize].lock())?;
+        // The region can take the next message
+        counters.received.store(received + 1, Ordering::Release);
+        Some(result)
+    }
+
+    /// Drops every message that hasn't been read yet, returning the credits to the sender.
+    pub fn discard(&self) {
+        let counters = self.counters();
+        let sent = counters.sent.load(Ordering::Acquire);
+        counters.received.store(sent, Ordering::Release);
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        let counters = self.counters();
+        // Reading the receiver's counter first keeps it from overtaking the sender's
+        let received = counters.received.load(Ordering::Acquire);
+        let sent = counters.sent.load(Ordering::Acquire);
+        ChannelStats {
+            capacity: self.capacity(),
+            sent,
+            received,
+            full: self.full,
+        }
+    }
+}
+
+/// Regions a message of `len` bytes takes.
+fn chunk_count(len: usize) -> usize {
+    len.div_ceil(MESSAGE_CAPACITY).max(1)
+}
+
+/// Splits `bytes` into the regions of one message of `frames` frames.
+fn chunks(bytes: &[u8], frames: u16, counts: Option<usize>) -> impl Iterator<Item = Message> + '_ {
+    let count = chunk_count(bytes.len());
+    (0..count).map(move |index| {
+        let end = ((index + 1) * MESSAGE_CAPACITY).min(bytes.len());
+        let mut chunk = Message::from_bytes(&bytes[index * MESSAGE_CAPACITY..end])
+            .expect("chunk fits into a region");
+        chunk.frames = frames;
+        chunk.more = (index + 1 < count) as u16;
+        chunk.counts = counts.map_or(0, counts_offset);
+        chunk
+    })
+}
+
+fn counts_offset(offset: usize) -> u32 {
+    debug_assert!(offset < COUNTS_CAPACITY, "counts lie outside the arena");
+    offset as u32
+}
+
+/// `payload` as the single frame of a batch.
+fn framed(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(frame::LEN_SIZE + payload.len());
+    write_frame(&mut bytes, payload).expect("writing to a Vec can't fail");
+    bytes
+}
+
+/// Splits the bytes of a batch into the payloads of its `frames` frames.
+pub fn split_frames(mut bytes: &[u8], frames: usize) -> io::Result<Vec<Vec<u8>>> {
+    let max_len = bytes.len();
+    (0..frames)
+        .map(|_| {
+            read_frame(&mut bytes, max_len)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+        })
+        .collect()
+}
+
+fn counters(credits: &Shmem) -> &Credits {
+    // SAFETY: `check_credits` made sure the page aligned mapping holds the counters,
+    // and it lives as long as `credits`
+    unsafe { &*(credits.as_ptr() as *const Credits) }
+}
+
+fn check_credits(credits: &Shmem) -> io::Result<()> {
+    if credits.len() < std::mem::size_of::<Credits>() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "credit segment is truncated",
+        ));
+    }
+    Ok(())
+}
+
+fn map_channel_regions(
+    name: &str,
+    capacity: usize,
+) -> io::Result<Vec<Arc<ProtectedRegion<Message>>>> {
+    let pkey = ProtectionKeys::new(false).map_err(io::Error::other)?;
+    (0..capacity)
+        .map(|index| map_region_with(&format!("{}.{}", name, index), &pkey))
+        .collect()
+}
+
+/// When [`Client::queue`] rings the doorbell for the requests it collected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+    /// Requests per batch at most; a batch also ends when the next one doesn't fit into its region.
+    pub max_requests: usize,
+    /// How long the first request of a batch waits for others before the batch is sent anyway.
+    pub linger: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: 64,
+            linger: Duration::from_micros(50),
+        }
+    }
+}
+
+/// Credit counters of both of a manager's channels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub requests: ChannelStats,
+    pub responses: ChannelStats,
+}
+
+/// The manager's side of the channels.
+pub struct Client {
+    requests: Channel,
+    responses: Channel,
+    counts: Arc<Arena>,
+    /// Offset of the counts that came with the last response.
+    received_counts: Option<usize>,
+    batching: BatchConfig,
+    /// Requests queued since the last doorbell, and when the first of them was.
+    batch: Message,
+    opened: Option<Instant>,
+    /// Queued requests the calculator hasn't answered yet.
+    awaiting: usize,
+    /// Responses to queued requests that haven't been taken yet.
+    answered: VecDeque<Vec<u8>>,
+}
+
+impl Client {
+    /// Connects as the calculator's only manager, failing with
+    /// [`io::ErrorKind::AddrInUse`] while another one is connected.
+    pub fn connect() -> io::Result<Self> {
+        // Mapping a region writes its initial value, which is harmless while the calculator
+        // waits for a request: no region holds anything it still needs.
+        let mut requests = Channel::open(SHMEM_REQUEST_CREDITS_FLINK, SHMEM_REQUEST_FLINK)?;
+        let mut responses = Channel::open(SHMEM_RESPONSE_CREDITS_FLINK, SHMEM_RESPONSE_FLINK)?;
+        let counts = map_counts()?;
+
+        // Whatever a manager that died mid-request left in either channel belongs to its
+        // session: the calculator drops its requests and we drop its responses
+        let session = requests.claim()?;
+        responses.set_session(session);
+        responses.discard();
+
+        Ok(Self {
+            requests,
+            responses,
+            counts,
+            received_counts: None,
+            batching: BatchConfig::default(),
+            batch: Message::empty(),
+            opened: None,
+            awaiting: 0,
+            answered: VecDeque::new(),
+        })
+    }
+
+    /// Writes a serialized request into the request regions, waiting for them to be free.
+    pub fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        // The calculator builds the next counts over the last ones
+        self.received_counts = None;
+        for chunk in chunks(request, 0, None) {
+            // It may still be answering a manager that died, and take no requests until
+            // those responses are out of its way
+            let mut spins = 0;
+            while self.requests.stats().credits() == 0 {
+                self.responses.skip_stale();
+                backoff(&mut spins);
+            }
+            self.requests.send(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Client::send`], failing with [`io::ErrorKind::WouldBlock`] while the calculator
+    /// hasn't taken the requests that fill the channel.
+    ///
+    /// Requests may be sent ahead of their responses this way, except for word counts:
+    /// every counts response replaces those of the one before.
+    pub fn try_send(&mut self, request: &[u8]) -> io::Result<()> {
+        self.responses.skip_stale();
+        self.requests.try_send_bytes(request, None)?;
+        self.received_counts = None;
+        Ok(())
+    }
+
+    /// Waits for the next response and reads it.
+    ///
+    /// Counts that came with the response are read by [`Client::counts`].
+    pub fn recv(&mut self) -> Vec<u8> {
+        let (bytes, counts) = self.responses.recv();
+        self.received_counts = counts;
+        bytes
+    }
+
+    /// Reads the counts that came with the last response, if any.
+    ///
+    /// They are only valid until the next request is sent.
+    pub fn counts(&mut self) -> io::Result<Option<BTreeMap<String, usize>>> {
+        match self.received_counts.take() {
+            Some(offset) => read_counts(&self.counts, offset)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_batching(&mut self, config: BatchConfig) {
+        self.batching = config;
+    }
+
+    /// Adds a request to the current batch, which is sent once it's full or has
+    /// lingered as long as the [`BatchConfig`] allows.
+    ///
+    /// Responses are taken in the same order by [`Client::recv_queued`]; they
+    /// carry word counts as JSON. A request longer than [`FRAME_CAPACITY`] goes
+    /// as a batch of its own, in chunks, right after the batch before it.
+    /// Queued requests and [`Client::send`] don't mix until every queued
+    /// request is answered.
+    pub fn queue(&mut self, request: &[u8]) -> io::Result<()> {
+        if request.len() > FRAME_CAPACITY {
+            self.flush()?;
+            for chunk in chunks(&framed(request), 1, None) {
+                self.wait_for_credit()?;
+                self.requests.send(chunk)?;
+            }
+            self.awaiting += 1;
+            self.received_counts = None;
+            return Ok(());
+        }
+        if !self.batch.push_frame(request) {
+            self.flush()?;
+            let pushed = self.batch.push_frame(request);
+            debug_assert!(pushed);
+        }
+        self.opened.get_or_insert_with(Instant::now);
+
+        if self.batch.frames() >= self.batching.max_requests {
+            self.flush()
+        } else {
+            self.poll().map(drop)
+        }
+    }
+
+    /// Sends the current batch if it has lingered long enough and a region is free,
+    /// returning whether it did.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        match self.opened {
+            Some(opened) if opened.elapsed() >= self.batching.linger => self.try_flush(),
+            _ => Ok(false),
+        }
+    }
+
+    /// Sends the current batch now, waiting for a free region.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.try_flush()? {
+            return Ok(());
+        }
+        self.wait_for_credit()?;
+        self.requests.send(self.batch)?;
+        self.sent_batch();
+        Ok(())
+    }
+
+    fn wait_for_credit(&mut self) -> io::Result<()> {
+        // The calculator may be waiting for us to take responses before it takes requests
+        let mut spins = 0;
+        while self.requests.stats().credits() == 0 {
+            self.collect()?;
+            backoff(&mut spins);
+        }
+        Ok(())
+    }
+
+    fn try_flush(&mut self) -> io::Result<bool> {
+        if self.batch.frames() == 0 {
+            return Ok(true);
+        }
+        match self.requests.try_send(self.batch) {
+            Ok(()) => {
+                self.sent_batch();
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn sent_batch(&mut self) {
+        self.awaiting += self.batch.frames();
+        self.batch = Message::empty();
+        self.opened = None;
+        // The calculator builds the next counts over the last ones
+        self.received_counts = None;
+    }
+
+    /// Waits for the response to the oldest queued request, sending its batch if it's still here.
+    pub fn recv_queued(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(response) = self.answered.pop_front() {
+                return Ok(response);
+            }
+            if self.awaiting == 0 {
+                if self.batch.frames() == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "no request is queued",
+                    ));
+                }
+                self.flush()?;
+            }
+            let responses = self.responses.recv_payloads()?;
+            self.take_responses(responses);
+        }
+    }
+
+    /// Takes every response that already arrived.
+    fn collect(&mut self) -> io::Result<()> {
+        while let Some(responses) = self.responses.try_recv_payloads() {
+            self.take_responses(responses?);
+        }
+        Ok(())
+    }
+
+    fn take_responses(&mut self, responses: Vec<Vec<u8>>) {
+        self.awaiting = self.awaiting.saturating_sub(responses.len());
+        self.answered.extend(responses);
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            requests: self.requests.stats(),
+            responses: self.responses.stats(),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.requests.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unlink(name: &str) {
+        let name = CString::new(name).unwrap();
+        unsafe { libc::shm_unlink(name.as_ptr()) };
+    }
+
+    #[test]
+    fn test_channel_credits() {
+        let credits = format!("/mpklink-test-credits-{}", std::process::id());
+        let regions = format!("/mpklink-test-channel-{}", std::process::id());
+        let mut sender = Channel::create(&credits, &regions, 2).unwrap();
+        let receiver = Channel::open(&credits, &regions).unwrap();
+        assert_eq!(receiver.capacity(), 2);
+        assert!(receiver.try_recv().is_none());
+
+        sender.try_send(Message::new("one").unwrap()).unwrap();
+        sender.send(Message::new("two").unwrap()).unwrap();
+        let error = sender.try_send(Message::new("three").unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        let stats = sender.stats();
+        assert_eq!((stats.in_flight(), stats.credits(), stats.full), (2, 0, 1));
+
+        assert_eq!(receiver.recv().0, b"one");
+        assert_eq!(receiver.stats().credits(), 1);
+        sender.try_send(Message::new("three").unwrap()).unwrap();
+        assert_eq!(receiver.recv().0, b"two");
+        assert_eq!(receiver.recv().0, b"three");
+        assert_eq!(
+            receiver.stats(),
+            ChannelStats {
+                capacity: 2,
+                sent: 3,
+                received: 3,
+                full: 0,
+            }
+        );
+
+        let mut batch = Message::empty();
+        assert!(batch.push_frame(b"first"));
+        assert!(batch.push_frame(b""));
+        assert!(!batch.push_frame(&[0; FRAME_CAPACITY]));
+        sender.send(batch).unwrap();
+        let payloads = receiver.recv_payloads().unwrap();
+        assert_eq!(payloads, [&b"first"[..], b""]);
+        assert_eq!(receiver.stats().received, 4);
+
+        sender.send(Message::new("dropped").unwrap()).unwrap();
+        receiver.discard();
+        assert!(receiver.try_recv().is_none());
+
+        unlink(&credits);
+        for index in 0..2 {
+            unlink(&format!("{}.{}", regions, index));
+        }
+    }
+
+    #[test]
+    fn test_chunked_messages() {
+        let credits = format!("/mpklink-test-chunk-credits-{}", std::process::id());
+        let regions = format!("/mpklink-test-chunks-{}", std::process::id());
+        let mut sender = Channel::create(&credits, &regions, 2).unwrap();
+        let receiver = Channel::open(&credits, &regions).unwrap();
+
+        let long = (0..5 * MESSAGE_CAPACITY + 7)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let error = sender.try_send_bytes(&long, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        sender
+            .try_send_bytes(&long[..2 * MESSAGE_CAPACITY], None)
+            .unwrap();
+        assert_eq!(
+            receiver.recv(),
+            (long[..2 * MESSAGE_CAPACITY].to_vec(), None)
+        );
+
+        // Channels stay in the thread that mapped them, which writes every region
+        let names = (credits.clone(), regions.clone());
+        let mapped = Arc::new(std::sync::Barrier::new(2));
+        let reader = std::thread::spawn({
+            let mapped = mapped.clone();
+            move || {
+                let receiver = Channel::open(&names.0, &names.1).unwrap();
+                mapped.wait();
+                (receiver.recv(), receiver.recv_payloads().unwrap())
+            }
+        });
+        mapped.wait();
+        sender.send_bytes(&long, Some(8)).unwrap();
+        // A frame too long for a batch goes as a batch of its own
+        sender.send_frame(&long).unwrap();
+        let (received, payloads) = reader.join().unwrap();
+        assert_eq!(received, (long.clone(), Some(8)));
+        assert_eq!(payloads, vec![long]);
+        assert_eq!(sender.stats().sent, 2 + 6 + 6);
+
+        unlink(&credits);
+        for index in 0..2 {
+            unlink(&format!("{}.{}", regions, index));
+        }
+    }
+
+    #[test]
+    fn test_counts_fall_back_to_json() {
+        let credits = format!("/mpklink-test-counts-credits-{}", std::process::id());
+        let regions = format!("/mpklink-test-counts-{}", std::process::id());
+        let mut sender = Channel::create(&credits, &regions, 4).unwrap();
+        let receiver = Channel::open(&credits, &regions).unwrap();
+        let pkey = ProtectionKeys::new(false).unwrap();
+        let arena = pkey.make_arena(PAGE_SIZE).unwrap();
+        let meta = Metadata { compute_ns: 1 };
+
+        let few = BTreeMap::from([("word".to_string(), 2)]);
+        send_counts(&mut sender, &arena, few.clone(), &meta).unwrap();
+        let (envelope, offset) = receiver.recv();
+        assert!(String::from_utf8(envelope)
+            .unwrap()
+            .contains(r#""result":{}"#));
+        assert_eq!(read_counts(&arena, offset.unwrap()).unwrap(), few);
+
+        // More than a page of counts fills the arena, so they go in the response
+        let many = (0..300)
+            .map(|i| (format!("word{}", i), i))
+            .collect::<BTreeMap<_, _>>();
+        assert!(write_counts(&arena, &many).is_err());
+        send_counts(&mut sender, &arena, many.clone(), &meta).unwrap();
+        let (json, offset) = receiver.recv();
+        assert_eq!(offset, None);
+        let response = Response::parse_for(
+            &wordcount::RequestKind::Counts,
+            &String::from_utf8(json).unwrap(),
+        );
+        assert_eq!(
+            response,
+            Response::Ok {
+                result: Output::Counts(many)
+            }
+        );
+
+        unlink(&credits);
+        for index in 0..4 {
+            unlink(&format!("{}.{}", regions, index));
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let credits = format!("/mpklink-test-session-credits-{}", std::process::id());
+        let regions = format!("/mpklink-test-sessions-{}", std::process::id());
+        let mut first = Channel::create(&credits, &regions, 4).unwrap();
+        let mut second = Channel::open(&credits, &regions).unwrap();
+        let mut receiver = Channel::open(&credits, &regions).unwrap();
+
+        let session = first.claim().unwrap();
+        let error = second.claim().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        first.send_bytes(b"stale", None).unwrap();
+        // A message cut short by the next session is dropped along with it
+        first
+            .send(Message {
+                more: 1,
+                ..Message::new("cut").unwrap()
+            })
+            .unwrap();
+        first.release();
+
+        let next = second.claim().unwrap();
+        assert!(next > session);
+        assert_eq!(receiver.claimed_session(), next);
+        second.send_bytes(b"fresh", None).unwrap();
+        second.send_bytes(b"also fresh", None).unwrap();
+        assert_eq!(receiver.recv_message().session, session);
+        assert_eq!(
+            receiver.recv_message(),
+            Received {
+                session: next,
+                frames: 0,
+                counts: None,
+                bytes: b"fresh".to_vec(),
+            }
+        );
+
+        // Yet another session only takes its own messages
+        first.send_bytes(b"other", None).unwrap();
+        second.send_bytes(b"last", None).unwrap();
+        receiver.set_session(next);
+        assert_eq!(receiver.recv(), (b"also fresh".to_vec(), None));
+        assert_eq!(receiver.recv(), (b"last".to_vec(), None));
+
+        unlink(&credits);
+        for index in 0..4 {
+            unlink(&format!("{}.{}", regions, index));
+        }
+    }
+
+    #[test]
+    fn test_full_batches() {
+        let mut batch = Message::empty();
+        assert!(batch.push_frame(&[1; FRAME_CAPACITY]));
+        assert!(!batch.push_frame(b""));
+        assert_eq!(batch.frames(), 1);
+        assert_eq!(batch.payloads().unwrap(), [vec![1; FRAME_CAPACITY]]);
+
+        let plain = Message::new("{}").unwrap();
+        assert_eq!(plain.frames(), 0);
+        assert_eq!(plain.payloads().unwrap(), [b"{}"]);
+    }
+}
@@ -0,0 +1,148 @@
+//! A persistent pair of FIFOs carrying length-prefixed frames.
+//!
+//! The calculator accepts one manager at a time: it opens the request FIFO for
+//! reading and the response FIFO for writing, then answers frames until the
+//! manager closes its end, after which it waits for the next one. Managers
+//! take [`PIPE_LOCK`] for as long as they are connected, so frames of two
+//! managers never interleave on the shared FIFOs.
+//!
+//! Both sides open the request FIFO first, which keeps the blocking FIFO
+//! opens from deadlocking.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::os::fd::AsRawFd;
+
+use crate::frame::{read_frame, write_frame};
+
+pub const PIPE_REQUEST: &str = "/tmp/request-pipe-request";
+pub const PIPE_RESPONSE: &str = "/tmp/request-pipe-response";
+pub const PIPE_LOCK: &str = "/tmp/request-pipe.lock";
+
+/// Creates both FIFOs unless they already exist.
+pub fn setup_pipes() -> io::Result<()> {
+    create_pipe(PIPE_REQUEST)?; // Pipe for sending requests
+    create_pipe(PIPE_RESPONSE)?; // Pipe for receiving responses
+    Ok(())
+}
+
+/// Creates a FIFO at `pipe_path` unless it already exists.
+pub fn create_pipe(pipe_path: &str) -> io::Result<()> {
+    let path = CString::new(pipe_path).expect("CString::new failed");
+    // SAFETY: `path` is a valid C string
+    let res = unsafe { libc::mkfifo(path.as_ptr(), 0o660) };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        // The pipe already exists; proceed as normal
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// One end of a connection over the FIFO pair.
+pub struct Connection {
+    reader: BufReader<File>,
+    writer: BufWriter<Counted>,
+    /// Held by managers for as long as they are connected.
+    _lock: Option<File>,
+}
+
+impl Connection {
+    /// Waits for the next manager to connect.
+    pub fn accept() -> io::Result<Self> {
+        let reader = OpenOptions::new().read(true).open(PIPE_REQUEST)?;
+        let writer = OpenOptions::new().write(true).open(PIPE_RESPONSE)?;
+        Ok(Self::new(reader, writer, None))
+    }
+
+    /// Connects to the calculator, waiting for other managers to disconnect first.
+    pub fn connect() -> io::Result<Self> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(PIPE_LOCK)?;
+        // SAFETY: `lock` is an open file; the lock is released when it is closed
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let writer = OpenOptions::new().write(true).open(PIPE_REQUEST)?;
+        let reader = OpenOptions::new().read(true).open(PIPE_RESPONSE)?;
+        Ok(Self::new(reader, writer, Some(lock)))
+    }
+
+    fn new(reader: File, writer: File, lock: Option<File>) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(Counted {
+                file: writer,
+                written: 0,
+            }),
+            _lock: lock,
+        }
+    }
+
+    /// Sends `payload` as one frame.
+    ///
+    /// If the peer closed its end before any of the frame went out, this fails
+    /// with [`io::ErrorKind::NotConnected`]: the peer can't have seen the frame,
+    /// so it may be sent again over a new connection.
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let written = self.writer.get_ref().written;
+        match write_frame(&mut self.writer, payload).and_then(|()| self.writer.flush()) {
+            Err(e)
+                if e.kind() == io::ErrorKind::BrokenPipe
+                    && self.writer.get_ref().written == written =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "peer closed its end before the frame was sent",
+                ))
+            }
+            result => result,
+        }
+    }
+
+    /// Reads the next frame, or `None` once the peer closed its end.
+    pub fn recv(&mut self, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+        read_frame(&mut self.reader, max_len)
+    }
+
+    /// Replaces a connection whose calculator went away, e.g. because it restarted.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        // Let go of the lock so the new connection can take it
+        self._lock = None;
+        *self = Self::connect()?;
+        Ok(())
+    }
+}
+
+/// Whether `err` means the peer closed its end of the FIFOs.
+pub fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// The write end of a FIFO, counting the bytes that made it into the FIFO.
+struct Counted {
+    file: File,
+    written: u64,
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
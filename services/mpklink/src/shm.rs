@@ -0,0 +1,877 @@
+//! Shared-memory slot table that lets several managers talk to one calculator.
+//!
+//! The calculator creates a single control segment holding a table of slots.
+//! A manager claims a free slot by swapping its pid into the slot's owner field,
+//! exchanges messages through it and marks it released when done; the calculator
+//! then empties the slot and frees it. Slots whose owner died without releasing
+//! them are freed the same way.
+//!
+//! Every slot has a request and a response ring of [`CELL_COUNT`] cells of
+//! [`CELL_SIZE`] bytes. Messages of any length are split across consecutive
+//! cells, the last one marked as the end of the message, so the segment's size
+//! doesn't depend on the size of the inputs. A request is a header message
+//! followed by a body message that the calculator consumes cell by cell
+//! through a [`RequestStream`].
+//!
+//! Layout of the control segment:
+//!
+//! ```text
+//! 0                      header: magic, slot count, cell count, cell size, calculator pid
+//! 64 + i * 64            slot i: owner pid, released flag
+//! 4096 + i * 2 * ring    slot i: request ring, followed by its response ring
+//! ```
+//!
+//! Each ring starts with its head (cells written) and tail (cells read)
+//! counters on separate cache lines, followed by the cells. The difference is
+//! what the ring holds, so a sender runs out of credits after [`CELL_COUNT`]
+//! unread cells and waits, or fails with [`io::ErrorKind::WouldBlock`] through
+//! [`ClientSlot::try_send`]; [`SlotTable::stats`] shows both rings' counters.
+//! A manager that waits on a calculator which has exited fails with
+//! [`io::ErrorKind::ConnectionAborted`] instead.
+
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use pkey_mprotect::{is_alive, SPINS_BEFORE_YIELD};
+use shared_memory::{Shmem, ShmemConf, ShmemError};
+
+pub const CONTROL_FLINK: &str = "/tmp/control.shm";
+
+/// Number of managers that can be connected at the same time.
+pub const SLOT_COUNT: usize = 8;
+/// Number of cells in each request and each response ring.
+pub const CELL_COUNT: usize = 16;
+/// Payload bytes of a cell.
+pub const CELL_SIZE: usize = 64 << 10;
+
+const MAGIC: u64 = u64::from_ne_bytes(*b"MPKSLOT3");
+const SLOT_HEADER_SIZE: usize = 64;
+const RING_HEADER_SIZE: usize = 128;
+const CELL_HEADER_SIZE: usize = std::mem::size_of::<CellHeader>();
+const BUFFERS_OFFSET: usize = 4096;
+
+#[repr(C)]
+struct TableHeader {
+    magic: AtomicU64,
+    slot_count: AtomicU64,
+    cell_count: AtomicU64,
+    cell_size: AtomicU64,
+    /// Pid of the calculator that created the segment.
+    server: AtomicU32,
+}
+
+#[repr(C)]
+struct SlotHeader {
+    /// Pid of the manager holding the slot, `0` if free.
+    owner: AtomicU32,
+    /// Set by the owner when it is done with the slot.
+    released: AtomicU32,
+}
+
+#[repr(C)]
+struct RingHeader {
+    head: AtomicU64,
+    _head_line: [u64; 7],
+    tail: AtomicU64,
+}
+
+#[repr(C)]
+struct CellHeader {
+    len: u32,
+    end: u32,
+}
+
+/// A mapped control segment.
+pub struct SlotTable {
+    shmem: Shmem,
+    slot_count: usize,
+    cell_count: usize,
+    cell_size: usize,
+}
+
+impl SlotTable {
+    /// Creates the control segment at `flink`, replacing one left behind by a killed calculator.
+    pub fn create(flink: &str) -> io::Result<Self> {
+        Self::create_with(flink, SLOT_COUNT, CELL_COUNT, CELL_SIZE)
+    }
+
+    pub fn create_with(
+        flink: &str,
+        slot_count: usize,
+        cell_count: usize,
+        cell_size: usize,
+    ) -> io::Result<Self> {
+        if BUFFERS_OFFSET < SLOT_HEADER_SIZE * (slot_count + 1) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many slots",
+            ));
+        }
+        // Cell headers have to stay aligned
+        if cell_count == 0
+            || cell_size == 0
+            || !cell_size.is_multiple_of(8)
+            || cell_size > u32::MAX as usize
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cells must be a non-zero multiple of 8 bytes",
+            ));
+        }
+
+        // A link left behind by a killed calculator points to a mapping nobody serves anymore
+        if Path::new(flink).exists() {
+            if let Ok(mut stale) = ShmemConf::new().flink(flink).open() {
+                if stale.len() >= std::mem::size_of::<TableHeader>() {
+                    // SAFETY: the header is at the start of a page aligned mapping
+                    let header = unsafe { &*(stale.as_ptr() as *const TableHeader) };
+                    let server = header.server.load(Ordering::Acquire);
+                    if server != 0 && is_alive(server) {
+                        return Err(owned_elsewhere());
+                    }
+                }
+                // Removes the mapping along with its link
+                stale.set_owner(true);
+            }
+            match std::fs::remove_file(flink) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        let size = segment_size(slot_count, cell_count, cell_size);
+        let shmem = match ShmemConf::new().size(size).flink(flink).create() {
+            Ok(m) => m,
+            // Another calculator created it since we looked
+            Err(ShmemError::LinkExists) => return Err(owned_elsewhere()),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+
+        let table = Self {
+            shmem,
+            slot_count,
+            cell_count,
+            cell_size,
+        };
+        let header = table.header();
+        header
+            .slot_count
+            .store(slot_count as u64, Ordering::Relaxed);
+        header
+            .cell_count
+            .store(cell_count as u64, Ordering::Relaxed);
+        header.cell_size.store(cell_size as u64, Ordering::Relaxed);
+        header.server.store(std::process::id(), Ordering::Relaxed);
+        // The segment starts zeroed, so every slot is free; publish it by writing the magic last
+        header.magic.store(MAGIC, Ordering::Release);
+        Ok(table)
+    }
+
+    /// Opens the control segment at `flink`, waiting for the calculator to create it.
+    pub fn open(flink: &str) -> io::Result<Self> {
+        while !Path::new(flink).exists() {
+            std::thread::yield_now();
+        }
+        let shmem = ShmemConf::new()
+            .flink(flink)
+            .open()
+            .map_err(io::Error::other)?;
+
+        // SAFETY: the header is at the start of a page aligned mapping
+        let header = unsafe { &*(shmem.as_ptr() as *const TableHeader) };
+        while header.magic.load(Ordering::Acquire) != MAGIC {
+            std::thread::yield_now();
+        }
+        let slot_count = header.slot_count.load(Ordering::Relaxed) as usize;
+        let cell_count = header.cell_count.load(Ordering::Relaxed) as usize;
+        let cell_size = header.cell_size.load(Ordering::Relaxed) as usize;
+        if shmem.len() < segment_size(slot_count, cell_count, cell_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "control segment is truncated",
+            ));
+        }
+
+        Ok(Self {
+            shmem,
+            slot_count,
+            cell_count,
+            cell_size,
+        })
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.slot_count
+    }
+
+    /// Largest chunk of a message that moves through the segment at once.
+    pub fn cell_size(&self) -> usize {
+        self.cell_size
+    }
+
+    /// Claims a free slot for this process, waiting until one is released.
+    pub fn claim(&self) -> ClientSlot<'_> {
+        loop {
+            if let Some(slot) = self.try_claim() {
+                return slot;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    /// Claims a free slot for this process, if there is one.
+    pub fn try_claim(&self) -> Option<ClientSlot<'_>> {
+        let pid = std::process::id();
+        // The calculator empties a slot before freeing it, so there's nothing to reset
+        (0..self.slot_count).find_map(|index| {
+            self.slot(index)
+                .owner
+                .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed)
+                .ok()?;
+            Some(ClientSlot { table: self, index })
+        })
+    }
+
+    /// Credit counters of both rings of slot `index`.
+    pub fn stats(&self, index: usize) -> SlotStats {
+        assert!(index < self.slot_count, "no slot {}", index);
+        SlotStats {
+            requests: self.ring(index, Direction::Request).stats(),
+            responses: self.ring(index, Direction::Response).stats(),
+        }
+    }
+
+    /// Empties slot `index` and makes it available to the next manager.
+    fn free(&self, index: usize) {
+        for direction in [Direction::Request, Direction::Response] {
+            let ring = self.ring(index, direction);
+            ring.header().head.store(0, Ordering::Relaxed);
+            ring.header().tail.store(0, Ordering::Relaxed);
+        }
+        let slot = self.slot(index);
+        slot.released.store(0, Ordering::Relaxed);
+        slot.owner.store(0, Ordering::Release);
+    }
+
+    fn header(&self) -> &TableHeader {
+        // SAFETY: the header is at the start of a page aligned mapping
+        unsafe { &*(self.shmem.as_ptr() as *const TableHeader) }
+    }
+
+    fn slot(&self, index: usize) -> &SlotHeader {
+        debug_assert!(index < self.slot_count);
+        // SAFETY: slot headers are 64 byte aligned and lie before `BUFFERS_OFFSET`
+        unsafe { &*(self.shmem.as_ptr().add(SLOT_HEADER_SIZE * (index + 1)) as *const SlotHeader) }
+    }
+
+    fn ring(&self, index: usize, direction: Direction) -> Ring<'_> {
+        debug_assert!(index < self.slot_count);
+        let ring_size = ring_size(self.cell_count, self.cell_size);
+        let offset = BUFFERS_OFFSET + (2 * index + direction as usize) * ring_size;
+        Ring {
+            // SAFETY: `open` checked that the mapping covers every ring
+            base: unsafe { self.shmem.as_ptr().add(offset) },
+            cell_count: self.cell_count,
+            cell_size: self.cell_size,
+            _table: PhantomData,
+        }
+    }
+}
+
+fn owned_elsewhere() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "another calculator owns the control segment",
+    )
+}
+
+fn ring_size(cell_count: usize, cell_size: usize) -> usize {
+    RING_HEADER_SIZE + cell_count * (CELL_HEADER_SIZE + cell_size)
+}
+
+fn segment_size(slot_count: usize, cell_count: usize, cell_size: usize) -> usize {
+    BUFFERS_OFFSET + slot_count * 2 * ring_size(cell_count, cell_size)
+}
+
+/// Credit counters of a ring, counted in cells.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RingStats {
+    pub capacity: usize,
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl RingStats {
+    /// Cells written but not read yet.
+    pub fn in_flight(&self) -> u64 {
+        self.sent - self.received
+    }
+
+    /// Cells that can be written before the sender has to wait.
+    pub fn credits(&self) -> u64 {
+        (self.capacity as u64).saturating_sub(self.in_flight())
+    }
+}
+
+/// Credit counters of both rings of a slot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlotStats {
+    pub requests: RingStats,
+    pub responses: RingStats,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Request = 0,
+    Response = 1,
+}
+
+/// A single-producer single-consumer queue of cells inside the segment.
+struct Ring<'a> {
+    base: *mut u8,
+    cell_count: usize,
+    cell_size: usize,
+    _table: PhantomData<&'a SlotTable>,
+}
+
+impl Ring<'_> {
+    fn header(&self) -> &RingHeader {
+        // SAFETY: rings are 8 byte aligned and start with their header
+        unsafe { &*(self.base as *const RingHeader) }
+    }
+
+    fn cell(&self, sequence: u64) -> (*mut CellHeader, *mut u8) {
+        let index = (sequence % self.cell_count as u64) as usize;
+        // SAFETY: `index` is below the cell count, so the cell lies inside the ring
+        unsafe {
+            let cell = self
+                .base
+                .add(RING_HEADER_SIZE + index * (CELL_HEADER_SIZE + self.cell_size));
+            (cell as *mut CellHeader, cell.add(CELL_HEADER_SIZE))
+        }
+    }
+
+    /// Payload buffer of the next cell to write, if the ring isn't full.
+    fn next_free(&self) -> Option<*mut u8> {
+        let header = self.header();
+        let head = header.head.load(Ordering::Relaxed);
+        if head - header.tail.load(Ordering::Acquire) == self.cell_count as u64 {
+            return None;
+        }
+        Some(self.cell(head).1)
+    }
+
+    /// Hands the cell returned by `next_free` to the consumer.
+    fn publish(&self, len: usize, end: bool) {
+        let header = self.header();
+        let head = header.head.load(Ordering::Relaxed);
+        let (cell, _) = self.cell(head);
+        // SAFETY: the consumer doesn't read the cell until the head moves past it
+        unsafe {
+            (*cell).len = len as u32;
+            (*cell).end = end as u32;
+        }
+        header.head.store(head + 1, Ordering::Release);
+    }
+
+    /// Payload and end marker of the next cell to read, if there is one.
+    fn next_full(&self) -> Option<(&[u8], bool)> {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        if header.head.load(Ordering::Acquire) == tail {
+            return None;
+        }
+        let (cell, data) = self.cell(tail);
+        // SAFETY: the producer doesn't touch the cell until the tail moves past it
+        unsafe {
+            let len = ((*cell).len as usize).min(self.cell_size);
+            Some((std::slice::from_raw_parts(data, len), (*cell).end != 0))
+        }
+    }
+
+    /// Hands the cell returned by `next_full` back to the producer.
+    fn consume(&self) {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        header.tail.store(tail + 1, Ordering::Release);
+    }
+
+    fn stats(&self) -> RingStats {
+        let header = self.header();
+        // Reading the tail first keeps it from overtaking the head
+        let received = header.tail.load(Ordering::Acquire);
+        let sent = header.head.load(Ordering::Acquire);
+        RingStats {
+            capacity: self.cell_count,
+            sent,
+            received,
+        }
+    }
+}
+
+/// A slot claimed by this process, released when dropped.
+pub struct ClientSlot<'a> {
+    table: &'a SlotTable,
+    index: usize,
+}
+
+impl ClientSlot<'_> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn stats(&self) -> SlotStats {
+        self.table.stats(self.index)
+    }
+
+    /// Sends a whole message, waiting for free cells as needed.
+    pub fn send(&self, message: &[u8]) -> io::Result<()> {
+        let mut cells = message.chunks(self.table.cell_size).peekable();
+        if cells.peek().is_none() {
+            return self.push(&[], true);
+        }
+        while let Some(cell) = cells.next() {
+            self.push(cell, cells.peek().is_none())?;
+        }
+        Ok(())
+    }
+
+    /// Like [`ClientSlot::send`], failing with [`io::ErrorKind::WouldBlock`] unless
+    /// the request ring has room for the whole message.
+    pub fn try_send(&self, message: &[u8]) -> io::Result<()> {
+        let cells = message.len().div_ceil(self.table.cell_size).max(1);
+        if cells > self.table.cell_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message takes {} cells, a ring holds {}",
+                    cells, self.table.cell_count
+                ),
+            ));
+        }
+        // Only we write to the ring, so its credits can't shrink before we use them
+        let stats = self.stats().requests;
+        if stats.credits() < cells as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} of {} cells unread", stats.in_flight(), stats.capacity),
+            ));
+        }
+        self.send(message)
+    }
+
+    /// Sends everything `reader` yields as one message and returns its length.
+    ///
+    /// The data is read straight into the shared cells, so at most a ring's
+    /// worth of it is in flight no matter how long the message is.
+    pub fn send_stream(&self, mut reader: impl Read) -> io::Result<u64> {
+        let ring = self.table.ring(self.index, Direction::Request);
+        let mut total = 0;
+        loop {
+            let data = self.wait(|| ring.next_free())?;
+            // SAFETY: the cell is ours until it is published
+            let cell = unsafe { std::slice::from_raw_parts_mut(data, self.table.cell_size) };
+
+            let mut len = 0;
+            let mut end = false;
+            while len < cell.len() {
+                match reader.read(&mut cell[len..]) {
+                    Ok(0) => {
+                        end = true;
+                        break;
+                    }
+                    Ok(n) => len += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            ring.publish(len, end);
+            total += len as u64;
+            if end {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Waits for the next message from the calculator.
+    pub fn recv(&self) -> io::Result<Vec<u8>> {
+        let ring = self.table.ring(self.index, Direction::Response);
+        let mut message = Vec::new();
+        loop {
+            let (data, end) = self.wait(|| ring.next_full())?;
+            message.extend_from_slice(data);
+            ring.consume();
+            if end {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn push(&self, data: &[u8], end: bool) -> io::Result<()> {
+        let ring = self.table.ring(self.index, Direction::Request);
+        let cell = self.wait(|| ring.next_free())?;
+        // SAFETY: the cell is ours until it is published and `data` fits into it
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), cell, data.len()) };
+        ring.publish(data.len(), end);
+        Ok(())
+    }
+
+    /// Spins until `ready` returns something, failing if the slot was taken from us
+    /// or the calculator exited.
+    fn wait<T>(&self, mut ready: impl FnMut() -> Option<T>) -> io::Result<T> {
+        let slot = self.table.slot(self.index);
+        let server = self.table.header().server.load(Ordering::Relaxed);
+        let mut spins = 0;
+        loop {
+            if let Some(value) = ready() {
+                return Ok(value);
+            }
+            if slot.owner.load(Ordering::Relaxed) != std::process::id() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "slot was reclaimed",
+                ));
+            }
+            // Give the calculator the core if it is slow to get to us
+            spins += 1;
+            if spins < SPINS_BEFORE_YIELD {
+                std::hint::spin_loop();
+                continue;
+            }
+            if spins % SPINS_BEFORE_YIELD == 0 && !is_alive(server) {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "calculator exited",
+                ));
+            }
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl Drop for ClientSlot<'_> {
+    fn drop(&mut self) {
+        // The calculator may still be working on the slot, it frees it once it has let go
+        self.table
+            .slot(self.index)
+            .released
+            .store(1, Ordering::Release);
+    }
+}
+
+/// The calculator's side of a request streamed through a slot.
+pub trait RequestStream: Sized {
+    /// Starts a request from its header message.
+    fn start(header: &[u8]) -> Self;
+    /// Consumes the next chunk of the body message.
+    fn feed(&mut self, chunk: &[u8]);
+    /// Ends the body and returns the response message.
+    fn finish(self) -> Vec<u8>;
+}
+
+impl RequestStream for wordcount::StreamingRequest {
+    fn start(header: &[u8]) -> Self {
+        Self::new(&String::from_utf8_lossy(header))
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        self.feed(chunk)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let (response, meta) = self.finish_timed();
+        response.to_json_with(&meta).into_bytes()
+    }
+}
+
+/// Where an exchange through a slot stands.
+enum Stage<S> {
+    Idle,
+    Header(Vec<u8>),
+    Body(S),
+    Responding { response: Vec<u8>, sent: usize },
+}
+
+/// Serves streamed requests on every slot of a table without blocking on any of them.
+pub struct Server<S> {
+    table: SlotTable,
+    stages: Vec<Stage<S>>,
+}
+
+impl<S: RequestStream> Server<S> {
+    pub fn new(table: SlotTable) -> Self {
+        let stages = (0..table.slot_count).map(|_| Stage::Idle).collect();
+        Self { table, stages }
+    }
+
+    pub fn table(&self) -> &SlotTable {
+        &self.table
+    }
+
+    /// Moves every exchange forward as far as the rings allow and returns how many cells moved.
+    pub fn poll(&mut self) -> usize {
+        let mut moved = 0;
+        for index in 0..self.table.slot_count {
+            let slot = self.table.slot(index);
+            if slot.owner.load(Ordering::Acquire) == 0 {
+                continue;
+            }
+            if slot.released.load(Ordering::Acquire) != 0 {
+                self.stages[index] = Stage::Idle;
+                self.table.free(index);
+                continue;
+            }
+            moved += self.receive(index) + self.respond(index);
+        }
+        moved
+    }
+
+    /// Frees slots whose owner no longer exists and returns how many were freed.
+    pub fn reclaim_stale(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for index in 0..self.table.slot_count {
+            let owner = self.table.slot(index).owner.load(Ordering::Acquire);
+            if owner == 0 || is_alive(owner) {
+                continue;
+            }
+            self.stages[index] = Stage::Idle;
+            self.table.free(index);
+            reclaimed += 1;
+        }
+        reclaimed
+    }
+
+    fn receive(&mut self, index: usize) -> usize {
+        let ring = self.table.ring(index, Direction::Request);
+        let stage = &mut self.stages[index];
+        let mut moved = 0;
+        while !matches!(stage, Stage::Responding { .. }) {
+            let Some((data, end)) = ring.next_full() else {
+                break;
+            };
+            *stage = match std::mem::replace(stage, Stage::Idle) {
+                Stage::Idle | Stage::Responding { .. } if end => Stage::Body(S::start(data)),
+                Stage::Idle | Stage::Responding { .. } => Stage::Header(data.to_vec()),
+                Stage::Header(mut header) => {
+                    header.extend_from_slice(data);
+                    if end {
+                        Stage::Body(S::start(&header))
+                    } else {
+                        Stage::Header(header)
+                    }
+                }
+                Stage::Body(mut request) => {
+                    request.feed(data);
+                    if end {
+                        Stage::Responding {
+                            response: request.finish(),
+                            sent: 0,
+                        }
+                    } else {
+                        Stage::Body(request)
+                    }
+                }
+            };
+            ring.consume();
+            moved += 1;
+        }
+        moved
+    }
+
+    fn respond(&mut self, index: usize) -> usize {
+        let Stage::Responding { response, sent } = &mut self.stages[index] else {
+            return 0;
+        };
+        let ring = self.table.ring(index, Direction::Response);
+        let mut moved = 0;
+        while let Some(cell) = ring.next_free() {
+            let len = (response.len() - *sent).min(self.table.cell_size);
+            // SAFETY: the cell is ours until it is published and the chunk fits into it
+            unsafe { std::ptr::copy_nonoverlapping(response[*sent..].as_ptr(), cell, len) };
+            *sent += len;
+            let end = *sent == response.len();
+            ring.publish(len, end);
+            moved += 1;
+            if end {
+                self.stages[index] = Stage::Idle;
+                break;
+            }
+        }
+        moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flink(name: &str) -> String {
+        format!("/tmp/mpklink-test-{}-{}.shm", name, std::process::id())
+    }
+
+    /// Answers with the header, a separator and the uppercased body.
+    struct Upper(Vec<u8>);
+
+    impl RequestStream for Upper {
+        fn start(header: &[u8]) -> Self {
+            let mut response = header.to_vec();
+            response.push(b'|');
+            Upper(response)
+        }
+
+        fn feed(&mut self, chunk: &[u8]) {
+            self.0.extend(chunk.to_ascii_uppercase());
+        }
+
+        fn finish(self) -> Vec<u8> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_slots_are_independent() {
+        let flink = flink("independent");
+        let mut server = Server::<Upper>::new(SlotTable::create_with(&flink, 2, 4, 64).unwrap());
+        let client = SlotTable::open(&flink).unwrap();
+        assert_eq!(client.slot_count(), 2);
+        assert_eq!(client.cell_size(), 64);
+
+        let first = client.claim();
+        let second = client.claim();
+        assert!(client.try_claim().is_none());
+
+        first.send(b"1").unwrap();
+        first.send(b"first").unwrap();
+        second.send(b"2").unwrap();
+        second.send_stream(&b"second"[..]).unwrap();
+        assert_eq!(server.poll(), 6);
+        assert_eq!(second.recv().unwrap(), b"2|SECOND");
+        assert_eq!(first.recv().unwrap(), b"1|FIRST");
+        assert_eq!(server.poll(), 0);
+
+        // Released slots are only free again once the calculator let go of them
+        drop(first);
+        assert!(client.try_claim().is_none());
+        server.poll();
+        assert_eq!(client.try_claim().unwrap().index(), 0);
+    }
+
+    #[test]
+    fn test_messages_span_cells() {
+        let flink = flink("span");
+        let mut server = Server::<Upper>::new(SlotTable::create_with(&flink, 1, 2, 8).unwrap());
+        let body = "a body much longer than the whole ring ".repeat(100);
+
+        let client = {
+            let flink = flink.clone();
+            let body = body.clone();
+            std::thread::spawn(move || {
+                let table = SlotTable::open(&flink).unwrap();
+                let slot = table.claim();
+                slot.send(b"a header spanning cells").unwrap();
+                assert_eq!(
+                    slot.send_stream(body.as_bytes()).unwrap(),
+                    body.len() as u64
+                );
+                let response = slot.recv().unwrap();
+                slot.send(b"").unwrap();
+                slot.send(b"").unwrap();
+                (response, slot.recv().unwrap())
+            })
+        };
+        while !client.is_finished() {
+            if server.poll() == 0 {
+                std::thread::yield_now();
+            }
+        }
+
+        let (response, empty) = client.join().unwrap();
+        let expected = format!("a header spanning cells|{}", body.to_ascii_uppercase());
+        assert_eq!(response, expected.as_bytes());
+        assert_eq!(empty, b"|");
+    }
+
+    #[test]
+    fn test_full_rings_refuse_messages() {
+        let flink = flink("full");
+        let mut server = Server::<Upper>::new(SlotTable::create_with(&flink, 1, 2, 8).unwrap());
+        let client = SlotTable::open(&flink).unwrap();
+        let slot = client.claim();
+
+        let error = slot.try_send(b"three cells long!").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        slot.try_send(b"header").unwrap();
+        let error = slot.try_send(b"sixteen byte body").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        slot.try_send(b"body").unwrap();
+        let error = slot.try_send(b"").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            slot.stats().requests,
+            RingStats {
+                capacity: 2,
+                sent: 2,
+                received: 0,
+            }
+        );
+
+        server.poll();
+        assert_eq!(slot.stats().requests.credits(), 2);
+        assert_eq!(server.table().stats(0).responses.in_flight(), 2);
+        assert_eq!(slot.recv().unwrap(), b"header|BODY");
+        assert_eq!(slot.stats().responses.credits(), 2);
+    }
+
+    #[test]
+    fn test_stale_segments_are_replaced() {
+        let flink = flink("replaced");
+        let table = SlotTable::create_with(&flink, 1, 4, 64).unwrap();
+        let error = SlotTable::create_with(&flink, 1, 4, 64).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let client = SlotTable::open(&flink).unwrap();
+        let slot = client.claim();
+        table.header().server.store(child.id(), Ordering::Release);
+        let error = slot.recv().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+
+        let table = SlotTable::create_with(&flink, 2, 4, 64).unwrap();
+        assert_eq!(SlotTable::open(&flink).unwrap().slot_count(), 2);
+        assert_eq!(
+            table.header().server.load(Ordering::Relaxed),
+            std::process::id()
+        );
+    }
+
+    #[test]
+    fn test_stale_slots_are_reclaimed() {
+        let flink = flink("stale");
+        let table = SlotTable::create_with(&flink, 1, 4, 64).unwrap();
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        table.slot(0).owner.store(child.id(), Ordering::Release);
+        let ring = table.ring(0, Direction::Request);
+        ring.next_free().unwrap();
+        ring.publish(0, false);
+
+        let mut server = Server::<Upper>::new(table);
+        assert!(server.table().try_claim().is_none());
+        assert_eq!(server.reclaim_stale(), 1);
+        assert_eq!(server.poll(), 0);
+
+        let slot = server.table().try_claim().unwrap();
+        assert!(server
+            .table()
+            .ring(0, Direction::Request)
+            .next_full()
+            .is_none());
+        drop(slot);
+        assert_eq!(server.reclaim_stale(), 0);
+    }
+}
@@ -0,0 +1,127 @@
+//! `services/mpk-thread`: the calculator as a compartment of the manager's process.
+//!
+//! Manager and calculator are compartments of one [`Domain`]. The calculator
+//! either runs on a thread of its own behind a channel each way, or is entered
+//! through a [`Gate`] on the manager's thread. [`run`] sets up either and hands
+//! the manager a [`Client`], which only works in the manager's compartment.
+
+use std::io;
+
+use pkey_mprotect::{Domain, Gate, ProtectionError, Receiver, Sender};
+
+/// The manager's end of the calculator.
+pub enum Client {
+    /// Requests and responses go through channels to the calculator's thread.
+    Channels {
+        requests: Sender,
+        responses: Receiver,
+    },
+    /// The calculator runs on our thread; a request waits for the call.
+    Gate { gate: Gate, pending: Vec<u8> },
+}
+
+impl Client {
+    /// Sends a serialized request.
+    pub fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Channels { requests, .. } => requests.send(request).map_err(io::Error::other),
+            Self::Gate { pending, .. } => {
+                pending.clear();
+                pending.extend_from_slice(request);
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits for the response to the last request.
+    pub fn recv(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Channels { responses, .. } => responses.recv().map_err(io::Error::other),
+            Self::Gate { gate, pending } => {
+                let request = String::from_utf8_lossy(pending);
+                Ok(gate.call(&*request, answer).into_bytes())
+            }
+        }
+    }
+}
+
+/// Runs `manager` in a compartment of its own, with a [`Client`] for a calculator
+/// in another one, and returns what it returns.
+///
+/// With `gated`, the calculator is entered through a gate instead of running on
+/// a thread of its own. A panic of `manager` carries on in the caller.
+pub fn run<R, F>(gated: bool, manager: F) -> io::Result<R>
+where
+    F: FnOnce(&mut Client) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let mut domain = Domain::new(false).map_err(io::Error::other)?;
+    let manager_id = domain
+        .compartment("request-manager")
+        .map_err(io::Error::other)?;
+    let calculator_id = domain
+        .compartment("request-calculator")
+        .map_err(io::Error::other)?;
+
+    let (mut client, calculator) = if gated {
+        let gate = domain.gate(calculator_id).map_err(io::Error::other)?;
+        let client = Client::Gate {
+            gate,
+            pending: Vec::new(),
+        };
+        (client, None)
+    } else {
+        let (requests, incoming) = domain
+            .channel(manager_id, calculator_id)
+            .map_err(io::Error::other)?;
+        let (outgoing, responses) = domain
+            .channel(calculator_id, manager_id)
+            .map_err(io::Error::other)?;
+        let calculator = domain
+            .spawn(calculator_id, move || serve(incoming, outgoing))
+            .map_err(io::Error::other)?;
+        let client = Client::Channels {
+            requests,
+            responses,
+        };
+        (client, Some(calculator))
+    };
+
+    // The calculator stops once the manager is done and its channels are dropped
+    let manager = domain
+        .spawn(manager_id, move || manager(&mut client))
+        .map_err(io::Error::other)?;
+    let result = manager
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+    if let Some(calculator) = calculator {
+        if let Err(e) = calculator.join().expect("the calculator doesn't panic") {
+            log::warn!("request-calculator failed: {}", e);
+        }
+    }
+    Ok(result)
+}
+
+/// Answers requests until the manager hangs up.
+fn serve(requests: Receiver, responses: Sender) -> Result<(), ProtectionError> {
+    log::debug!("Starting request-calculator...");
+    loop {
+        let request = match requests.recv() {
+            Ok(request) => String::from_utf8_lossy(&request).into_owned(),
+            Err(ProtectionError::Disconnected) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let response = answer(&request);
+        match responses.send(response.as_bytes()) {
+            Err(ProtectionError::Disconnected) => return Ok(()),
+            result => result?,
+        }
+    }
+}
+
+fn answer(request: &str) -> String {
+    log::debug!("Received request: {}", request);
+    let response = wordcount::process_request_timed(request);
+    log::debug!("Sent response: {:?}", response);
+    response
+}
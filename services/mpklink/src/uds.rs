@@ -0,0 +1,41 @@
+//! Unix domain socket transport: one JSON request or response per line.
+//!
+//! Serialized requests escape every newline of their input, so a line is
+//! always exactly one message.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+pub const UNIX_SOCKET: &str = "/tmp/service.sock";
+
+/// The manager's end of a connection; the calculator serves it until it is dropped.
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    pub fn connect() -> io::Result<Self> {
+        let writer = UnixStream::connect(UNIX_SOCKET)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    /// Sends a serialized request as one line.
+    pub fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        self.writer.write_all(request)?;
+        self.writer.write_all(b"\n")
+    }
+
+    /// Waits for the next response line.
+    pub fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let mut response = Vec::new();
+        if self.reader.read_until(b'\n', &mut response)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "calculator closed the connection",
+            ));
+        }
+        Ok(response)
+    }
+}
@@ -1,72 +1,24 @@
-use nix::unistd::mkfifo;
-use nix::sys::stat::Mode;
-use nix::errno::Errno;
-use std::fs::OpenOptions;
-use std::io::{Write, BufReader, BufRead};
-use std::collections::HashMap;
-use serde_json::Value;
-
-const PIPE_REQUEST: &str = "/tmp/request-pipe-request";
-const PIPE_RESPONSE: &str = "/tmp/request-pipe-response";
+use std::io;
+use mpklink::pipe::{is_disconnect, setup_pipes, Connection};
+use wordcount::{max_request_len, ErrorCode, Response};
 
 // Service 2 Functions
-fn create_pipe(pipe_path: &str) -> Result<(), nix::Error> {
-    match mkfifo(pipe_path, Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IWGRP) {
-        Ok(_) => Ok(()), // Successfully created the pipe
-        Err(e) if e == nix::Error::from(Errno::EEXIST) => {
-            // The pipe already exists; proceed as normal
-            // println!("Pipe already exists at {}", pipe_path);
-            Ok(())
-        }
-        Err(e) => Err(e), // Propagate other errors
-    }
-}
-
-fn setup_pipes() -> Result<(), nix::Error> {
-    create_pipe(PIPE_REQUEST)?; // Pipe for sending requests
-    create_pipe(PIPE_RESPONSE)?; // Pipe for receiving responses
-    Ok(())
-}
-
-fn recv_request() -> Result<String, std::io::Error> {
-    let request_pipe = OpenOptions::new()
-        .read(true)
-        .open(PIPE_REQUEST)?;
-    let mut reader = BufReader::new(request_pipe);
-    let mut request = String::new();
-    reader.read_line(&mut request)?;
-    Ok(request.trim().to_string())
-}
-
-fn send_response(response: &str) -> Result<(), std::io::Error> {
-    let mut response_pipe = OpenOptions::new()
-        .write(true)
-        .open(PIPE_RESPONSE)?;
-    writeln!(response_pipe, "{}", response)?;
-    Ok(())
-}
-
-fn process_request(request: String) -> String {
-    match serde_json::from_str::<Value>(&request) {
-        Ok(parsed) => {
-            let req_type = parsed["type"].as_str().unwrap_or_default();
-            let input = parsed["string"].as_str().unwrap_or_default();
-            match req_type {
-                "total" => {
-                    let total_count = input.split_whitespace().count() as i32;
-                    total_count.to_string()
-                }
-                "counts" => {
-                    let mut counts: HashMap<String, i32> = HashMap::new();
-                    for word in input.split_whitespace() {
-                        *counts.entry(word.to_string()).or_insert(0) += 1;
-                    }
-                    serde_json::to_string(&counts).unwrap_or_default()
-                }
-                _ => "Unknown request type".to_string(),
+fn serve(connection: &mut Connection) -> Result<(), io::Error> {
+    // Answer requests until the manager closes its end
+    loop {
+        let response = match connection.recv(max_request_len()) {
+            Ok(Some(request)) => {
+                let request = String::from_utf8_lossy(&request);
+                // println!("Received request: {}", request);
+                wordcount::process_request_timed(&request)
             }
-        }
-        Err(_) => "Failed to parse request".to_string(),
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                Response::error(ErrorCode::PayloadTooLarge, e.to_string()).to_json()
+            }
+            Err(e) => return Err(e),
+        };
+        connection.send(response.as_bytes())?;
     }
 }
 
@@ -74,13 +26,16 @@ fn main() -> Result<(), std::io::Error> {
     // println!("Starting request-calculator...");
 
     // Create pipes
-    setup_pipes().expect("Failed to create pipes");
+    setup_pipes()?;
 
-    // Process requests in a loop
+    // The FIFOs stay in place; every manager connects to them in turn
     loop {
-        let request = recv_request()?;
-        // println!("Received request: {}", request);
-        let response = process_request(request);
-        send_response(&response)?;
+        let mut connection = Connection::accept()?;
+        match serve(&mut connection) {
+            Ok(()) => {}
+            // The manager went away mid-request, wait for the next one
+            Err(e) if is_disconnect(&e) => {}
+            Err(e) => return Err(e),
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,9 +1,50 @@
 use std::os::unix::net::{UnixStream, UnixListener};
-use std::io::{Write, BufReader, BufRead};
-use std::collections::HashMap;
-use serde_json::Value;
+use std::io::{Write, BufReader, BufRead, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 
-const UNIX_SOCKET: &str = "/tmp/service.sock";
+use mpklink::uds::UNIX_SOCKET;
+use wordcount::{max_request_len, ErrorCode, Response};
+
+// Managers beyond this wait in the listen backlog until a connection closes
+const MAX_CONNECTIONS: usize = 64;
+
+/// Counts connections being served so that at most `max` threads run at once.
+struct ConnectionLimit {
+    active: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            active: Mutex::new(0),
+            freed: Condvar::new(),
+            max,
+        })
+    }
+
+    /// Blocks until another connection may be served.
+    fn acquire(self: &Arc<Self>) -> ConnectionPermit {
+        let mut active = self.active.lock().unwrap();
+        while *active >= self.max {
+            active = self.freed.wait(active).unwrap();
+        }
+        *active += 1;
+        ConnectionPermit(self.clone())
+    }
+}
+
+/// Frees its connection slot when the connection thread finishes.
+struct ConnectionPermit(Arc<ConnectionLimit>);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
 
 // Service 2 Functions
 fn create_socket(path: &str) -> Result<UnixListener, std::io::Error> {
@@ -13,8 +54,9 @@ fn create_socket(path: &str) -> Result<UnixListener, std::io::Error> {
             Ok(listener)
         }
         Err(e) => {
-            match e.raw_os_error() {
-                Some(48) => {
+            // EADDRINUSE is 48 on macOS but 98 on Linux, so match on the kind
+            match e.kind() {
+                std::io::ErrorKind::AddrInUse => {
                     // println!("Socket already exists at path: {}", path);
                     std::fs::remove_file(path).expect("Failed to remove file");
                     create_socket(path)
@@ -25,11 +67,51 @@ fn create_socket(path: &str) -> Result<UnixListener, std::io::Error> {
     }
 }
 
-fn recv_request(stream: &UnixStream) -> Result<String, std::io::Error> {
-    let mut reader = BufReader::new(stream);
-    let mut request = String::new();
-    reader.read_line(&mut request)?;
-    Ok(request)
+/// A line a manager sent, the length of one too long to accept, or why one isn't UTF-8.
+enum Line {
+    Request(String),
+    TooLong(u64),
+    Invalid(std::string::FromUtf8Error),
+}
+
+fn recv_request(reader: &mut BufReader<&UnixStream>) -> Result<Option<Line>, std::io::Error> {
+    let mut request = Vec::new();
+    // Reading stops a byte past the limit, so an endless line is never held whole
+    let limit = max_request_len() as u64 + 1;
+    let read = reader.by_ref().take(limit).read_until(b'\n', &mut request)?;
+    // Zero bytes means the manager closed its end of the connection
+    if read == 0 {
+        return Ok(None);
+    }
+    if request.last() == Some(&b'\n') {
+        request.pop();
+    } else if request.len() > max_request_len() {
+        return Ok(Some(Line::TooLong(read as u64 + skip_line(reader)?)));
+    }
+    Ok(Some(match String::from_utf8(request) {
+        Ok(request) => Line::Request(request),
+        Err(e) => Line::Invalid(e),
+    }))
+}
+
+// Drops the rest of a line, so the next request is read from its start
+fn skip_line(reader: &mut BufReader<&UnixStream>) -> Result<u64, std::io::Error> {
+    let mut skipped = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(skipped);
+        }
+        let (len, end) = match buf.iter().position(|&b| b == b'\n') {
+            Some(newline) => (newline + 1, true),
+            None => (buf.len(), false),
+        };
+        reader.consume(len);
+        skipped += len as u64;
+        if end {
+            return Ok(skipped);
+        }
+    }
 }
 
 fn send_response(mut stream: &UnixStream, response: &str) -> Result<(), std::io::Error> {
@@ -38,41 +120,45 @@ fn send_response(mut stream: &UnixStream, response: &str) -> Result<(), std::io:
     Ok(())
 }
 
-fn process_request(request: String) -> String {
-    match serde_json::from_str::<Value>(&request) {
-        Ok(parsed) => {
-            let req_type = parsed["type"].as_str().unwrap_or_default();
-            let input = parsed["string"].as_str().unwrap_or_default();
-            match req_type {
-                "total" => {
-                    let total_count = input.split_whitespace().count() as i32;
-                    total_count.to_string()
-                }
-                "counts" => {
-                    let mut counts: HashMap<String, i32> = HashMap::new();
-                    for word in input.split_whitespace() {
-                        *counts.entry(word.to_string()).or_insert(0) += 1;
-                    }
-                    serde_json::to_string(&counts).unwrap_or_default()
-                }
-                _ => "Unknown request type".to_string(),
+fn handle_client(stream: UnixStream) -> Result<(), std::io::Error> {
+    let mut reader = BufReader::new(&stream);
+
+    // Process requests until the manager disconnects
+    while let Some(line) = recv_request(&mut reader)? {
+        let response = match line {
+            Line::Request(request) => wordcount::process_request_timed(&request),
+            Line::TooLong(len) => wordcount::too_large(len as usize).to_json(),
+            Line::Invalid(e) => {
+                Response::error(ErrorCode::ParseFailure, format!("request is not UTF-8: {}", e)).to_json()
             }
-        }
-        Err(_) => "Failed to parse request".to_string(),
+        };
+        send_response(&stream, &response)?;
     }
+    Ok(())
 }
 
 fn main() -> Result<(), std::io::Error> {
     // println!("Starting request-calculator...");
 
     let listener = create_socket(UNIX_SOCKET)?;
-    let (stream, _) = listener.accept()?;
+    let limit = ConnectionLimit::new(MAX_CONNECTIONS);
 
-    // Process requests in a loop
+    // Serve every manager on its own thread
     loop {
-        let request = recv_request(&stream)?;
-        // println!("Received request: {}", request);
-        let response = process_request(request);
-        send_response(&stream, &response)?;
+        let permit = limit.acquire();
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        thread::spawn(move || {
+            let _permit = permit;
+            if let Err(e) = handle_client(stream) {
+                eprintln!("Connection failed: {}", e);
+            }
+        });
     }
-}
\ No newline at end of file
+}
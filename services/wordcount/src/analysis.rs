@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::request::{GrepMode, RequestKind, Ties};
+
+/// Result of an analysis, serialized without a tag.
+///
+/// Every collection is sorted so equal inputs always produce identical JSON.
+/// Without the tag the JSON is ambiguous, so it is only read back through
+/// [`Output::from_value`], which is told the kind of the request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Output {
+    /// `total` and `unique`.
+    Count(usize),
+    /// `counts`, keyed in lexicographic order.
+    Counts(BTreeMap<String, usize>),
+    /// `top` and `ngrams`, most frequent first.
+    Ranking(Vec<(String, usize)>),
+    /// `histogram`, keyed by word length.
+    Histogram(BTreeMap<usize, usize>),
+    /// `grep`, in ascending order.
+    Matches(Vec<usize>),
+}
+
+/// Formats the result as the JSON it is sent as.
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+impl Output {
+    /// Reads the result of a `kind` analysis back from its JSON.
+    ///
+    /// The JSON alone doesn't say which variant it is: a histogram looks like
+    /// word counts and an empty ranking like no matches, so `kind` decides.
+    pub fn from_value(kind: &RequestKind, value: serde_json::Value) -> serde_json::Result<Self> {
+        use serde_json::from_value;
+        Ok(match kind {
+            RequestKind::Total | RequestKind::Unique => Self::Count(from_value(value)?),
+            RequestKind::Counts => Self::Counts(from_value(value)?),
+            RequestKind::Top { .. } | RequestKind::Ngrams { .. } => {
+                Self::Ranking(from_value(value)?)
+            }
+            RequestKind::Histogram => Self::Histogram(from_value(value)?),
+            RequestKind::Grep { .. } => Self::Matches(from_value(value)?),
+        })
+    }
+}
+
+/// Runs `kind` over every word of `input`.
+pub fn analyze(kind: &RequestKind, input: &str) -> Output {
+    let mut analyzer = Analyzer::new(kind);
+    for (line, text) in input.lines().enumerate() {
+        for word in text.split_whitespace() {
+            analyzer.push(word, line + 1);
+        }
+    }
+    analyzer.finish()
+}
+
+/// Incremental analysis that is fed one word at a time.
+pub struct Analyzer {
+    state: State,
+}
+
+enum State {
+    Total(usize),
+    Counts(HashMap<String, usize>),
+    Unique(HashMap<String, usize>),
+    Top {
+        n: Option<usize>,
+        ties: Ties,
+        counts: HashMap<String, usize>,
+    },
+    Histogram(BTreeMap<usize, usize>),
+    Ngrams {
+        window: VecDeque<String>,
+        n: usize,
+        counts: HashMap<String, usize>,
+    },
+    Grep {
+        word: String,
+        mode: GrepMode,
+        position: usize,
+        matches: Vec<usize>,
+    },
+}
+
+impl Analyzer {
+    pub fn new(kind: &RequestKind) -> Self {
+        let state = match kind {
+            RequestKind::Total => State::Total(0),
+            RequestKind::Counts => State::Counts(HashMap::new()),
+            RequestKind::Unique => State::Unique(HashMap::new()),
+            RequestKind::Top { n, ties } => State::Top {
+                n: *n,
+                ties: *ties,
+                counts: HashMap::new(),
+            },
+            RequestKind::Histogram => State::Histogram(BTreeMap::new()),
+            RequestKind::Ngrams { n } => State::Ngrams {
+                window: VecDeque::with_capacity(*n),
+                n: *n,
+                counts: HashMap::new(),
+            },
+            RequestKind::Grep { word, mode } => State::Grep {
+                word: word.clone(),
+                mode: *mode,
+                position: 0,
+                matches: Vec::new(),
+            },
+        };
+        Self { state }
+    }
+
+    /// Feeds the next word, found on the 1-based `line`.
+    pub fn push(&mut self, word: &str, line: usize) {
+        match &mut self.state {
+            State::Total(total) => *total += 1,
+            State::Counts(counts) | State::Unique(counts) | State::Top { counts, .. } => {
+                increment(counts, word)
+            }
+            State::Histogram(lengths) => *lengths.entry(word.chars().count()).or_insert(0) += 1,
+            State::Ngrams { window, n, counts } => {
+                if window.len() == *n {
+                    window.pop_front();
+                }
+                window.push_back(word.to_string());
+                if window.len() == *n {
+                    let ngram = window.iter().map(String::as_str).collect::<Vec<_>>().join(" ");
+                    *counts.entry(ngram).or_insert(0) += 1;
+                }
+            }
+            State::Grep {
+                word: needle,
+                mode,
+                position,
+                matches,
+            } => {
+                if word == needle {
+                    match mode {
+                        GrepMode::Lines if matches.last() == Some(&line) => {}
+                        GrepMode::Lines => matches.push(line),
+                        GrepMode::Positions => matches.push(*position),
+                    }
+                }
+                *position += 1;
+            }
+        }
+    }
+
+    /// Consumes the analyzer and returns its result.
+    pub fn finish(self) -> Output {
+        match self.state {
+            State::Total(total) => Output::Count(total),
+            State::Counts(counts) => Output::Counts(counts.into_iter().collect()),
+            State::Unique(counts) => Output::Count(counts.len()),
+            State::Top { n, ties, counts } => Output::Ranking(rank(counts, n, ties)),
+            State::Histogram(lengths) => Output::Histogram(lengths),
+            State::Ngrams { counts, .. } => Output::Ranking(rank(counts, None, Ties::Alphabetical)),
+            State::Grep { matches, .. } => Output::Matches(matches),
+        }
+    }
+}
+
+fn increment(counts: &mut HashMap<String, usize>, word: &str) {
+    match counts.get_mut(word) {
+        Some(count) => *count += 1,
+        None => {
+            counts.insert(word.to_string(), 1);
+        }
+    }
+}
+
+/// Sorts by descending count, then lexicographically, and keeps the first `n`.
+fn rank(counts: HashMap<String, usize>, n: Option<usize>, ties: Ties) -> Vec<(String, usize)> {
+    let mut ranking: Vec<_> = counts.into_iter().collect();
+    ranking.sort_unstable_by(|(a_word, a_count), (b_word, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_word.cmp(b_word))
+    });
+
+    let n = match n {
+        Some(n) => n,
+        None => return ranking,
+    };
+    let len = match ties {
+        Ties::Alphabetical => n.min(ranking.len()),
+        Ties::Include => match n.checked_sub(1).and_then(|last| ranking.get(last)) {
+            Some((_, cutoff)) => {
+                let cutoff = *cutoff;
+                n + ranking[n..].iter().take_while(|(_, count)| *count == cutoff).count()
+            }
+            None => n.min(ranking.len()),
+        },
+    };
+    ranking.truncate(len);
+    ranking
+}
@@ -0,0 +1,308 @@
+//! Word counting shared by every request-calculator.
+//!
+//! Requests have the form `{"type": "total", "string": "hello world"}`, where
+//! `"type"` selects a [`RequestKind`] and its parameters sit next to it, e.g.
+//! `{"type": "top", "n": 10, "string": "..."}`. Input that isn't UTF-8 is sent
+//! base64 encoded in a `"base64"` field instead of `"string"`.
+//! Calculators answer with a [`Response`] envelope, either
+//! `{"status": "ok", "result": 2}` or `{"status": "error", "error": {"code": ..., "message": ...}}`.
+//! All results are sorted so they can be compared byte-for-byte across transports.
+//!
+//! Transports that can't hold a whole input at once send just the header, e.g.
+//! `{"type": "total"}`, and stream the input through a [`StreamingRequest`].
+
+mod analysis;
+mod request;
+mod response;
+mod stream;
+
+pub use analysis::{analyze, Analyzer, Output};
+pub use request::{GrepMode, Input, Request, RequestKind, Ties};
+pub use response::{Error, ErrorCode, Metadata, Response};
+pub use stream::{ChunkedAnalyzer, StreamingRequest};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use serde_json::Value;
+
+/// Largest request a calculator accepts, in bytes.
+///
+/// A quarter above the biggest generated input (100M words, about 1 GiB).
+/// Calculators check it before reading a request, from the frame header or
+/// while reading the line, so a bogus length can't make them allocate more.
+pub const MAX_REQUEST_LEN: usize = 5 << 28;
+
+/// Largest response a manager accepts, in bytes.
+///
+/// Results can outgrow their input, e.g. the positions of every word or the
+/// counts of every trigram, so this leaves room for several times
+/// [`MAX_REQUEST_LEN`]. Managers that read a length before the response check
+/// it, so a corrupt one can't make them allocate more.
+pub const MAX_RESPONSE_LEN: usize = 4 * MAX_REQUEST_LEN;
+
+/// The limit calculators enforce: [`MAX_REQUEST_LEN`], unless the environment
+/// variable `WORDCOUNT_MAX_REQUEST_LEN` sets another, e.g. for tests that check
+/// it without sending a gigabyte.
+pub fn max_request_len() -> usize {
+    static LIMIT: OnceLock<usize> = OnceLock::new();
+    *LIMIT.get_or_init(|| {
+        std::env::var("WORDCOUNT_MAX_REQUEST_LEN")
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(MAX_REQUEST_LEN)
+    })
+}
+
+/// The answer to a request of `len` bytes, more than [`max_request_len`].
+pub fn too_large(len: usize) -> Response {
+    Response::error(
+        ErrorCode::PayloadTooLarge,
+        format!(
+            "request is {} bytes, at most {} are accepted",
+            len,
+            max_request_len()
+        ),
+    )
+}
+
+/// Parses a JSON request and answers it.
+pub fn handle_request(request: &str) -> Response {
+    if request.len() > max_request_len() {
+        return too_large(request.len());
+    }
+
+    let parsed = match serde_json::from_str::<Value>(request) {
+        Ok(parsed) => parsed,
+        Err(e) => return Response::error(ErrorCode::ParseFailure, e.to_string()),
+    };
+
+    let req_type = match request_type(&parsed) {
+        Ok(req_type) => req_type,
+        Err(response) => return response,
+    };
+
+    let request = match serde_json::from_value::<Request>(parsed) {
+        Ok(request) => request,
+        Err(e) => {
+            return Response::error(
+                ErrorCode::ParseFailure,
+                format!("invalid {} request: {}", req_type, e),
+            )
+        }
+    };
+    if let Err(message) = request.kind.validate() {
+        return Response::error(ErrorCode::ParseFailure, message);
+    }
+
+    let input = match request.input.text() {
+        Ok(input) => input,
+        Err(message) => return Response::error(ErrorCode::ParseFailure, message),
+    };
+
+    match panic::catch_unwind(AssertUnwindSafe(|| analyze(&request.kind, &input))) {
+        Ok(result) => Response::Ok { result },
+        Err(_) => Response::error(ErrorCode::Internal, format!("{} analysis panicked", req_type)),
+    }
+}
+
+/// Returns the request type, or the error response for a missing or unknown one.
+fn request_type(parsed: &Value) -> Result<String, Response> {
+    match parsed["type"].as_str() {
+        Some(req_type) if RequestKind::TYPES.contains(&req_type) => Ok(req_type.to_string()),
+        Some(req_type) => Err(Response::error(
+            ErrorCode::UnknownType,
+            format!("unknown request type: {}", req_type),
+        )),
+        None => Err(Response::error(ErrorCode::UnknownType, "missing request type")),
+    }
+}
+
+/// Parses the kind of a request whose input is sent separately.
+fn parse_kind(parsed: Value) -> Result<RequestKind, Response> {
+    let req_type = request_type(&parsed)?;
+    let kind = serde_json::from_value::<RequestKind>(parsed).map_err(|e| {
+        Response::error(
+            ErrorCode::ParseFailure,
+            format!("invalid {} request: {}", req_type, e),
+        )
+    })?;
+    kind.validate()
+        .map_err(|message| Response::error(ErrorCode::ParseFailure, message))?;
+    Ok(kind)
+}
+
+/// Parses a JSON request and returns the serialized [`Response`].
+pub fn process_request(request: &str) -> String {
+    handle_request(request).to_json()
+}
+
+/// Like [`process_request`], with the time it took in the response [`Metadata`].
+pub fn process_request_timed(request: &str) -> String {
+    let start = Instant::now();
+    let response = handle_request(request);
+    response.to_json_with(&Metadata::since(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(result: &str) -> String {
+        format!(r#"{{"status":"ok","result":{}}}"#, result)
+    }
+
+    fn error_code(request: &str) -> ErrorCode {
+        match handle_request(request) {
+            Response::Error { error } => error.code,
+            Response::Ok { result } => panic!("unexpected result {}", result),
+        }
+    }
+
+    #[test]
+    fn test_total() {
+        assert_eq!(analyze(&RequestKind::Total, "hello world\nhello  "), Output::Count(3));
+        assert_eq!(analyze(&RequestKind::Total, ""), Output::Count(0));
+    }
+
+    #[test]
+    fn test_counts_are_sorted() {
+        let response = process_request(r#"{"type": "counts", "string": "b a c a"}"#);
+        assert_eq!(response, ok(r#"{"a":2,"b":1,"c":1}"#));
+    }
+
+    #[test]
+    fn test_top_breaks_ties_alphabetically() {
+        let response = process_request(r#"{"type": "top", "string": "b c a c b d", "n": 3}"#);
+        assert_eq!(response, ok(r#"[["b",2],["c",2],["a",1]]"#));
+
+        let response = process_request(r#"{"type": "top", "string": "b a"}"#);
+        assert_eq!(response, ok(r#"[["a",1],["b",1]]"#));
+
+        let response = process_request(
+            r#"{"type": "top", "string": "b c a c b d", "n": 3, "ties": "include"}"#,
+        );
+        assert_eq!(response, ok(r#"[["b",2],["c",2],["a",1],["d",1]]"#));
+    }
+
+    #[test]
+    fn test_unique_and_histogram() {
+        let response = process_request(r#"{"type": "unique", "string": "a b a c"}"#);
+        assert_eq!(response, ok("3"));
+
+        let response = process_request(r#"{"type": "histogram", "string": "a bb cc ddd é"}"#);
+        assert_eq!(response, ok(r#"{"1":2,"2":2,"3":1}"#));
+    }
+
+    #[test]
+    fn test_ngrams_span_lines() {
+        let response = process_request(r#"{"type": "ngrams", "string": "a b\na b c"}"#);
+        assert_eq!(response, ok(r#"[["a b",2],["b a",1],["b c",1]]"#));
+
+        let response = process_request(r#"{"type": "ngrams", "n": 3, "string": "a b a b"}"#);
+        assert_eq!(response, ok(r#"[["a b a",1],["b a b",1]]"#));
+    }
+
+    #[test]
+    fn test_grep() {
+        let input = "a b a\nc\nb a";
+        let kind = RequestKind::Grep {
+            word: "a".to_string(),
+            mode: GrepMode::Lines,
+        };
+        let response = process_request(&Request::new(kind, input).to_json());
+        assert_eq!(response, ok("[1,3]"));
+
+        let response = process_request(
+            r#"{"type": "grep", "word": "a", "mode": "positions", "string": "a b a\nc\nb a"}"#,
+        );
+        assert_eq!(response, ok("[0,2,5]"));
+    }
+
+    #[test]
+    fn test_requests_escape_their_input() {
+        let input = "a \"b\", \"type\": \"counts\"}\\\n\tc\u{0}";
+        let request = Request::new(RequestKind::Total, input);
+        assert_eq!(process_request(&request.to_json()), ok("5"));
+        assert_eq!(serde_json::from_str::<Request>(&request.to_json()).unwrap(), request);
+
+        let request = Request::new(RequestKind::Counts, &b"ok \xff ok"[..]);
+        assert!(matches!(request.input, Input::Base64(_)));
+        assert_eq!(process_request(&request.to_json()), ok("{\"ok\":2,\"\u{fffd}\":1}"));
+
+        assert_eq!(
+            error_code(r#"{"type": "total", "base64": "not base64!"}"#),
+            ErrorCode::ParseFailure
+        );
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        assert_eq!(error_code(r#"{"type": "nope"}"#), ErrorCode::UnknownType);
+        assert_eq!(error_code(r#"{"string": "a"}"#), ErrorCode::UnknownType);
+        assert_eq!(error_code("not json"), ErrorCode::ParseFailure);
+        assert_eq!(error_code(r#"{"type": "total"}"#), ErrorCode::ParseFailure);
+        assert_eq!(
+            error_code(r#"{"type": "ngrams", "n": 0, "string": "a"}"#),
+            ErrorCode::ParseFailure
+        );
+        assert_eq!(
+            error_code(r#"{"type": "top", "n": 0, "string": "a"}"#),
+            ErrorCode::ParseFailure
+        );
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let response = process_request(r#"{"type": "nope", "string": "a"}"#);
+        assert_eq!(
+            response,
+            r#"{"status":"error","error":{"code":"unknown_type","message":"unknown request type: nope"}}"#
+        );
+        assert_eq!(
+            Response::parse_for(&RequestKind::Total, &response),
+            handle_request(r#"{"type": "nope"}"#)
+        );
+
+        let response = Response::parse_for(
+            &RequestKind::Counts,
+            &process_request(r#"{"type": "counts", "string": "a"}"#),
+        );
+        let expected = Output::Counts([("a".to_string(), 1)].into_iter().collect());
+        assert_eq!(response, Response::Ok { result: expected });
+
+        let response = process_request(r#"{"type": "histogram", "string": "aaaaaaaaaa b"}"#);
+        let expected = Output::Histogram([(1, 1), (10, 1)].into_iter().collect());
+        assert_eq!(
+            Response::parse_for(&RequestKind::Histogram, &response),
+            Response::Ok { result: expected }
+        );
+        let kind = RequestKind::Grep {
+            word: "a".to_string(),
+            mode: GrepMode::Lines,
+        };
+        let expected = Output::Matches(Vec::new());
+        assert_eq!(Response::parse_for(&kind, &ok("[]")), Response::Ok { result: expected });
+        match Response::parse_for(&RequestKind::Total, &ok("[]")) {
+            Response::Error { error } => assert_eq!(error.code, ErrorCode::Internal),
+            response => panic!("unexpected response {:?}", response),
+        }
+
+        let response = process_request_timed(r#"{"type": "total", "string": "a b"}"#);
+        assert!(response.starts_with(r#"{"status":"ok","result":2,"meta":{"compute_ns":"#));
+        assert_eq!(
+            Response::parse_for(&RequestKind::Total, &response),
+            Response::Ok { result: Output::Count(2) }
+        );
+        let (_, meta) = Response::parse_with_metadata(&RequestKind::Total, &response);
+        assert!(meta.is_some());
+        let (_, meta) = Response::parse_with_metadata(&RequestKind::Total, &ok("2"));
+        assert_eq!(meta, None);
+
+        match Response::parse_for(&RequestKind::Total, "Received response: 3") {
+            Response::Error { error } => assert_eq!(error.code.exit_code(), 5),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// A word-analysis request as sent by a request-manager.
+///
+/// On the wire the analysis and its parameters are flattened next to the input:
+/// `{"type": "top", "n": 10, "string": "..."}`. Managers build requests with
+/// [`Request::new`] and [`Request::to_json`] rather than by hand, so any input
+/// is escaped properly and can't inject fields of its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    #[serde(flatten)]
+    pub kind: RequestKind,
+    #[serde(flatten)]
+    pub input: Input,
+}
+
+impl Request {
+    pub fn new(kind: RequestKind, input: impl Into<Vec<u8>>) -> Self {
+        Self {
+            kind,
+            input: Input::from_bytes(input.into()),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("requests always serialize")
+    }
+}
+
+/// The text a request is about, in the field named after its encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Input {
+    /// `"string"`: the input itself.
+    String(String),
+    /// `"base64"`: input that isn't valid UTF-8, base64 encoded.
+    Base64(String),
+}
+
+impl Input {
+    /// Sends UTF-8 as is and falls back to base64 for anything else.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(string) => Self::String(string),
+            Err(e) => Self::Base64(BASE64.encode(e.as_bytes())),
+        }
+    }
+
+    /// The input as sent by the manager.
+    pub fn bytes(&self) -> Result<Cow<'_, [u8]>, String> {
+        match self {
+            Self::String(string) => Ok(Cow::Borrowed(string.as_bytes())),
+            Self::Base64(encoded) => BASE64
+                .decode(encoded)
+                .map(Cow::Owned)
+                .map_err(|e| format!("invalid base64 input: {}", e)),
+        }
+    }
+
+    /// The input as text; bytes that aren't UTF-8 become U+FFFD.
+    pub fn text(&self) -> Result<Cow<'_, str>, String> {
+        match self {
+            Self::String(string) => Ok(Cow::Borrowed(string)),
+            Self::Base64(_) => {
+                let bytes = self.bytes()?;
+                Ok(Cow::Owned(String::from_utf8_lossy(&bytes).into_owned()))
+            }
+        }
+    }
+}
+
+/// The analysis to run over the input, selected by the JSON `"type"` field.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RequestKind {
+    /// Number of words.
+    Total,
+    /// Occurrences of every word.
+    Counts,
+    /// Number of distinct words.
+    Unique,
+    /// The `n` most frequent words, or all of them when `n` is missing.
+    Top {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        n: Option<usize>,
+        #[serde(default)]
+        ties: Ties,
+    },
+    /// Number of words of every length, measured in characters.
+    Histogram,
+    /// Frequencies of every sequence of `n` consecutive words.
+    Ngrams {
+        #[serde(default = "default_ngram_size")]
+        n: usize,
+    },
+    /// Lines or word positions at which `word` occurs.
+    Grep {
+        word: String,
+        #[serde(default)]
+        mode: GrepMode,
+    },
+}
+
+impl RequestKind {
+    /// Values accepted in the `"type"` field.
+    pub const TYPES: &'static [&'static str] = &[
+        "total",
+        "counts",
+        "unique",
+        "top",
+        "histogram",
+        "ngrams",
+        "grep",
+    ];
+
+    /// Rejects parameters that deserialize but cannot be answered.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Ngrams { n: 0 } => Err("ngram size must be at least 1".to_string()),
+            Self::Top { n: Some(0), .. } => Err("top needs n of at least 1".to_string()),
+            Self::Grep { word, .. } if word.split_whitespace().count() != 1 => {
+                Err("grep expects a single word".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The JSON header of a request whose input is streamed separately.
+    pub fn header(&self) -> String {
+        serde_json::to_string(self).expect("request kinds always serialize")
+    }
+}
+
+/// How `top` orders words that share a count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ties {
+    /// Ties are ordered lexicographically and cut off at exactly `n` words.
+    #[default]
+    Alphabetical,
+    /// Ties are ordered lexicographically, and every word tied with the
+    /// `n`-th one is included even if that returns more than `n` words.
+    Include,
+}
+
+/// What a `grep` request reports for every match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrepMode {
+    /// 1-based numbers of the lines containing the word, each reported once.
+    #[default]
+    Lines,
+    /// 0-based indices of the word among all words of the input.
+    Positions,
+}
+
+fn default_ngram_size() -> usize {
+    2
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::Output;
+use crate::request::RequestKind;
+
+/// Envelope every calculator answers with.
+///
+/// Serializes as `{"status": "ok", "result": ...}` or
+/// `{"status": "error", "error": {"code": "unknown_type", "message": "..."}}`,
+/// and is read back by [`Response::parse_with_metadata`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Response {
+    Ok { result: Output },
+    Error { error: Error },
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            error: Error {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+
+    /// Parses the answer to a request of `kind`.
+    ///
+    /// The result is read as the variant `kind` produces, since the JSON alone
+    /// doesn't tell e.g. a histogram from word counts. Anything that is not a
+    /// valid envelope is reported as an [`ErrorCode::Internal`] error, so the
+    /// caller always has an exit code to map to.
+    pub fn parse_for(kind: &RequestKind, response: &str) -> Self {
+        Self::parse_with_metadata(kind, response).0
+    }
+
+    /// Like [`Response::parse_for`], also returning the metadata the calculator sent, if any.
+    pub fn parse_with_metadata(kind: &RequestKind, response: &str) -> (Self, Option<Metadata>) {
+        #[derive(Deserialize)]
+        #[serde(tag = "status", rename_all = "lowercase")]
+        enum Envelope {
+            Ok {
+                result: serde_json::Value,
+                meta: Option<Metadata>,
+            },
+            Error {
+                error: Error,
+                meta: Option<Metadata>,
+            },
+        }
+
+        let malformed = |e: serde_json::Error| {
+            Self::error(ErrorCode::Internal, format!("malformed response: {}", e))
+        };
+        match serde_json::from_str(response) {
+            Ok(Envelope::Ok { result, meta }) => match Output::from_value(kind, result) {
+                Ok(result) => (Self::Ok { result }, meta),
+                Err(e) => (malformed(e), meta),
+            },
+            Ok(Envelope::Error { error, meta }) => (Self::Error { error }, meta),
+            Err(e) => (malformed(e), None),
+        }
+    }
+
+    /// Serializes the response, or an [`ErrorCode::Internal`] error saying why it couldn't be.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(Self::unserializable)
+    }
+
+    /// Serializes the response with `meta` next to its fields.
+    pub fn to_json_with(&self, meta: &Metadata) -> String {
+        #[derive(Serialize)]
+        struct Envelope<'a> {
+            #[serde(flatten)]
+            response: &'a Response,
+            meta: &'a Metadata,
+        }
+
+        serde_json::to_string(&Envelope {
+            response: self,
+            meta,
+        })
+        .unwrap_or_else(Self::unserializable)
+    }
+
+    fn unserializable(e: serde_json::Error) -> String {
+        let error = Self::error(
+            ErrorCode::Internal,
+            format!("response can't be serialized: {}", e),
+        );
+        // An error holds nothing but strings
+        serde_json::to_string(&error).expect("error responses are always serializable")
+    }
+}
+
+/// What a calculator reports about how it produced a response.
+///
+/// It is sent as a `"meta"` field of the envelope, e.g.
+/// `{"status": "ok", "result": 2, "meta": {"compute_ns": 5000}}`, which
+/// [`Response::parse_with_metadata`] returns next to the response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Time spent parsing and analysing the request, in nanoseconds.
+    pub compute_ns: u64,
+}
+
+impl Metadata {
+    /// Metadata of a response computed since `start`.
+    pub fn since(start: Instant) -> Self {
+        Self {
+            compute_ns: start.elapsed().as_nanos() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.as_str(), self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is not valid JSON or its fields don't match the request type.
+    ParseFailure,
+    /// The `"type"` field is missing or names no known analysis.
+    UnknownType,
+    /// The request does not fit into what the transport or calculator accepts.
+    PayloadTooLarge,
+    /// The calculator failed while answering a valid request.
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ParseFailure => "parse_failure",
+            Self::UnknownType => "unknown_type",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// Process exit code a request-manager terminates with on this error.
+    ///
+    /// `1` stays reserved for usage and I/O errors of the manager itself.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::ParseFailure => 2,
+            Self::UnknownType => 3,
+            Self::PayloadTooLarge => 4,
+            Self::Internal => 5,
+        }
+    }
+}
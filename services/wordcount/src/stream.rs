@@ -0,0 +1,223 @@
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::analysis::Analyzer;
+use crate::request::RequestKind;
+use crate::response::{ErrorCode, Metadata, Response};
+use crate::{max_request_len, too_large};
+
+/// Feeds words to an [`Analyzer`] from input that arrives in arbitrary chunks.
+///
+/// Words and UTF-8 sequences may be split across chunk boundaries: whatever
+/// follows the last whitespace of a chunk is kept until the next one arrives,
+/// so memory stays bounded by the chunk size plus the longest word.
+/// Invalid UTF-8 is replaced the same way [`String::from_utf8_lossy`] does.
+pub struct ChunkedAnalyzer {
+    analyzer: Analyzer,
+    pending: Vec<u8>,
+    line: usize,
+}
+
+impl ChunkedAnalyzer {
+    pub fn new(kind: &RequestKind) -> Self {
+        Self {
+            analyzer: Analyzer::new(kind),
+            pending: Vec::new(),
+            line: 1,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(chunk);
+
+        // Keep a UTF-8 sequence that is cut off at the end for the next chunk
+        let complete = complete_utf8_len(&pending);
+        let text = String::from_utf8_lossy(&pending[..complete]);
+
+        // Everything after the last whitespace may be the start of a longer word
+        let words_end = match text.char_indices().rev().find(|(_, c)| c.is_whitespace()) {
+            Some((index, c)) => index + c.len_utf8(),
+            None => {
+                self.pending = pending;
+                return;
+            }
+        };
+        self.push_words(&text[..words_end]);
+
+        let mut rest = text[words_end..].as_bytes().to_vec();
+        rest.extend_from_slice(&pending[complete..]);
+        self.pending = rest;
+    }
+
+    /// Flushes the last word and returns the result.
+    pub fn finish(mut self) -> crate::Output {
+        let pending = std::mem::take(&mut self.pending);
+        self.push_words(&String::from_utf8_lossy(&pending));
+        self.analyzer.finish()
+    }
+
+    fn push_words(&mut self, text: &str) {
+        let mut lines = text.split('\n');
+        if let Some(first) = lines.next() {
+            for word in first.split_whitespace() {
+                self.analyzer.push(word, self.line);
+            }
+        }
+        for line in lines {
+            self.line += 1;
+            for word in line.split_whitespace() {
+                self.analyzer.push(word, self.line);
+            }
+        }
+    }
+}
+
+/// Length of the prefix of `bytes` that doesn't end in a truncated UTF-8 sequence.
+fn complete_utf8_len(bytes: &[u8]) -> usize {
+    // A sequence is at most 4 bytes long, so only the last 3 can start a truncated one
+    for back in 1..=bytes.len().min(3) {
+        let index = bytes.len() - back;
+        let byte = bytes[index];
+        if byte & 0b1100_0000 == 0b1000_0000 {
+            // Continuation byte, keep looking for the leading one
+            continue;
+        }
+        let expected = match byte {
+            0b1111_0000..=0b1111_0111 => 4,
+            0b1110_0000..=0b1110_1111 => 3,
+            0b1100_0000..=0b1101_1111 => 2,
+            _ => 1,
+        };
+        return if expected > back { index } else { bytes.len() };
+    }
+    bytes.len()
+}
+
+/// A request whose input is streamed in chunks after a header.
+///
+/// The header is the request without its `"string"`, e.g. `{"type": "top", "n": 10}`.
+/// The header and the input count towards [`max_request_len`] together; once
+/// they exceed it, the rest of the input is skipped.
+pub struct StreamingRequest {
+    state: Result<ChunkedAnalyzer, Response>,
+    /// Bytes of the header and the input so far.
+    len: usize,
+    /// Time spent on the request so far, not counting waits for chunks.
+    compute: Duration,
+}
+
+impl StreamingRequest {
+    pub fn new(header: &str) -> Self {
+        let start = Instant::now();
+        let state = serde_json::from_str::<Value>(header)
+            .map_err(|e| Response::error(ErrorCode::ParseFailure, e.to_string()))
+            .and_then(crate::parse_kind)
+            .map(|kind| ChunkedAnalyzer::new(&kind));
+        Self {
+            state,
+            len: header.len(),
+            compute: start.elapsed(),
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        let start = Instant::now();
+        self.len += chunk.len();
+        if self.len > max_request_len() {
+            // Drops the analysis, the final length is reported once the input ends
+            if self.state.is_ok() {
+                self.state = Err(too_large(self.len));
+            }
+        } else if let Ok(analyzer) = &mut self.state {
+            analyzer.feed(chunk);
+        }
+        self.compute += start.elapsed();
+    }
+
+    pub fn finish(self) -> Response {
+        self.finish_timed().0
+    }
+
+    /// Like [`StreamingRequest::finish`], with the time spent on every chunk added up.
+    pub fn finish_timed(self) -> (Response, Metadata) {
+        let start = Instant::now();
+        let response = match self.state {
+            _ if self.len > max_request_len() => too_large(self.len),
+            Ok(analyzer) => Response::Ok {
+                result: analyzer.finish(),
+            },
+            Err(response) => response,
+        };
+        let compute = self.compute + start.elapsed();
+        let meta = Metadata {
+            compute_ns: compute.as_nanos() as u64,
+        };
+        (response, meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze;
+
+    fn chunked(kind: &RequestKind, input: &str, chunk_size: usize) -> crate::Output {
+        let mut analyzer = ChunkedAnalyzer::new(kind);
+        for chunk in input.as_bytes().chunks(chunk_size) {
+            analyzer.feed(chunk);
+        }
+        analyzer.finish()
+    }
+
+    #[test]
+    fn test_chunks_match_whole_input() {
+        let input = "héllo wörld\u{a0}héllo\n\nthe  end\r\nof ünïcödé 字 字\n";
+        let kinds = [
+            RequestKind::Counts,
+            RequestKind::Ngrams { n: 2 },
+            RequestKind::Grep {
+                word: "字".to_string(),
+                mode: crate::GrepMode::Lines,
+            },
+            RequestKind::Grep {
+                word: "héllo".to_string(),
+                mode: crate::GrepMode::Positions,
+            },
+        ];
+        for kind in &kinds {
+            let expected = analyze(kind, input);
+            for chunk_size in 1..=input.len() {
+                assert_eq!(chunked(kind, input, chunk_size), expected, "{}", chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_replaced() {
+        let input = b"ok \xff\xfe bad\xe2\x82";
+        let mut analyzer = ChunkedAnalyzer::new(&RequestKind::Counts);
+        for chunk in input.chunks(2) {
+            analyzer.feed(chunk);
+        }
+        let expected = analyze(&RequestKind::Counts, &String::from_utf8_lossy(input));
+        assert_eq!(analyzer.finish(), expected);
+    }
+
+    #[test]
+    fn test_streaming_request() {
+        let mut request = StreamingRequest::new(r#"{"type": "top", "n": 1}"#);
+        request.feed(b"b a ");
+        request.feed(b"b");
+        let expected = crate::Output::Ranking(vec![("b".to_string(), 2)]);
+        assert_eq!(request.finish(), Response::Ok { result: expected });
+
+        let mut request = StreamingRequest::new(r#"{"type": "nope"}"#);
+        request.feed(b"ignored");
+        match request.finish() {
+            Response::Error { error } => assert_eq!(error.code, ErrorCode::UnknownType),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}